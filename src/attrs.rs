@@ -0,0 +1,383 @@
+use crate::nc_client::NcClient;
+use anyhow::Result;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use regex::Regex;
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+// The metadata is kept on the server as a custom DAV property of the file. Its value is JSON.
+pub const ATTRS_NS: &str = "urn:x-ncs";
+pub const ATTRS_PROP: &str = "attrs";
+// only these are read and written. The others need privileges or belong to the system.
+const XATTR_PREFIX: &str = "user.";
+
+// what is preserved for the files matching `pattern`.
+// `pattern` is a regex matched against the path from the root. ex) "bin/run.sh"
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JsonAttrRule {
+    pub pattern: String,
+    #[serde(default)]
+    pub executable: bool,
+    #[serde(default)]
+    pub xattrs: bool,
+}
+
+// `.ncs/attrs.json`. Nothing is preserved without it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct JsonAttrRules {
+    pub rules: Vec<JsonAttrRule>,
+}
+
+// The first matching rule is used.
+#[derive(Clone, Debug, Default)]
+pub struct AttrRules {
+    rules: Vec<(Regex, JsonAttrRule)>,
+}
+
+impl AttrRules {
+    pub fn load<P: AsRef<Path>>(rules_file: P) -> Result<Self> {
+        let j = match fs::read_to_string(rules_file.as_ref()) {
+            Ok(j) => j,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let raw: JsonAttrRules =
+            serde_json::from_str(&j).map_err(|e| anyhow!("{:?}: {}", rules_file.as_ref(), e))?;
+        Self::new(raw.rules)
+    }
+
+    pub fn new(raw_rules: Vec<JsonAttrRule>) -> Result<Self> {
+        let mut rules = Vec::new();
+        for (i, rule) in raw_rules.into_iter().enumerate() {
+            let r = Regex::new(&rule.pattern)
+                .map_err(|e| anyhow!("rules[{}] {:?}: {}", i, rule.pattern, e))?;
+            rules.push((r, rule));
+        }
+
+        Ok(Self { rules })
+    }
+
+    // `path` is the one in the tree. ex) "/bin/run.sh"
+    fn rule_for(&self, path: &str) -> Option<&JsonAttrRule> {
+        let path = path.trim_start_matches('/');
+        self.rules
+            .iter()
+            .find(|(r, _)| r.is_match(path))
+            .map(|(_, rule)| rule)
+            .filter(|rule| rule.executable || rule.xattrs)
+    }
+
+    pub fn applies_to(&self, path: &str) -> bool {
+        self.rule_for(path).is_some()
+    }
+
+    // what is uploaded for the local file. None if no rule matches.
+    pub fn read(&self, path: &str, local_path: &Path) -> Option<FileAttrs> {
+        let rule = self.rule_for(path)?;
+        let mut attrs = FileAttrs::default();
+        if rule.executable {
+            let mode = fs::metadata(local_path).ok()?.permissions().mode();
+            attrs.executable = Some(mode & 0o111 != 0);
+        }
+        if rule.xattrs {
+            match xattr::read_all(local_path) {
+                Ok(x) => attrs.xattrs = x,
+                Err(e) => warn!("{:?}: can't read the xattrs. | {:?}", local_path, e),
+            }
+        }
+
+        Some(attrs)
+    }
+
+    // Only what the rule for `path` allows is written.
+    pub fn apply(&self, path: &str, local_path: &Path, attrs: &FileAttrs) -> io::Result<()> {
+        let rule = match self.rule_for(path) {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        if let (true, Some(executable)) = (rule.executable, attrs.executable) {
+            let mut perm = fs::metadata(local_path)?.permissions();
+            let mode = perm.mode();
+            // executable where readable, like `chmod +x`.
+            let mode = if executable {
+                mode | ((mode & 0o444) >> 2)
+            } else {
+                mode & !0o111
+            };
+            perm.set_mode(mode);
+            fs::set_permissions(local_path, perm)?;
+        }
+        if rule.xattrs {
+            for (name, value) in attrs.xattrs.iter() {
+                if !name.starts_with(XATTR_PREFIX) {
+                    continue;
+                }
+                match base64::decode(value) {
+                    Ok(v) => xattr::set(local_path, name, &v)?,
+                    Err(e) => warn!("{:?}: broken xattr {}. | {:?}", local_path, name, e),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// xattr values are in base64.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileAttrs {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub executable: Option<bool>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+}
+
+impl FileAttrs {
+    pub fn proppatch_body(&self) -> Result<String> {
+        let j = serde_json::to_string(self)?;
+        Ok(format!(
+            r#"<?xml version="1.0"?>
+<d:propertyupdate xmlns:d="DAV:" xmlns:ncs="{}">
+  <d:set>
+    <d:prop>
+      <ncs:{}>{}</ncs:{}>
+    </d:prop>
+  </d:set>
+</d:propertyupdate>
+"#,
+            ATTRS_NS,
+            ATTRS_PROP,
+            xml_escape(&j),
+            ATTRS_PROP
+        ))
+    }
+
+    // from a PROPFIND response. None if the file has no such property.
+    pub fn from_propfind(text: &str) -> Result<Option<Self>> {
+        let document = roxmltree::Document::parse(text)?;
+        let value = document
+            .descendants()
+            .find(|n| {
+                n.tag_name().namespace() == Some(ATTRS_NS) && n.tag_name().name() == ATTRS_PROP
+            })
+            .and_then(|n| n.text())
+            .filter(|t| !t.trim().is_empty());
+
+        match value {
+            Some(v) => Ok(Some(serde_json::from_str(v)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+pub async fn upload_attrs(client: &NcClient, remote_path: &str, attrs: &FileAttrs) -> Result<()> {
+    let req = client
+        .dav_request(Method::from_bytes(b"PROPPATCH").unwrap(), remote_path)?
+        .body(attrs.proppatch_body()?);
+    client.send(req).await?;
+
+    Ok(())
+}
+
+pub async fn download_attrs(client: &NcClient, remote_path: &str) -> Result<Option<FileAttrs>> {
+    let body = format!(
+        r#"<?xml version="1.0"?>
+<d:propfind xmlns:d="DAV:" xmlns:ncs="{}">
+  <d:prop>
+    <ncs:{} />
+  </d:prop>
+</d:propfind>
+"#,
+        ATTRS_NS, ATTRS_PROP
+    );
+    let req = client
+        .dav_request(Method::from_bytes(b"PROPFIND").unwrap(), remote_path)?
+        .header("Depth", "0")
+        .body(body);
+    let res = client.send(req).await?;
+    let text = res.text_with_charset("utf-8").await?;
+
+    FileAttrs::from_propfind(&text)
+}
+
+#[cfg(target_os = "linux")]
+mod xattr {
+    use super::XATTR_PREFIX;
+    use std::collections::BTreeMap;
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    fn c_path(path: &Path) -> io::Result<CString> {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    // the ones in the "user." namespace. Values are in base64.
+    pub fn read_all(path: &Path) -> io::Result<BTreeMap<String, String>> {
+        let p = c_path(path)?;
+        let len = unsafe { libc::listxattr(p.as_ptr(), std::ptr::null_mut(), 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut names = vec![0u8; len as usize];
+        let len = unsafe { libc::listxattr(p.as_ptr(), names.as_mut_ptr() as *mut _, names.len()) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        names.truncate(len as usize);
+
+        let mut xattrs = BTreeMap::new();
+        for name in names.split(|b| *b == 0).filter(|n| !n.is_empty()) {
+            let name_s = String::from_utf8_lossy(name).to_string();
+            if !name_s.starts_with(XATTR_PREFIX) {
+                continue;
+            }
+            let c_name =
+                CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let len =
+                unsafe { libc::getxattr(p.as_ptr(), c_name.as_ptr(), std::ptr::null_mut(), 0) };
+            if len < 0 {
+                continue;
+            }
+            let mut value = vec![0u8; len as usize];
+            let len = unsafe {
+                libc::getxattr(
+                    p.as_ptr(),
+                    c_name.as_ptr(),
+                    value.as_mut_ptr() as *mut _,
+                    value.len(),
+                )
+            };
+            if len < 0 {
+                continue;
+            }
+            value.truncate(len as usize);
+            xattrs.insert(name_s, base64::encode(&value));
+        }
+
+        Ok(xattrs)
+    }
+
+    pub fn set(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+        let p = c_path(path)?;
+        let c_name =
+            CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let res = unsafe {
+            libc::setxattr(
+                p.as_ptr(),
+                c_name.as_ptr(),
+                value.as_ptr() as *const _,
+                value.len(),
+                0,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+// xattrs are only supported on Linux.
+#[cfg(not(target_os = "linux"))]
+mod xattr {
+    use std::collections::BTreeMap;
+    use std::io;
+    use std::path::Path;
+
+    pub fn read_all(_path: &Path) -> io::Result<BTreeMap<String, String>> {
+        Ok(BTreeMap::new())
+    }
+
+    pub fn set(_path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attr_rules_test() {
+        let rules = AttrRules::new(vec![
+            JsonAttrRule {
+                pattern: r"^bin/".to_string(),
+                executable: true,
+                xattrs: false,
+            },
+            JsonAttrRule {
+                pattern: r"\.sh$".to_string(),
+                executable: true,
+                xattrs: true,
+            },
+        ])
+        .unwrap();
+        assert!(rules.applies_to("/bin/run"));
+        assert!(rules.applies_to("/a/b.sh"));
+        assert!(!rules.applies_to("/a/b.txt"));
+        assert!(AttrRules::new(vec![JsonAttrRule {
+            pattern: "(".to_string(),
+            executable: true,
+            xattrs: false,
+        }])
+        .is_err());
+
+        let base = std::env::temp_dir().join(format!("ncs_attrs_test_{}", std::process::id()));
+        fs::create_dir_all(base.join("bin")).unwrap();
+        let local = base.join("bin/run");
+        fs::write(&local, b"#!/bin/sh\n").unwrap();
+        fs::set_permissions(&local, fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(
+            rules.read("/bin/run", &local),
+            Some(FileAttrs {
+                executable: Some(false),
+                ..Default::default()
+            })
+        );
+
+        let attrs = FileAttrs {
+            executable: Some(true),
+            xattrs: BTreeMap::new(),
+        };
+        rules.apply("/bin/run", &local, &attrs).unwrap();
+        let mode = fs::metadata(&local).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+        fs::remove_dir_all(&base).unwrap();
+
+        let mut attrs = attrs;
+        attrs
+            .xattrs
+            .insert("user.a&b".to_string(), base64::encode(b"<x>"));
+        let body = attrs.proppatch_body().unwrap();
+        let response = format!(
+            r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:ncs="{}"><d:response><d:href>/a</d:href>
+<d:propstat><d:prop>{}</d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat>
+</d:response></d:multistatus>"#,
+            ATTRS_NS,
+            &body[body.find("<ncs:").unwrap()..body.find("</d:prop>").unwrap()]
+        );
+        assert_eq!(FileAttrs::from_propfind(&response).unwrap(), Some(attrs));
+
+        let missing = r#"<?xml version="1.0"?><d:multistatus xmlns:d="DAV:"><d:response>
+<d:propstat><d:prop><x:attrs xmlns:x="urn:x-ncs"/></d:prop><d:status>HTTP/1.1 404 Not Found</d:status></d:propstat>
+</d:response></d:multistatus>"#;
+        assert_eq!(FileAttrs::from_propfind(missing).unwrap(), None);
+    }
+}
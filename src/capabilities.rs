@@ -0,0 +1,238 @@
+use crate::meta::write_atomic;
+use crate::names::ForbiddenNames;
+use crate::nc_client::NcClient;
+use anyhow::{Context, Result};
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use reqwest::Method;
+use serde_json::Value;
+use std::{fmt, fs};
+
+pub const CAPABILITIES_PATH: &str = "/ocs/v2.php/cloud/capabilities";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ServerVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub micro: u32,
+}
+
+impl fmt::Display for ServerVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.micro)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ServerCapabilities {
+    pub version: ServerVersion,
+    pub version_string: String,
+    // activity app with api v2. X-Activity-* headers come with it.
+    pub activity: bool,
+    // `remote.php/dav` (files, trashbin, versions, uploads). If not, only `remote.php/webdav`.
+    pub dav: bool,
+    // the push server app. Changes could be pushed instead of polled with it.
+    pub notify_push: bool,
+    // chunked upload version, e.g. "1.0"
+    pub chunking: Option<String>,
+    // checksum types the server takes on upload, e.g. "SHA1"
+    pub checksums: Vec<String>,
+    pub forbidden_names: ForbiddenNames,
+}
+
+impl ServerCapabilities {
+    // used when the server can't be asked (offline start up) and nothing is cached.
+    // The features which this client was written for are assumed.
+    pub fn assumed() -> Self {
+        Self {
+            activity: true,
+            dav: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn from_json(text: &str) -> Result<Self> {
+        let v: Value = serde_json::from_str(text)?;
+        let data = v
+            .pointer("/ocs/data")
+            .context("Invalid capabilities response.")?;
+
+        let version = data.get("version");
+        let num = |key: &str| {
+            version
+                .and_then(|v| v.get(key))
+                .and_then(Value::as_u64)
+                .unwrap_or(0) as u32
+        };
+        let version_string = version
+            .and_then(|v| v.get("string"))
+            .and_then(Value::as_str)
+            .unwrap_or("")
+            .to_string();
+
+        let caps = data.get("capabilities");
+        let cap = |pointer: &str| caps.and_then(|c| c.pointer(pointer));
+
+        let activity = cap("/activity/apiv2").is_some();
+        let dav = cap("/dav").is_some();
+        let notify_push = cap("/notify_push").is_some();
+        let chunking = cap("/dav/chunking")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        let strings = |pointer: &str| {
+            cap(pointer).and_then(Value::as_array).map(|a| {
+                a.iter()
+                    .filter_map(Value::as_str)
+                    .map(str::to_string)
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        let checksums = strings("/checksums/supportedTypes").unwrap_or_default();
+
+        // Nextcloud 30 and later. The older ones only have the blacklisted files.
+        let mut forbidden_names = ForbiddenNames::default();
+        if let Some(names) = strings("/files/forbidden_filenames") {
+            forbidden_names.names = names;
+        }
+        for name in strings("/files/blacklisted_files").unwrap_or_default() {
+            if !forbidden_names.names.contains(&name) {
+                forbidden_names.names.push(name);
+            }
+        }
+        forbidden_names.basenames =
+            strings("/files/forbidden_filename_basenames").unwrap_or_default();
+        forbidden_names.characters =
+            strings("/files/forbidden_filename_characters").unwrap_or_default();
+        forbidden_names.extensions =
+            strings("/files/forbidden_filename_extensions").unwrap_or_default();
+
+        Ok(Self {
+            version: ServerVersion {
+                major: num("major"),
+                minor: num("minor"),
+                micro: num("micro"),
+            },
+            version_string,
+            activity,
+            dav,
+            notify_push,
+            chunking,
+            checksums,
+            forbidden_names,
+        })
+    }
+
+    // Hard requirements. ncs can't work without them.
+    pub fn check_requirements(&self) -> Result<()> {
+        if !self.activity {
+            return Err(anyhow!(
+                "The Activity app (API v2) is not available on the server (Nextcloud {}). \
+                 ncs needs it to follow remote changes. Please enable the Activity app.",
+                self.version_string
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn supports_chunking(&self) -> bool {
+        self.dav && self.chunking.is_some()
+    }
+}
+
+// The answer is kept in `cache_path` for the next offline start up.
+pub async fn fetch_capabilities(
+    client: &NcClient,
+    cache_path: Option<&str>,
+) -> Result<ServerCapabilities> {
+    let req = client
+        .ocs_request(Method::GET, CAPABILITIES_PATH)?
+        .query(&[("format", "json")]);
+    let res = client.send(req).await?;
+    let text = res.text_with_charset("utf-8").await?;
+
+    let caps = ServerCapabilities::from_json(&text)?;
+    info!(
+        "Nextcloud {} (activity: {}, dav: {}, notify_push: {}, chunking: {:?}, checksums: {:?})",
+        caps.version_string,
+        caps.activity,
+        caps.dav,
+        caps.notify_push,
+        caps.chunking,
+        caps.checksums
+    );
+    if let Some(cache_path) = cache_path {
+        if let Err(e) = write_atomic(cache_path, text.as_bytes()) {
+            warn!("{}: can't keep the capabilities. | {:?}", cache_path, e);
+        }
+    }
+
+    Ok(caps)
+}
+
+// the ones got last time the server was online.
+pub fn cached_capabilities(cache_path: &str) -> Option<ServerCapabilities> {
+    let text = fs::read_to_string(cache_path).ok()?;
+    match ServerCapabilities::from_json(&text) {
+        Ok(caps) => Some(caps),
+        Err(e) => {
+            warn!("{}: broken capabilities cache. | {:?}", cache_path, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capabilities_test() {
+        let text = r#"{"ocs":{"meta":{"status":"ok","statuscode":200},"data":{
+            "version":{"major":25,"minor":0,"micro":2,"string":"25.0.2","edition":""},
+            "capabilities":{
+                "dav":{"chunking":"1.0","bulkupload":"1.0"},
+                "activity":{"apiv2":["filters","filters-api","previews","rich-strings"]},
+                "checksums":{"supportedTypes":["SHA1"],"preferredUploadType":"SHA1"},
+                "files":{"blacklisted_files":[".htaccess"],
+                    "forbidden_filenames":[".htaccess","thumbs.db"],
+                    "forbidden_filename_characters":["\\",":"],
+                    "forbidden_filename_extensions":[".part"]}
+            }}}}"#;
+
+        let caps = ServerCapabilities::from_json(text).unwrap();
+        assert_eq!(
+            caps.version,
+            ServerVersion {
+                major: 25,
+                minor: 0,
+                micro: 2
+            }
+        );
+        assert!(caps.activity);
+        assert!(caps.supports_chunking());
+        assert!(!caps.notify_push);
+        assert_eq!(caps.checksums, vec!["SHA1".to_string()]);
+        assert_eq!(caps.forbidden_names.names.len(), 2);
+        assert!(caps.forbidden_names.check("Thumbs.db").is_some());
+        assert!(caps.forbidden_names.check("a:b").is_some());
+        assert!(caps.check_requirements().is_ok());
+
+        let cache = std::env::temp_dir().join(format!("ncs_caps_test_{}", std::process::id()));
+        let cache = cache.to_str().unwrap();
+        assert!(cached_capabilities(cache).is_none());
+        fs::write(cache, text).unwrap();
+        let cached = cached_capabilities(cache).unwrap();
+        assert!(cached.forbidden_names.check("a:b").is_some());
+        fs::remove_file(cache).unwrap();
+
+        let text = r#"{"ocs":{"data":{"version":{"major":12,"minor":0,"micro":0,"string":"12.0.0"},
+            "capabilities":{"notify_push":{"type":["files"]}}}}}"#;
+        let caps = ServerCapabilities::from_json(text).unwrap();
+        assert!(!caps.dav);
+        assert!(caps.notify_push);
+        assert!(caps.checksums.is_empty());
+        assert!(caps.check_requirements().is_err());
+    }
+}
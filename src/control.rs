@@ -0,0 +1,484 @@
+use crate::failures::FailureRegistry;
+use crate::local_listen::LocalEvent;
+use crate::meta::*;
+use crate::nc_listen::refresh;
+use crate::throttle::{self, LimitKind, ScheduleRule, Throttle};
+use crate::*;
+use anyhow::Result;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc::{self, error::SendError};
+use tokio::sync::oneshot;
+
+pub const HELP: &str = r#"commands:
+  (empty line) | quit        terminate.
+  RESET | repair hard        hard repair. delete all local files and get them again.
+  repair normal              get the server tree again and stash local files not on the server.
+  repair <normal|hard> --dry-run  show the plan of the repair without doing it.
+  limit                      show bandwidth and request limits.
+  limit <up|down|req> <n>    set limit (bytes/sec or requests/sec). 0 or "unlimited" to disable.
+  schedule <rules>           set limit schedule. ex) schedule 22:00-06:00 up=unlimited down=unlimited
+  schedule off               clear limit schedule.
+  trash list                 list items in the trash bin of the server.
+  trash restore <name|path>  restore an item from the trash bin.
+  versions <path>            list the versions of a file on the server.
+  versions get <id> <path>   download a version next to the file as "~<name>.v<id>".
+  versions restore <id> <path>  restore a version as the current file.
+  stash list [path]          list stashed copies. (only under path if given)
+  stash show <id>            show the details of a stashed copy.
+  stash restore <id>         put a stashed copy back to its original path.
+  stash cleanup              apply the autostash retention policy now and report freed space.
+  check-excludes <path>      show which rule includes or excludes the path.
+  deletes                    show local deletions held by the mass deletion guard.
+  deletes confirm            propagate the held deletions to the server.
+  deletes discard            drop the held deletions and get the files back from the server.
+  failures                   show failed operations waiting for a retry.
+  failures retry             retry all failed operations now, flagged ones too.
+  history <path>             show what was done to the path (and under it) and why.
+  status                     show the sync status as JSON.
+  tree                       export the tree as JSON.
+  help                       show this message."#;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepairKind {
+    Normal,
+    Hard,
+}
+
+#[derive(Debug)]
+pub enum ControlRequest {
+    Repair { kind: RepairKind, dry_run: bool },
+    Terminate,
+    Help,
+    ShowLimits,
+    SetLimit(LimitKind, Option<u64>),
+    SetSchedule(Vec<ScheduleRule>),
+    TrashList,
+    TrashRestore(String),
+    StashList(Option<String>),
+    StashShow(u64),
+    StashRestore(u64),
+    StashCleanup,
+    VersionList(String),
+    VersionGet(String, String),
+    VersionRestore(String, String),
+    CheckExcludes(String),
+    ShowHeldDeletes,
+    ConfirmHeldDeletes,
+    DiscardHeldDeletes,
+    ShowFailures,
+    RetryFailures,
+    History(String),
+    Status,
+    Tree,
+}
+
+pub type ControlReply = oneshot::Sender<String>;
+
+// the rest of the line after `n` words. Paths may contain spaces.
+fn rest_words(line: &str, n: usize) -> &str {
+    let mut rest = line.trim_start();
+    for _ in 0..n {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        rest = rest[end..].trim_start();
+    }
+    rest.trim_end()
+}
+
+fn parse_id(s: &str) -> Result<u64> {
+    s.parse().map_err(|_| anyhow!("Invalid id: {}", s))
+}
+
+impl ControlRequest {
+    // requests which change the local or the server. They are refused in the dry-run mode.
+    pub fn is_mutating(&self) -> bool {
+        match self {
+            ControlRequest::Repair { dry_run, .. } => !dry_run,
+            ControlRequest::TrashRestore(_)
+            | ControlRequest::StashRestore(_)
+            | ControlRequest::StashCleanup
+            | ControlRequest::VersionGet(..)
+            | ControlRequest::VersionRestore(..)
+            | ControlRequest::ConfirmHeldDeletes
+            | ControlRequest::DiscardHeldDeletes => true,
+            _ => false,
+        }
+    }
+}
+
+pub fn parse(line: &str) -> Result<ControlRequest> {
+    let line = line.trim();
+    let mut words = line.split_whitespace();
+    let head = words.next().unwrap_or("");
+    let args = words.collect::<Vec<_>>();
+
+    let req = match (head, args.as_slice()) {
+        ("", _) | ("quit", _) | ("exit", _) => ControlRequest::Terminate,
+        ("RESET", _) => ControlRequest::Repair {
+            kind: RepairKind::Hard,
+            dry_run: false,
+        },
+        ("repair", [kind, opts @ ..]) => {
+            let kind = match *kind {
+                "normal" => RepairKind::Normal,
+                "hard" => RepairKind::Hard,
+                _ => return Err(anyhow!("Unknown repair kind: {}", kind)),
+            };
+            let dry_run = match opts {
+                [] => false,
+                ["--dry-run"] => true,
+                _ => return Err(anyhow!("Unknown option: {}", opts.join(" "))),
+            };
+            ControlRequest::Repair { kind, dry_run }
+        }
+        ("help", _) => ControlRequest::Help,
+        ("limit", []) => ControlRequest::ShowLimits,
+        ("limit", [kind, value]) => {
+            let kind = LimitKind::from_name(kind)
+                .ok_or_else(|| anyhow!("Unknown limit kind: {}", kind))?;
+            ControlRequest::SetLimit(kind, throttle::parse_limit(value)?)
+        }
+        ("schedule", ["off"]) => ControlRequest::SetSchedule(Vec::new()),
+        ("schedule", [_, ..]) => {
+            ControlRequest::SetSchedule(throttle::parse_schedule(rest_words(line, 1))?)
+        }
+        ("trash", ["list"]) => ControlRequest::TrashList,
+        ("trash", ["restore", _, ..]) => {
+            ControlRequest::TrashRestore(rest_words(line, 2).to_string())
+        }
+        ("versions", ["get", id, _, ..]) => {
+            ControlRequest::VersionGet(id.to_string(), rest_words(line, 3).to_string())
+        }
+        ("versions", ["restore", id, _, ..]) => {
+            ControlRequest::VersionRestore(id.to_string(), rest_words(line, 3).to_string())
+        }
+        ("versions", [_, ..]) => ControlRequest::VersionList(rest_words(line, 1).to_string()),
+        ("stash", ["list"]) => ControlRequest::StashList(None),
+        ("stash", ["list", _, ..]) => {
+            ControlRequest::StashList(Some(rest_words(line, 2).to_string()))
+        }
+        ("stash", ["show", id]) => ControlRequest::StashShow(parse_id(id)?),
+        ("stash", ["restore", id]) => ControlRequest::StashRestore(parse_id(id)?),
+        ("stash", ["cleanup"]) => ControlRequest::StashCleanup,
+        ("check-excludes", [_, ..]) => {
+            ControlRequest::CheckExcludes(rest_words(line, 1).to_string())
+        }
+        ("deletes", []) => ControlRequest::ShowHeldDeletes,
+        ("deletes", ["confirm"]) => ControlRequest::ConfirmHeldDeletes,
+        ("deletes", ["discard"]) => ControlRequest::DiscardHeldDeletes,
+        ("failures", []) => ControlRequest::ShowFailures,
+        ("failures", ["retry"]) => ControlRequest::RetryFailures,
+        ("history", [_, ..]) => ControlRequest::History(rest_words(line, 1).to_string()),
+        ("status", []) => ControlRequest::Status,
+        ("tree", []) => ControlRequest::Tree,
+        _ => return Err(anyhow!("Unknown command: {}\n{}", line, HELP)),
+    };
+
+    Ok(req)
+}
+
+// Parses the line and sends it to the main loop. Parse errors are returned as the reply.
+// Requests handled outside of `Command::Control` have no reply.
+pub async fn dispatch(
+    line: &str,
+    tx: &mpsc::Sender<Command>,
+) -> Result<Option<String>, SendError<Command>> {
+    let req = match parse(line) {
+        Ok(req) => req,
+        Err(e) => return Ok(Some(e.to_string())),
+    };
+
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let com = match req {
+        ControlRequest::Repair {
+            kind: RepairKind::Hard,
+            dry_run: false,
+        } => Command::HardRepair,
+        ControlRequest::Repair {
+            kind: RepairKind::Normal,
+            dry_run: false,
+        } => Command::NormalRepair,
+        ControlRequest::Terminate => Command::Terminate(false),
+        req => Command::Control(req, reply_tx),
+    };
+    tx.send(com).await?;
+
+    Ok(reply_rx.await.ok())
+}
+
+// Replies of the requests handled by the main loop. An error is replied as it is.
+
+fn join_lines<T: ToString>(items: &[T]) -> String {
+    items
+        .iter()
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn show_limits(throttle: &Throttle) -> String {
+    format!(
+        "limits: {}\nin effect: {}",
+        throttle.get_limits(),
+        throttle.get_current_limits()
+    )
+}
+
+pub fn set_limit(throttle: &Throttle, kind: LimitKind, value: Option<u64>) -> String {
+    throttle.set_limit(kind, value);
+    format!("limits: {}", throttle.get_limits())
+}
+
+pub fn set_schedule(throttle: &Throttle, rules: Vec<ScheduleRule>) -> String {
+    let n = rules.len();
+    throttle.set_schedule(rules);
+    format!("schedule: {} rules", n)
+}
+
+pub async fn trash_list(nc_info: &NCInfo, local_info: &LocalInfo) -> Result<String> {
+    let items = trashbin::list(nc_info, local_info).await?;
+    if items.is_empty() {
+        return Ok("trash bin is empty.".to_string());
+    }
+
+    Ok(join_lines(&items))
+}
+
+pub async fn trash_restore(
+    nc_info: &NCInfo,
+    local_info: &LocalInfo,
+    target: &str,
+) -> Result<String> {
+    let item = trashbin::restore(nc_info, local_info, target).await?;
+
+    Ok(format!(
+        "restored: {}\nit will be synced with the next activity.",
+        item
+    ))
+}
+
+// `prefix`: only the items stashed from under it.
+pub fn stash_list(local_info: &LocalInfo, prefix: Option<&str>) -> Result<String> {
+    let prefix = prefix.map(add_head_slash);
+    let items = local_info
+        .stash_index
+        .list()?
+        .into_iter()
+        .filter(|item| match &prefix {
+            Some(p) => item.original_path.starts_with(p.as_str()),
+            None => true,
+        })
+        .collect::<Vec<_>>();
+    if items.is_empty() {
+        return Ok("no stashed copies.".to_string());
+    }
+
+    Ok(join_lines(&items))
+}
+
+pub fn stash_show(local_info: &LocalInfo, id: u64) -> Result<String> {
+    let reply = match local_info.stash_index.get(id)? {
+        Some(item) => format!(
+            "{}\nfile: {:?}",
+            item.detail(),
+            stash::stashed_path(&item, local_info)
+        ),
+        None => format!("No such stash item: {}", id),
+    };
+
+    Ok(reply)
+}
+
+pub fn stash_cleanup(local_info: &LocalInfo) -> Result<String> {
+    let report = stash::cleanup(local_info)?;
+
+    Ok(format!("{}\n({})", report, local_info.retention_policy))
+}
+
+pub fn stash_restore(local_info: &LocalInfo, id: u64) -> Result<String> {
+    let item = stash::restore(local_info, id)?;
+
+    Ok(format!("restored: {}", item))
+}
+
+pub async fn version_list(
+    nc_info: &NCInfo,
+    local_info: &LocalInfo,
+    tree: &Tree,
+    path: &str,
+) -> Result<String> {
+    let vs = versions::list(nc_info, local_info, tree, path).await?;
+    if vs.is_empty() {
+        return Ok(format!("{} has no versions.", path));
+    }
+
+    Ok(join_lines(&vs))
+}
+
+pub async fn version_get(
+    nc_info: &NCInfo,
+    local_info: &LocalInfo,
+    tree: &Tree,
+    path: &str,
+    id: &str,
+) -> Result<String> {
+    let p = versions::download(nc_info, local_info, tree, path, id).await?;
+
+    Ok(format!("saved: {:?}", p))
+}
+
+pub async fn version_restore(
+    nc_info: &NCInfo,
+    local_info: &LocalInfo,
+    tree: &mut Tree,
+    nc2l_cancel_map: &mut HashMap<String, usize>,
+    path: &str,
+    id: &str,
+) -> Result<String> {
+    versions::restore(nc_info, local_info, tree, nc2l_cancel_map, path, id).await?;
+
+    Ok(format!("restored version {} of {}", id, path))
+}
+
+pub fn show_held_deletes(held: &[LocalEvent]) -> String {
+    let mut lines = vec![format!("{} deletions are held.", held.len())];
+    lines.extend(held.iter().map(|ev| format!("{:?}", ev)));
+    lines.join("\n")
+}
+
+// The files are got back from the server.
+pub async fn discard_held_deletes(
+    held: Vec<LocalEvent>,
+    tree: &mut Tree,
+    nc_info: &NCInfo,
+    local_info: &LocalInfo,
+    nc2l_cancel_map: &mut HashMap<String, usize>,
+) -> String {
+    let n = held.len();
+    for ev in held {
+        if let LocalEvent::Delete(p) = ev {
+            let res = refresh(&p, true, tree, nc_info, local_info, nc2l_cancel_map, false).await;
+            if let Err(e) = res {
+                info!("{:?}", e);
+            }
+        }
+    }
+
+    format!(
+        "{} deletions are discarded. the files are got back from the server.",
+        n
+    )
+}
+
+pub fn show_failures(failures: &FailureRegistry) -> Result<String> {
+    let list = failures.list()?;
+    if list.is_empty() {
+        return Ok("no failed operations.".to_string());
+    }
+
+    Ok(list
+        .iter()
+        .map(|f| format!("{}\n    {}", f, f.message))
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+pub fn retry_failures(failures: &FailureRegistry) -> Result<String> {
+    let n = failures.retry_all()?;
+
+    Ok(format!("{} failed operations will be retried.", n))
+}
+
+pub fn history(local_info: &LocalInfo, path: &str) -> Result<String> {
+    let records = local_info.history.query(path)?;
+    if records.is_empty() {
+        return Ok(format!("no history of {}.", path));
+    }
+
+    Ok(join_lines(&records))
+}
+
+pub async fn repair_plan(
+    kind: RepairKind,
+    local_info: &LocalInfo,
+    nc_info: &NCInfo,
+) -> Result<String> {
+    let plan = match kind {
+        RepairKind::Normal => repair::plan_normal_repair(local_info, nc_info).await?,
+        RepairKind::Hard => repair::plan_hard_repair(local_info, nc_info).await?,
+    };
+
+    Ok(plan.to_string())
+}
+
+// The same commands as stdin over a unix socket. One request per line.
+// Each reply ends with an empty line.
+pub async fn serve_socket(path: &str, tx: mpsc::Sender<Command>) -> Result<()> {
+    // left by the last run.
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    info!("control socket: {}", path);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(stream, tx).await {
+                debug!("control socket: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn serve_connection(stream: UnixStream, tx: mpsc::Sender<Command>) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(ln) = lines.next_line().await? {
+        // an empty line means "terminate" on stdin. It is not taken from the socket.
+        if ln.trim().is_empty() {
+            continue;
+        }
+        let reply = dispatch(&ln, &tx)
+            .await
+            .map_err(|_| anyhow!("The main loop is closed."))?
+            .unwrap_or_else(|| "ok".to_string());
+        writer
+            .write_all(format!("{}\n\n", reply).as_bytes())
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn reply_test() {
+        let throttle = Throttle::default();
+        assert_eq!(
+            set_limit(&throttle, LimitKind::Upload, Some(1024)),
+            "limits: up=1024 down=unlimited req=unlimited"
+        );
+        assert!(show_limits(&throttle).starts_with("limits: up=1024"));
+        assert_eq!(set_schedule(&throttle, Vec::new()), "schedule: 0 rules");
+
+        let held = vec![LocalEvent::Delete(PathBuf::from("/a"))];
+        assert_eq!(
+            show_held_deletes(&held),
+            "1 deletions are held.\nDelete(\"/a\")"
+        );
+
+        let failures = FailureRegistry::new();
+        assert_eq!(show_failures(&failures).unwrap(), "no failed operations.");
+        assert_eq!(
+            retry_failures(&failures).unwrap(),
+            "0 failed operations will be retried."
+        );
+    }
+}
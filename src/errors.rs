@@ -1,3 +1,4 @@
+use reqwest::StatusCode;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -6,12 +7,74 @@ pub enum NcsError {
     LockError,
     #[error("Bad status {0}.")]
     BadStatusError(u16),
+    #[error("Unauthorized. Please check NC_USERNAME and NC_PASSWORD.")]
+    UnauthorizedError,
+    #[error("Forbidden.")]
+    ForbiddenError,
+    #[error("Not found on the server.")]
+    NotFoundError,
+    #[error("Conflict. The parent collection may not exist.")]
+    ConflictError,
+    #[error("Precondition failed.")]
+    PreconditionFailedError,
+    #[error("Too many requests.")]
+    TooManyRequestsError,
+    #[error("Insufficient storage on the server.")]
+    InsufficientStorageError,
+    #[error("Service unavailable.")]
+    ServiceUnavailableError,
+    #[error("Server error {0}.")]
+    ServerError(u16),
     #[error("Invalid XML.")]
     InvalidXMLError,
     #[error("Failed upgrade Weak")]
     WeakUpgradeError,
     #[error("Invalid path. {0}")]
     InvalidPathError(String),
+    #[error("Invalid exclude rules.\n{0}")]
+    InvalidExcludeError(String),
     #[error("Network is offline.")]
     NetworkOfflineError,
+    #[error("Rejected by the forbidden filename rules of the server. {0}")]
+    ForbiddenNameError(String),
+}
+
+impl NcsError {
+    pub fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED => Self::UnauthorizedError,
+            StatusCode::FORBIDDEN => Self::ForbiddenError,
+            StatusCode::NOT_FOUND => Self::NotFoundError,
+            StatusCode::CONFLICT => Self::ConflictError,
+            StatusCode::PRECONDITION_FAILED => Self::PreconditionFailedError,
+            StatusCode::TOO_MANY_REQUESTS => Self::TooManyRequestsError,
+            StatusCode::INSUFFICIENT_STORAGE => Self::InsufficientStorageError,
+            StatusCode::SERVICE_UNAVAILABLE => Self::ServiceUnavailableError,
+            s if s.is_server_error() => Self::ServerError(s.as_u16()),
+            s => Self::BadStatusError(s.as_u16()),
+        }
+    }
+
+    // the variant name without its content. ex) "ServerError"
+    pub fn name(&self) -> String {
+        let name = format!("{:?}", self);
+        name.split('(').next().unwrap_or("").to_string()
+    }
+
+    // the HTTP status this error came from.
+    pub fn status_code(&self) -> Option<u16> {
+        let status = match self {
+            Self::BadStatusError(s) | Self::ServerError(s) => *s,
+            Self::UnauthorizedError => 401,
+            Self::ForbiddenError => 403,
+            Self::NotFoundError => 404,
+            Self::ConflictError => 409,
+            Self::PreconditionFailedError => 412,
+            Self::TooManyRequestsError => 429,
+            Self::InsufficientStorageError => 507,
+            Self::ServiceUnavailableError => 503,
+            _ => return None,
+        };
+        Some(status)
+    }
 }
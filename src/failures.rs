@@ -0,0 +1,401 @@
+use crate::errors::NcsError::{self, *};
+use crate::local_listen::{deal_local_event, LocalEvent};
+use crate::meta::*;
+use crate::metrics::METRICS;
+use crate::nc_listen::{self, NCEvent};
+use crate::*;
+use anyhow::Result;
+use chrono::{DateTime, Duration, Local};
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+const BASE_DELAY_SECS: i64 = 30;
+const MAX_DELAY_SECS: i64 = 60 * 60;
+// permanent errors are given up (and flagged) after this number of attempts.
+const PERMANENT_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Clone)]
+pub enum FailedOp {
+    // server -> local. ex) "/a/b.txt"
+    Download(String),
+    // local -> server. retried as the same event.
+    Local(LocalEvent),
+}
+
+impl FailedOp {
+    pub fn path(&self) -> String {
+        match self {
+            FailedOp::Download(p) => add_head_slash(p),
+            FailedOp::Local(LocalEvent::Create(p))
+            | FailedOp::Local(LocalEvent::Modify(p))
+            | FailedOp::Local(LocalEvent::Delete(p))
+            | FailedOp::Local(LocalEvent::Move(_, p)) => path2str(p),
+        }
+    }
+
+    pub fn action(&self) -> &'static str {
+        match self {
+            FailedOp::Download(_) => "download",
+            FailedOp::Local(LocalEvent::Create(_)) | FailedOp::Local(LocalEvent::Modify(_)) => {
+                "upload"
+            }
+            FailedOp::Local(LocalEvent::Delete(_)) => "delete",
+            FailedOp::Local(LocalEvent::Move(..)) => "move",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ErrorKind {
+    Http { status: u16 },
+    Ncs { error: String },
+    Io { error: String },
+    Other,
+}
+
+impl ErrorKind {
+    pub fn from_error(e: &anyhow::Error) -> Self {
+        for cause in e.chain() {
+            if let Some(e) = cause.downcast_ref::<NcsError>() {
+                return match e.status_code() {
+                    Some(status) => ErrorKind::Http { status },
+                    None => ErrorKind::Ncs { error: e.name() },
+                };
+            }
+            if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+                if let Some(status) = e.status() {
+                    return ErrorKind::Http {
+                        status: status.as_u16(),
+                    };
+                }
+            }
+            if let Some(e) = cause.downcast_ref::<io::Error>() {
+                return ErrorKind::Io {
+                    error: format!("{:?}", e.kind()),
+                };
+            }
+        }
+
+        ErrorKind::Other
+    }
+
+    // Retrying doesn't help. The user has to do something.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            ErrorKind::Http { status } => matches!(status, 400 | 403 | 404 | 405 | 413 | 415 | 507),
+            ErrorKind::Ncs { error } => {
+                error == "InvalidPathError" || error == "ForbiddenNameError"
+            }
+            ErrorKind::Io { error } => error == "PermissionDenied" || error == "InvalidInput",
+            ErrorKind::Other => false,
+        }
+    }
+
+    // only a rename by the user makes it succeed. Flagged at the first failure.
+    pub fn needs_rename(&self) -> bool {
+        matches!(self, ErrorKind::Ncs { error } if error == "ForbiddenNameError")
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Http { status } => write!(f, "http {}", status),
+            ErrorKind::Ncs { error } => write!(f, "{}", error),
+            ErrorKind::Io { error } => write!(f, "io {}", error),
+            ErrorKind::Other => write!(f, "other"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Failure {
+    pub op: FailedOp,
+    pub kind: ErrorKind,
+    pub message: String,
+    pub attempts: u32,
+    pub next_retry: DateTime<Local>,
+    // a permanent failure which was given up. It is only retried by the user.
+    pub flagged: bool,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}  {}  {}  attempts: {}",
+            self.op.path(),
+            self.op.action(),
+            self.kind,
+            self.attempts
+        )?;
+        if self.flagged {
+            write!(f, "  NEEDS ATTENTION")
+        } else {
+            write!(f, "  next: {}", self.next_retry.format("%Y-%m-%d %H:%M:%S"))
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct FailureReport {
+    pub path: String,
+    pub action: &'static str,
+    pub kind: ErrorKind,
+    pub message: String,
+    pub attempts: u32,
+    // RFC 3339. None if flagged.
+    pub next_retry: Option<String>,
+    pub flagged: bool,
+}
+
+impl From<&Failure> for FailureReport {
+    fn from(f: &Failure) -> Self {
+        Self {
+            path: f.op.path(),
+            action: f.op.action(),
+            kind: f.kind.clone(),
+            message: f.message.clone(),
+            attempts: f.attempts,
+            next_retry: (!f.flagged).then(|| f.next_retry.to_rfc3339()),
+            flagged: f.flagged,
+        }
+    }
+}
+
+fn backoff(attempts: u32) -> Duration {
+    let secs = BASE_DELAY_SECS.saturating_mul(1 << attempts.saturating_sub(1).min(16));
+    Duration::seconds(secs.min(MAX_DELAY_SECS))
+}
+
+// failed operations keyed by path. Shared by clones.
+#[derive(Debug, Clone, Default)]
+pub struct FailureRegistry {
+    items: Arc<Mutex<HashMap<String, Failure>>>,
+}
+
+impl FailureRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, op: FailedOp, e: &anyhow::Error) -> Result<()> {
+        self.record_at(op, e, Local::now())
+    }
+
+    fn record_at(&self, op: FailedOp, e: &anyhow::Error, now: DateTime<Local>) -> Result<()> {
+        let mut items = self.items.lock().map_err(|_| LockError)?;
+        let path = op.path();
+        let attempts = items.get(&path).map_or(0, |f| f.attempts) + 1;
+        let kind = ErrorKind::from_error(e);
+        METRICS.record_error(e);
+        let flagged =
+            kind.is_permanent() && (attempts >= PERMANENT_ATTEMPTS || kind.needs_rename());
+        if flagged {
+            warn!(
+                "{} {} keeps failing ({}). It is not retried any more. | {:#}",
+                op.action(),
+                path,
+                kind,
+                e
+            );
+        }
+
+        items.insert(
+            path,
+            Failure {
+                op,
+                kind,
+                message: format!("{:#}", e),
+                attempts,
+                next_retry: now + backoff(attempts),
+                flagged,
+            },
+        );
+
+        Ok(())
+    }
+
+    // records the result of a (re)tried operation.
+    pub fn settle<T>(&self, op: FailedOp, res: &Result<T>) -> Result<()> {
+        match res {
+            Ok(_) => self.resolve(&op.path()),
+            Err(e) => self.record(op, e),
+        }
+    }
+
+    // the operation on the path succeeded.
+    pub fn resolve(&self, path: &str) -> Result<()> {
+        let mut items = self.items.lock().map_err(|_| LockError)?;
+        if items.remove(&add_head_slash(path)).is_some() {
+            info!("{} is recovered.", add_head_slash(path));
+        }
+
+        Ok(())
+    }
+
+    // The registry is only bookkeeping. These don't stop the sync when it can't be updated.
+    pub fn record_or_warn(&self, op: FailedOp, e: &anyhow::Error) {
+        let path = op.path();
+        warn_unrecorded(&path, self.record(op, e));
+    }
+
+    pub fn resolve_or_warn(&self, path: &str) {
+        warn_unrecorded(path, self.resolve(path));
+    }
+
+    pub fn settle_or_warn<T>(&self, op: FailedOp, res: &Result<T>) {
+        let path = op.path();
+        warn_unrecorded(&path, self.settle(op, res));
+    }
+
+    // operations to retry now. They stay registered until they are resolved.
+    pub fn take_due(&self) -> Result<Vec<FailedOp>> {
+        self.take_due_at(Local::now())
+    }
+
+    fn take_due_at(&self, now: DateTime<Local>) -> Result<Vec<FailedOp>> {
+        let items = self.items.lock().map_err(|_| LockError)?;
+        let mut due = items
+            .values()
+            .filter(|f| !f.flagged && f.next_retry <= now)
+            .collect::<Vec<_>>();
+        due.sort_by_key(|f| f.next_retry);
+
+        Ok(due.into_iter().map(|f| f.op.clone()).collect())
+    }
+
+    // retry everything at the next tick, flagged ones too.
+    pub fn retry_all(&self) -> Result<usize> {
+        let mut items = self.items.lock().map_err(|_| LockError)?;
+        let now = Local::now();
+        for f in items.values_mut() {
+            f.flagged = false;
+            f.attempts = 0;
+            f.next_retry = now;
+        }
+
+        Ok(items.len())
+    }
+
+    pub fn count(&self) -> usize {
+        self.items.lock().map_or(0, |items| items.len())
+    }
+
+    // sorted by path.
+    pub fn list(&self) -> Result<Vec<Failure>> {
+        let items = self.items.lock().map_err(|_| LockError)?;
+        let mut list = items.values().cloned().collect::<Vec<_>>();
+        list.sort_by_key(|f| f.op.path());
+
+        Ok(list)
+    }
+}
+
+fn warn_unrecorded(path: &str, res: Result<()>) {
+    if let Err(e) = res {
+        warn!("{}: can't record the result. | {:?}", path, e);
+    }
+}
+
+pub async fn retry(
+    op: FailedOp,
+    tree: &mut Tree,
+    nc_info: &NCInfo,
+    local_info: &LocalInfo,
+    nc2l_cancel_map: &mut HashMap<String, usize>,
+    l2nc_cancel_set: &mut HashSet<NCEvent>,
+) -> Result<()> {
+    debug!("retry: {} {}", op.action(), op.path());
+    match op {
+        FailedOp::Download(path) => {
+            // removed from the tree since then.
+            let entry = match tree.get_mut(&path) {
+                Some(e) => e,
+                None => return Ok(()),
+            };
+            if entry.type_.is_dir() {
+                return Ok(());
+            }
+            entry.type_ = EntryType::File { etag: None };
+
+            let res =
+                nc_listen::download_file_with_check_etag(nc_info, local_info, tree, &path, false)
+                    .await;
+            if let Some(entry) = tree.get_mut(&path) {
+                entry.status = match res {
+                    Ok(_) => EntryStatus::UpToDate,
+                    Err(_) => EntryStatus::Error,
+                };
+            }
+            if let Some(item) = res? {
+                let counter = nc2l_cancel_map.entry(item).or_insert(0);
+                *counter += 1;
+            }
+
+            Ok(())
+        }
+        FailedOp::Local(ev) => {
+            deal_local_event(
+                ev,
+                tree,
+                nc_info,
+                local_info,
+                nc2l_cancel_map,
+                l2nc_cancel_set,
+            )
+            .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failure_registry_test() {
+        let registry = FailureRegistry::new();
+        let now = Local::now();
+
+        let e = anyhow::Error::from(ForbiddenError).context("upload failed");
+        assert_eq!(ErrorKind::from_error(&e), ErrorKind::Http { status: 403 });
+        let op = FailedOp::Local(LocalEvent::Create("a/b.txt".into()));
+        for i in 1..=PERMANENT_ATTEMPTS {
+            registry.record_at(op.clone(), &e, now).unwrap();
+            let f = &registry.list().unwrap()[0];
+            assert_eq!(f.attempts, i);
+            assert_eq!(f.flagged, i == PERMANENT_ATTEMPTS);
+        }
+        // flagged ones are not retried.
+        assert!(registry
+            .take_due_at(now + Duration::days(1))
+            .unwrap()
+            .is_empty());
+
+        let e = anyhow::Error::from(io::Error::from(io::ErrorKind::TimedOut));
+        registry
+            .record_at(FailedOp::Download("c".to_string()), &e, now)
+            .unwrap();
+        assert!(registry.take_due_at(now).unwrap().is_empty());
+        let due = registry.take_due_at(now + backoff(1)).unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].path(), "/c");
+
+        registry.resolve("/c").unwrap();
+        assert_eq!(registry.list().unwrap().len(), 1);
+
+        // retrying doesn't help until it's renamed.
+        let e = anyhow::Error::from(ForbiddenNameError("\".htaccess\"".to_string()));
+        let op = FailedOp::Local(LocalEvent::Create(".htaccess".into()));
+        registry.record_at(op, &e, now).unwrap();
+        assert!(registry.list().unwrap()[0].flagged);
+        assert_eq!(backoff(20), Duration::seconds(MAX_DELAY_SECS));
+    }
+}
@@ -1,4 +1,8 @@
+use crate::history::{HistoryRecord, Source};
+use crate::logging::sync_event;
 use crate::meta::LocalInfo;
+use crate::path2str;
+use crate::stash::{self, Duplicate, StashCause, StashKind, StashReason};
 use anyhow::Result;
 use chrono::prelude::*;
 use fs_extra::dir::CopyOptions;
@@ -13,24 +17,97 @@ pub fn save_file<R: io::Read + ?Sized>(
     r: &mut R,
     filename: &str,
     use_stash: bool,
+    cause: StashCause,
     local_info: &LocalInfo,
 ) -> Result<()> {
     debug!("save_file: {}", filename);
 
-    let p = path::Path::new(filename);
+    set_aside(path::Path::new(filename), use_stash, cause, local_info)?;
+
+    let mut out = fs::File::create(filename).map_err(|e| anyhow!("{:?} | {:?}", filename, e))?;
+    io::copy(r, &mut out).map_err(|e| anyhow!("{:?} | {:?}", filename, e))?;
+
+    Ok(())
+}
+
+// moves the already written `tmp` to `filename`, in place of what is there.
+pub fn save_file_from(
+    tmp: &path::Path,
+    filename: &str,
+    use_stash: bool,
+    cause: StashCause,
+    local_info: &LocalInfo,
+) -> Result<()> {
+    debug!("save_file_from: {:?} -> {}", tmp, filename);
+
+    set_aside(path::Path::new(filename), use_stash, cause, local_info)?;
+
+    fs::rename(tmp, filename).map_err(|e| anyhow!("{:?} | {:?}", filename, e))?;
+
+    Ok(())
+}
+
+// stashes the file at `p` before it is overwritten.
+fn set_aside(
+    p: &path::Path,
+    use_stash: bool,
+    cause: StashCause,
+    local_info: &LocalInfo,
+) -> Result<()> {
     if p.exists() {
-        autostash_item(p, local_info).map_err(|e| anyhow!("{:?} | {:?}", filename, e))?;
+        autostash_item(p, cause, local_info).map_err(|e| anyhow!("{:?} | {:?}", p, e))?;
         if use_stash {
-            stash_item(p, local_info).map_err(|e| anyhow!("{:?} | {:?}", filename, e))?;
+            stash_item(p, cause, local_info).map_err(|e| anyhow!("{:?} | {:?}", p, e))?;
         }
     }
 
-    let mut out = fs::File::create(filename).map_err(|e| anyhow!("{:?} | {:?}", filename, e))?;
-    io::copy(r, &mut out).map_err(|e| anyhow!("{:?} | {:?}", filename, e))?;
+    Ok(())
+}
+
+// makes a symlink to `target` at `filename`, in place of what is there.
+// A symlink already there has nothing to stash.
+pub fn save_link(
+    target: &path::Path,
+    filename: &str,
+    use_stash: bool,
+    cause: StashCause,
+    local_info: &LocalInfo,
+) -> Result<()> {
+    debug!("save_link: {} -> {:?}", filename, target);
+
+    let p = path::Path::new(filename);
+    if let Ok(meta) = fs::symlink_metadata(p) {
+        if !meta.file_type().is_symlink() {
+            autostash_item(p, cause, local_info)
+                .map_err(|e| anyhow!("{:?} | {:?}", filename, e))?;
+            if use_stash {
+                stash_item(p, cause, local_info)
+                    .map_err(|e| anyhow!("{:?} | {:?}", filename, e))?;
+            }
+        }
+        if meta.is_dir() {
+            fs::remove_dir_all(p).map_err(|e| anyhow!("{:?} | {:?}", filename, e))?;
+        } else {
+            fs::remove_file(p).map_err(|e| anyhow!("{:?} | {:?}", filename, e))?;
+        }
+    }
+
+    make_symlink(target, p).map_err(|e| anyhow!("{:?} | {:?}", filename, e))?;
 
     Ok(())
 }
 
+#[cfg(unix)]
+fn make_symlink(target: &path::Path, link: &path::Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+// symlinks need privileges on Windows. The link-descriptor file is kept as it is.
+#[cfg(not(unix))]
+fn make_symlink(target: &path::Path, link: &path::Path) -> io::Result<()> {
+    fs::write(link, crate::symlinks::descriptor(target))
+}
+
 pub fn create_dir_all<T>(dir_path: T) -> Result<()>
 where
     T: AsRef<path::Path> + Debug,
@@ -78,10 +155,11 @@ where
         if to_path.as_ref().exists();
         if to_path.as_ref().is_file();
         then {
-            autostash_item(&to_path, local_info)
+            let cause = StashCause::new(StashReason::MoveOverwrite, None);
+            autostash_item(&to_path, cause, local_info)
                 .map_err(|e| anyhow!("{:?} | {:?}", to_path, e))?;
             if use_stash {
-                stash_item(&to_path, local_info)
+                stash_item(&to_path, cause, local_info)
                     .map_err(|e| anyhow!("{:?} | {:?}", to_path, e))?;
             }
         }
@@ -94,11 +172,22 @@ where
     };
     fs_extra::move_items(&[&from_path], &to_path, &options)
         .map_err(|e| anyhow!("{:?}->{:?} | {:?}", from_path, to_path, e))?;
+    sync_event(
+        "move",
+        &log_path(&from_path, local_info),
+        Some(&log_path(&to_path, local_info)),
+        "moved on the server",
+    );
 
     Ok(())
 }
 
-pub fn remove_entry<T>(path: T, use_stash: bool, local_info: &LocalInfo) -> Result<()>
+pub fn remove_entry<T>(
+    path: T,
+    use_stash: bool,
+    cause: StashCause,
+    local_info: &LocalInfo,
+) -> Result<()>
 where
     T: AsRef<path::Path> + Debug,
 {
@@ -111,12 +200,18 @@ where
         return Ok(());
     }
 
-    autostash_item(&path, local_info).map_err(|e| anyhow!("{:?} | {:?}", path, e))?;
+    autostash_item(&path, cause, local_info).map_err(|e| anyhow!("{:?} | {:?}", path, e))?;
     if use_stash {
-        stash_item(&path, local_info).map_err(|e| anyhow!("{:?} | {:?}", path, e))?;
+        stash_item(&path, cause, local_info).map_err(|e| anyhow!("{:?} | {:?}", path, e))?;
     }
 
     fs_extra::remove_items(&[&path]).map_err(|e| anyhow!("{:?} | {:?}", path, e))?;
+    sync_event(
+        "delete",
+        &log_path(&path, local_info),
+        None,
+        cause.reason.describe(),
+    );
 
     Ok(())
 }
@@ -127,93 +222,164 @@ where
 {
     debug!("remove items: len(paths) = {:?}", paths.len());
 
+    let cause = stash::DELETE_CAUSE;
     for path in paths.iter() {
         if path.as_ref().exists() {
-            autostash_item(&path, local_info).map_err(|e| anyhow!("{:?} | {:?}", path, e))?;
+            autostash_item(path, cause, local_info).map_err(|e| anyhow!("{:?} | {:?}", path, e))?;
             if use_stash {
-                stash_item(&path, local_info).map_err(|e| anyhow!("{:?} | {:?}", path, e))?;
+                stash_item(path, cause, local_info).map_err(|e| anyhow!("{:?} | {:?}", path, e))?;
             }
         }
     }
 
     fs_extra::remove_items(paths).map_err(|e| anyhow!("{:?} | {:?}", paths, e))?;
+    for path in paths.iter() {
+        sync_event("delete", &log_path(path, local_info), None, "removed all");
+    }
+
+    Ok(())
+}
+
+// root relative path for the sync log.
+fn log_path<P: AsRef<path::Path>>(p: P, local_info: &LocalInfo) -> String {
+    stash::original_relpath(local_info, p.as_ref())
+        .unwrap_or_else(|_| p.as_ref().to_string_lossy().to_string())
+}
+
+// Put a stashed copy back to `to_path`. An existing item there is autostashed first.
+pub fn restore_from_stash<T, U>(from_path: T, to_path: U, local_info: &LocalInfo) -> Result<()>
+where
+    T: AsRef<path::Path> + Debug,
+    U: AsRef<path::Path> + Debug,
+{
+    debug!("restore_from_stash: {:?} => {:?}", from_path, to_path);
+
+    if to_path.as_ref().exists() {
+        let cause = StashCause::new(StashReason::RestoreOverwrite, None);
+        remove_entry(&to_path, false, cause, local_info)?;
+    }
+    if let Some(parent) = to_path.as_ref().parent() {
+        create_dir_all(parent)?;
+    }
+
+    if from_path.as_ref().is_dir() {
+        let options = CopyOptions {
+            copy_inside: true,
+            ..Default::default()
+        };
+        fs_extra::dir::copy(&from_path, &to_path, &options)
+            .map_err(|e| anyhow!("{:?}->{:?} | {:?}", from_path, to_path, e))?;
+    } else {
+        fs::copy(&from_path, &to_path)
+            .map_err(|e| anyhow!("{:?}->{:?} | {:?}", from_path, to_path, e))?;
+    }
+    sync_event(
+        "restore",
+        &log_path(&to_path, local_info),
+        None,
+        "restored from the stash",
+    );
 
     Ok(())
 }
 
-fn stash_item<P>(path: P, local_info: &LocalInfo) -> Result<()>
+fn stash_item<P>(path: P, cause: StashCause, local_info: &LocalInfo) -> Result<()>
 where
     P: AsRef<path::Path> + Debug,
 {
     let stashpath_name = local_info.get_stashpath_name();
     let stash_folder = path::Path::new(&stashpath_name);
 
-    stash_item_sub(path, stash_folder)?;
+    stash_into(path, stash_folder, StashKind::Stash, cause, local_info)?;
 
     Ok(())
 }
 
-fn autostash_item<P>(path: P, local_info: &LocalInfo) -> Result<()>
+fn autostash_item<P>(path: P, cause: StashCause, local_info: &LocalInfo) -> Result<()>
 where
     P: AsRef<path::Path> + Debug,
 {
     let stashpath_name = local_info.get_autostashpath_name_with_date();
     let stash_folder = path::Path::new(&stashpath_name);
 
-    stash_item_sub(path, stash_folder)?;
-    cleanup_autostash(local_info)?;
+    stash_into(path, stash_folder, StashKind::Autostash, cause, local_info)?;
+    stash::cleanup_if_due(local_info)?;
 
     debug!("autostash_item: {:?}", stash_folder);
 
     Ok(())
 }
 
-use crate::errors::NcsError::InvalidPathError;
-use once_cell::sync::Lazy;
-use regex::Regex;
-
-fn cleanup_autostash(local_info: &LocalInfo) -> Result<()> {
-    #[allow(non_upper_case_globals)]
-    const re: Lazy<Regex> = Lazy::new(|| regex::Regex::new(r"^\d{8}+$").unwrap());
-
-    let autostashpath_name = local_info.get_autostashpath_name();
-    let stash_folder = path::Path::new(&autostashpath_name);
+// copy into the stash and record it. The same content is stored only once.
+fn stash_into<P, Q>(
+    path: P,
+    stash_folder: Q,
+    kind: StashKind,
+    cause: StashCause,
+    local_info: &LocalInfo,
+) -> Result<()>
+where
+    P: AsRef<path::Path> + Debug,
+    Q: AsRef<path::Path> + Debug,
+{
+    let p_ref = path.as_ref();
+    if !p_ref.exists() {
+        debug!("{:?} : not found.", path);
+        return Ok(());
+    }
 
-    for entry in stash_folder.read_dir()? {
-        let entry = entry?;
-        let path = entry.path();
+    let hash = if p_ref.is_file() {
+        Some(stash::content_hash(p_ref)?)
+    } else {
+        None
+    };
+    let duplicate = match &hash {
+        Some(h) => stash::find_duplicate(local_info, p_ref, kind, h)?,
+        None => Duplicate::None,
+    };
 
-        if !path.is_dir() {
+    let stashed = match duplicate {
+        Duplicate::Latest => {
+            debug!("{:?} : same as the latest copy.", path);
             return Ok(());
         }
-
-        let f_name = path
-            .file_name()
-            .ok_or_else(|| InvalidPathError("Invalid file name.".to_string()))?
-            .to_string_lossy();
-        let d = match re.captures(&f_name) {
-            Some(d) => d,
-            None => continue,
-        };
-
-        let d = d.get(0).unwrap().as_str();
-        let d_w = NaiveDate::parse_from_str(d, "%Y%m%d");
-        let d = match d_w {
-            Ok(d) => d,
-            Err(_) => continue,
-        };
-
-        if Local::today().naive_local() - d
-            > chrono::Duration::days(local_info.autostash_keep_span as i64)
-        {
-            fs::remove_dir_all(path)?;
-        }
-    }
+        Duplicate::Stored(stashed) => stashed,
+        Duplicate::None => match stash_item_sub(&path, stash_folder)? {
+            Some(stashed) => stashed,
+            None => return Ok(()),
+        },
+    };
+    let id = stash::record(local_info, p_ref, &stashed, kind, cause, hash)?;
+    let action = match kind {
+        StashKind::Stash => "stash",
+        StashKind::Autostash => "autostash",
+    };
+    let rel_path = log_path(p_ref, local_info);
+    sync_event(
+        action,
+        &rel_path,
+        None,
+        &format!("{} (id {})", cause.reason.describe(), id),
+    );
+    let metadir_name = local_info.get_metadir_name();
+    let stash_location = stashed
+        .strip_prefix(&metadir_name)
+        .map(path2str)
+        .unwrap_or_else(|_| path2str(&stashed));
+    let source = Source::Stash {
+        reason: cause.reason.describe().to_string(),
+    };
+    local_info.history.append(
+        HistoryRecord::new(&rel_path, source, action)
+            .etags(cause.etag.map(|e| e.to_string()), None)
+            .stash(id, &stash_location),
+    );
 
     Ok(())
 }
 
-fn stash_item_sub<P, Q>(path: P, stash_folder: Q) -> Result<()>
+// returns the path of the stashed copy.
+fn stash_item_sub<P, Q>(path: P, stash_folder: Q) -> Result<Option<path::PathBuf>>
 where
     P: AsRef<path::Path> + Debug,
     Q: AsRef<path::Path> + Debug,
@@ -222,7 +388,7 @@ where
 
     if !path.as_ref().exists() {
         debug!("{:?} : not found.", path);
-        return Ok(());
+        return Ok(None);
     }
 
     fs::create_dir_all(&stash_folder)?;
@@ -232,7 +398,7 @@ where
 
     let original_name = match name {
         Some(v) => v,
-        _ => return Ok(()),
+        _ => return Ok(None),
     }
     .to_string();
 
@@ -244,10 +410,10 @@ where
     let dt = Local::now();
     let name = format!("{}_{}{}", original_name, dt.format("%Y%m%d%H%M%S%3f"), ext);
     let stash_folder = stash_folder.as_ref();
+    let target_path = stash_folder.join(name);
 
     if p_ref.is_file() {
-        let target_path = stash_folder.join(name);
-        fs::copy(path, target_path)?;
+        fs::copy(&path, &target_path)?;
     } else {
         // let target_path = stash_folder.join(name);
         fs_extra::copy_items(
@@ -264,9 +430,8 @@ where
                 .file_name()
                 .ok_or_else(|| anyhow!("{:?} | invalid dir", p_ref))?,
         );
-        let target_path = stash_folder.join(name);
         fs::rename(&from_path, &target_path)?;
     }
 
-    Ok(())
+    Ok(Some(target_path))
 }
@@ -0,0 +1,245 @@
+use crate::errors::NcsError::*;
+use crate::*;
+use anyhow::Result;
+use chrono::Local;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// what caused an operation.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Source {
+    // `event` is the LocalEvent variant. ex) "create"
+    Local { event: String },
+    // activities of the server. The id is of the latest activity of the poll.
+    Server { activity_id: Option<String> },
+    Refresh,
+    // "soft", "normal", "hard", "excludes" or "rebuild"
+    Repair { kind: String },
+    // a copy made before the local file is overwritten or deleted.
+    Stash { reason: String },
+}
+
+impl Source {
+    pub fn local(event: &str) -> Self {
+        Self::Local {
+            event: event.to_string(),
+        }
+    }
+
+    pub fn server(activity_id: &str) -> Self {
+        Self::Server {
+            activity_id: Some(activity_id.to_string()).filter(|id| !id.is_empty()),
+        }
+    }
+
+    pub fn repair(kind: &str) -> Self {
+        Self::Repair {
+            kind: kind.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Local { event } => write!(f, "local ({})", event),
+            Source::Server {
+                activity_id: Some(id),
+            } => write!(f, "server (activity {})", id),
+            Source::Server { activity_id: None } => write!(f, "server"),
+            Source::Refresh => write!(f, "refresh"),
+            Source::Repair { kind } => write!(f, "{} repair", kind),
+            Source::Stash { reason } => write!(f, "stash ({})", reason),
+        }
+    }
+}
+
+// A line of `.ncs/history.jsonl`. Paths are root relative with a head slash.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HistoryRecord {
+    pub time: String,
+    pub path: String,
+    pub source: Source,
+    pub action: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old_etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_etag: Option<String>,
+    // relative to the meta dir. ex) "/autostash/20211010/a.txt"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stash_id: Option<u64>,
+}
+
+impl HistoryRecord {
+    pub fn new(path: &str, source: Source, action: &str) -> Self {
+        Self {
+            time: Local::now().to_rfc3339(),
+            path: add_head_slash(path.trim_start_matches('/')),
+            source,
+            action: action.to_string(),
+            to: None,
+            old_etag: None,
+            new_etag: None,
+            stash: None,
+            stash_id: None,
+        }
+    }
+
+    pub fn to(mut self, to: &str) -> Self {
+        self.to = Some(add_head_slash(to.trim_start_matches('/')));
+        self
+    }
+
+    pub fn etags(mut self, old_etag: Option<String>, new_etag: Option<String>) -> Self {
+        self.old_etag = old_etag;
+        self.new_etag = new_etag;
+        self
+    }
+
+    pub fn stash(mut self, id: u64, location: &str) -> Self {
+        self.stash_id = Some(id);
+        self.stash = Some(location.to_string());
+        self
+    }
+
+    // the path itself, a child of it, or moved from/to it.
+    fn concerns(&self, path: &str) -> bool {
+        let under = |p: &str| p == path || p.starts_with(&format!("{}/", path)) || path == "/";
+        under(&self.path) || self.to.as_deref().is_some_and(under)
+    }
+}
+
+impl fmt::Display for HistoryRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:<14} {}", self.time, self.action, self.path)?;
+        if let Some(to) = &self.to {
+            write!(f, " -> {}", to)?;
+        }
+        write!(f, "  by {}", self.source)?;
+        if self.old_etag.is_some() || self.new_etag.is_some() {
+            write!(
+                f,
+                "  etag {} -> {}",
+                self.old_etag.as_deref().unwrap_or("-"),
+                self.new_etag.as_deref().unwrap_or("-")
+            )?;
+        }
+        if let (Some(id), Some(stash)) = (self.stash_id, &self.stash) {
+            write!(f, "  stash id {} at {}", id, stash)?;
+        }
+
+        Ok(())
+    }
+}
+
+// `.ncs/history.jsonl`, append only. Shared by the clones of LocalInfo.
+#[derive(Clone, Debug)]
+pub struct HistoryStore {
+    file: PathBuf,
+    lock: Arc<Mutex<()>>,
+}
+
+impl HistoryStore {
+    pub fn new<P: AsRef<Path>>(file: P) -> Self {
+        Self {
+            file: file.as_ref().to_path_buf(),
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    fn append_raw(&self, record: &HistoryRecord) -> Result<()> {
+        let _guard = self.lock.lock().map_err(|_| LockError)?;
+        // the meta dir may be removed by a hard repair.
+        if let Some(parent) = self.file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.file)?;
+        writeln!(f, "{}", serde_json::to_string(record)?)?;
+
+        Ok(())
+    }
+
+    // The history is not worth stopping the sync. Errors are only logged.
+    pub fn append(&self, record: HistoryRecord) {
+        if let Err(e) = self.append_raw(&record) {
+            warn!("can't write the history. {:?} | {:?}", record, e);
+        }
+    }
+
+    // oldest first. Broken lines are skipped.
+    pub fn query(&self, path: &str) -> Result<Vec<HistoryRecord>> {
+        let path =
+            add_head_slash(drop_slash(path.trim_start_matches('/'), &RE_HAS_LAST_SLASH).as_str());
+        let content = {
+            let _guard = self.lock.lock().map_err(|_| LockError)?;
+            match fs::read_to_string(&self.file) {
+                Ok(s) => s,
+                Err(_) => return Ok(Vec::new()),
+            }
+        };
+
+        let records = content
+            .lines()
+            .filter_map(|l| serde_json::from_str::<HistoryRecord>(l).ok())
+            .filter(|r| r.concerns(&path))
+            .collect();
+
+        Ok(records)
+    }
+}
+
+// the etag of the file entry at `path`. None for a directory or a missing entry.
+pub fn tree_etag(tree: &Tree, path: &str) -> Option<String> {
+    tree.get(path).and_then(entry_etag)
+}
+
+pub fn entry_etag(entry: &Entry) -> Option<String> {
+    Some(entry.type_.get_etag()).filter(|e| !e.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_store_test() {
+        let dir = std::env::temp_dir().join(format!("ncs_history_test_{}", std::process::id()));
+        let store = HistoryStore::new(dir.join("history.jsonl"));
+        assert!(store.query("/a").unwrap().is_empty());
+
+        store.append(
+            HistoryRecord::new("a/b.txt", Source::server("42"), "download")
+                .etags(Some("e1".to_string()), Some("e2".to_string())),
+        );
+        store.append(HistoryRecord::new("/c", Source::local("move"), "move_remote").to("/a/c"));
+        store.append(HistoryRecord::new("/ab", Source::Refresh, "download"));
+
+        let records = store.query("/a/").unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].path, "/a/b.txt");
+        assert_eq!(
+            records[0].source,
+            Source::Server {
+                activity_id: Some("42".to_string())
+            }
+        );
+        assert_eq!(records[1].to.as_deref(), Some("/a/c"));
+        assert_eq!(store.query("/").unwrap().len(), 3);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
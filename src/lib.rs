@@ -3,10 +3,10 @@ use anyhow::{Context, Result};
 use log::{debug, info};
 use once_cell::sync::Lazy;
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex, Weak};
+use std::sync::Arc;
 // use notify::DebouncedEvent;
 
 #[macro_use]
@@ -16,32 +16,66 @@ extern crate if_chain;
 #[macro_use]
 extern crate async_recursion;
 
+pub mod attrs;
+pub mod capabilities;
+pub mod control;
 pub mod errors;
+pub mod failures;
 mod fileope;
+pub mod history;
 pub mod local_listen;
+pub mod logging;
 pub mod messaging;
 pub mod meta;
+pub mod metrics;
+pub mod mtime;
+pub mod names;
+pub mod nc_client;
 pub mod nc_listen;
 pub mod network;
+pub mod plan;
 pub mod repair;
-
+pub mod stash;
+pub mod status;
+pub mod symlinks;
+pub mod throttle;
+pub mod trashbin;
+pub mod tree_store;
+pub mod versions;
+
+// Owned by the main loop. Nothing is locked, readers outside of it get a snapshot.
 pub struct PublicResource {
-    pub root: ArcEntry,
+    tree: Arc<Tree>,
     pub nc_state: nc_listen::NCState,
     // pub local_event_que: VecDeque<notify::DebouncedEvent>,
 }
 
-pub type ArcResource = Arc<Mutex<PublicResource>>;
-pub type WeakResource = Weak<Mutex<PublicResource>>;
-
 impl PublicResource {
-    pub fn new(root: ArcEntry, nc_state: nc_listen::NCState) -> Self {
+    pub fn new(tree: Tree, nc_state: nc_listen::NCState) -> Self {
         Self {
-            root,
+            tree: Arc::new(tree),
             nc_state,
             // local_event_que: VecDeque::new(),
         }
     }
+
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+
+    // copies the tree only while a snapshot is alive.
+    pub fn tree_mut(&mut self) -> &mut Tree {
+        Arc::make_mut(&mut self.tree)
+    }
+
+    pub fn snapshot(&self) -> Arc<Tree> {
+        self.tree.clone()
+    }
+
+    pub fn set_tree(&mut self, mut tree: Tree) {
+        tree.take_place_of(&self.tree);
+        self.tree = Arc::new(tree);
+    }
 }
 
 use errors::NcsError::*;
@@ -136,7 +170,8 @@ impl EntryType {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum EntryStatus {
     UpToDate,
     NeedUpdate,
@@ -153,25 +188,34 @@ impl fmt::Display for EntryStatus {
     }
 }
 
+// index of an entry in the arena of a Tree.
+// It's reused after the entry is removed, so keep paths instead of ids beyond an operation.
+pub type EntryId = usize;
+
 #[derive(Debug, Clone)]
 pub struct Entry {
     name: String,
-    parent: Option<WeakEntry>,
+    // the name on the server if it differs from the local one. (see names)
+    remote_name: Option<String>,
+    parent: Option<EntryId>,
     pub status: EntryStatus,
     pub type_: EntryType,
-    children: HashMap<String, ArcEntry>,
+    // the modification time of the local file when it was last synced. (see mtime)
+    pub mtime: Option<i64>,
+    children: BTreeMap<String, EntryId>,
 }
 
-pub type ArcEntry = Arc<Mutex<Entry>>;
-pub type WeakEntry = Weak<Mutex<Entry>>;
-
 impl fmt::Display for Entry {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.status, self.get_name())?;
+        if let Some(remote_name) = &self.remote_name {
+            write!(f, " (remote: {})", remote_name)?;
+        }
         if let EntryType::File { ref etag } = self.type_ {
-            write!(f, "{}{} etag: {:?}", self.status, self.get_name(), etag)
-        } else {
-            write!(f, "{}{}", self.status, self.get_name())
+            write!(f, " etag: {:?}", etag)?;
         }
+
+        Ok(())
     }
 }
 
@@ -180,10 +224,12 @@ impl Entry {
         let name = drop_slash(&name, &RE_HAS_LAST_SLASH);
         Entry {
             name,
+            remote_name: None,
             type_,
+            mtime: None,
             status: EntryStatus::NeedUpdate,
             parent: None,
-            children: HashMap::new(),
+            children: BTreeMap::new(),
         }
     }
 
@@ -200,336 +246,504 @@ impl Entry {
         self.name.clone()
     }
 
-    pub fn set_name(&mut self, new_name: &str) {
-        let new_name = drop_slash(new_name, &RE_HAS_LAST_SLASH);
-        self.name = new_name;
+    pub fn get_remote_name(&self) -> String {
+        let s = match &self.type_ {
+            &EntryType::Directory => "/",
+            _ => "",
+        };
+
+        format!("{}{}", self.get_remote_raw_name(), s)
     }
 
-    pub fn is_root(&self) -> bool {
-        self.name == "" && self.parent.is_none()
+    pub fn get_remote_raw_name(&self) -> String {
+        self.remote_name
+            .clone()
+            .unwrap_or_else(|| self.name.clone())
     }
 
-    pub fn get_parent(entry: &ArcEntry) -> Result<Option<ArcEntry>> {
-        let entry = entry.lock().map_err(|_| LockError)?;
-        let p = match entry.parent.as_ref() {
-            Some(p) => p,
-            _ => return Ok(None),
-        };
-        Ok(Some(p.upgrade().with_context(|| WeakUpgradeError)?))
-    }
-
-    // only can be used when it's ancestors are not locked.
-    // for example, you can't use it in recursive context.
-    pub fn get_path(entry: &ArcEntry) -> Result<String> {
-        let mut parent_names = Vec::new();
-        let mut p = entry.clone();
-        while let Some(q) = Entry::get_parent(&p)? {
-            let name = { q.lock().map_err(|_| LockError)?.get_name() };
-            parent_names.push(name);
-            p = q;
-        }
-        parent_names.reverse();
-        let entry = entry.lock().map_err(|_| LockError)?;
-        Ok(format!("{}{}", parent_names.join(""), entry.get_raw_name()))
-    }
-
-    pub fn append_child(parent: &ArcEntry, child: ArcEntry) -> Result<()> {
-        let weak_parent = Arc::downgrade(parent);
-        let child_name = {
-            let mut child_ref = child.lock().map_err(|_| LockError)?;
-            child_ref.parent = Some(weak_parent);
-            child_ref.get_raw_name()
-        };
-        parent
-            .lock()
-            .map_err(|_| LockError)?
-            .children
-            .insert(child_name, child);
-        Ok(())
+    // kept only if it differs from the local name.
+    pub fn set_remote_name(&mut self, remote_name: &str) {
+        self.remote_name = Some(remote_name.to_string()).filter(|r| r != &self.name);
     }
 
-    // すんごく今更ですがなんで私は最終的にtype_とchildrenを分ける設計にしたのでしょうか...アホか
-    pub fn clear_children_because_wrong_type(&mut self) {
-        if self.type_.is_file() {
-            self.children = HashMap::new();
-        }
+    pub fn with_remote_name(mut self, remote_name: &str) -> Self {
+        self.set_remote_name(remote_name);
+        self
     }
 
-    pub fn get_child(&self, child_name: &str) -> Option<WeakEntry> {
-        const RE_LAST_ITEM: Lazy<Regex> = Lazy::new(|| Regex::new(r".*?([^/\\]*)$").unwrap());
+    // renamed on the local side only. The current name is kept as the remote one.
+    pub fn with_local_name(mut self, local_name: &str) -> Self {
+        let remote_name = self.get_remote_raw_name();
+        self.name = drop_slash(local_name, &RE_HAS_LAST_SLASH);
+        self.set_remote_name(&remote_name);
+        self
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.name.is_empty() && self.parent.is_none()
+    }
+
+    pub fn get_parent(&self) -> Option<EntryId> {
+        self.parent
+    }
+
+    pub fn get_child(&self, child_name: &str) -> Option<EntryId> {
+        static RE_LAST_ITEM: Lazy<Regex> = Lazy::new(|| Regex::new(r".*?([^/\\]*)$").unwrap());
 
         let child_name = RE_LAST_ITEM.replace(child_name, "$1").to_string();
-        self.children.get(&child_name).map(|a| Arc::downgrade(a))
+        self.children.get(&child_name).copied()
     }
 
-    pub fn get_all_children(&self) -> Vec<WeakEntry> {
-        self.children
-            .values()
-            .map(|c| Arc::downgrade(c))
-            .collect::<Vec<_>>()
+    // sorted by name to get the same output every time.
+    pub fn get_all_children(&self) -> Vec<EntryId> {
+        self.children.values().copied().collect()
     }
+}
 
-    pub fn get_tree(&self) -> String {
-        let mut res = String::new();
+// The synced tree. Entries live in an arena and are linked by ids, and every path has an index.
+// Paths are root relative with a head slash, and the root is "/". ex) "/a/b.txt"
+// Readers who must not block the main loop get an `Arc<Tree>` snapshot from PublicResource.
+#[derive(Debug, Clone)]
+pub struct Tree {
+    entries: Vec<Option<Entry>>,
+    free: Vec<EntryId>,
+    index: HashMap<String, EntryId>,
+    // the paths added, removed or changed since the last save. (see tree_store)
+    changed: HashSet<String>,
+}
 
-        self.tree_rec(&mut res, "");
+impl Default for Tree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        res
+impl Tree {
+    pub const ROOT: EntryId = 0;
+
+    pub fn new() -> Self {
+        Self::with_root(Entry::new("".to_string(), EntryType::Directory))
     }
 
-    fn tree_rec(&self, tree: &mut String, indent: &str) {
-        let s = format!("{}\n", self);
-        tree.push_str(s.as_str());
-        let mut ch_iter = self.children.values().peekable();
-        while let Some(c) = ch_iter.next() {
-            if let Ok(c) = c.lock() {
-                let is_last = ch_iter.peek().is_some();
-                tree.push_str(
-                    format!("{}{}", indent, if is_last { "├── " } else { "└── " }).as_str(),
-                );
-                c.tree_rec(
-                    tree,
-                    format!("{}{}   ", indent, if is_last { "|" } else { " " }).as_str(),
-                );
-            }
+    // The root keeps its name and type. A subtree fetched for `graft` has the name of its top.
+    pub fn with_root(mut root: Entry) -> Self {
+        root.parent = None;
+        root.children = BTreeMap::new();
+        let mut index = HashMap::new();
+        index.insert("/".to_string(), Self::ROOT);
+        Self {
+            entries: vec![Some(root)],
+            free: Vec::new(),
+            index,
+            changed: HashSet::new(),
         }
     }
 
-    pub fn prepare_path_vec(path: &str) -> Vec<String> {
-        let path = drop_slash(path, &RE_HAS_LAST_SLASH);
-        let mut path_vec = path
-            .split("/")
-            .map(|s| s.to_string())
-            .collect::<Vec<String>>();
-        if path_vec.len() == 0 {
-            path_vec.push("".to_string());
+    // "a/b/" -> "/a/b", "" -> "/"
+    pub fn normalize_path(path: &str) -> String {
+        add_head_slash(path.trim_start_matches('/').trim_end_matches('/'))
+    }
+
+    fn child_path(parent_path: &str, name: &str) -> String {
+        if parent_path == "/" {
+            format!("/{}", name)
+        } else {
+            format!("{}/{}", parent_path, name)
         }
-        path_vec.reverse();
+    }
 
-        path_vec
+    // the entries under the root. The root itself is not counted.
+    pub fn len(&self) -> usize {
+        self.index.len() - 1
     }
 
-    pub fn get(this: &ArcEntry, path: &str) -> Result<Option<WeakEntry>> {
-        let root_w = Arc::downgrade(this);
-        let root_ref = this.lock().map_err(|_| LockError)?;
-        if !root_ref.is_root() {
-            return Ok(None);
-        }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-        let path_vec = Self::prepare_path_vec(path);
+    pub fn root(&self) -> &Entry {
+        self.entries[Self::ROOT].as_ref().unwrap()
+    }
 
-        if path_vec.len() == 0 {
-            return Ok(None);
-        }
+    pub fn root_mut(&mut self) -> &mut Entry {
+        self.entries[Self::ROOT].as_mut().unwrap()
+    }
 
-        if path_vec.len() == 1 {
-            if path_vec[0] == "" {
-                return Ok(Some(root_w));
-            } else {
-                return Ok(None);
-            }
+    pub fn entry(&self, id: EntryId) -> Option<&Entry> {
+        self.entries.get(id).and_then(|e| e.as_ref())
+    }
+
+    // the entry is saved again, whatever is changed.
+    pub fn entry_mut(&mut self, id: EntryId) -> Option<&mut Entry> {
+        if id != Self::ROOT {
+            let path = self.path_of(id)?;
+            self.changed.insert(path);
         }
+        self.slot_mut(id)
+    }
+
+    // for the links. They are saved by the callers.
+    fn slot_mut(&mut self, id: EntryId) -> Option<&mut Entry> {
+        self.entries.get_mut(id).and_then(|e| e.as_mut())
+    }
+
+    pub fn changes(&self) -> impl Iterator<Item = &String> {
+        self.changed.iter()
+    }
+
+    pub fn has_changes(&self) -> bool {
+        !self.changed.is_empty()
+    }
+
+    pub fn clear_changes(&mut self) {
+        self.changed.clear();
+    }
 
-        root_ref.get_rec(path_vec)
+    // in place of the whole tree. Every entry is saved again, and the ones only in `old` are removed.
+    pub fn take_place_of(&mut self, old: &Tree) {
+        let paths = old.index.keys().chain(old.changed.iter());
+        let paths = paths.chain(self.index.keys()).filter(|p| *p != "/");
+        let paths = paths.cloned().collect::<Vec<_>>();
+        self.changed.extend(paths);
     }
 
-    fn get_rec(&self, mut path_vec: Vec<String>) -> Result<Option<WeakEntry>> {
-        let name_w = path_vec.pop();
-        if name_w.is_none() || self.get_raw_name() != name_w.unwrap() {
-            return Ok(None);
+    pub fn get_id(&self, path: &str) -> Option<EntryId> {
+        self.index.get(&Self::normalize_path(path)).copied()
+    }
+
+    pub fn get(&self, path: &str) -> Option<&Entry> {
+        self.get_id(path).and_then(|id| self.entry(id))
+    }
+
+    pub fn get_mut(&mut self, path: &str) -> Option<&mut Entry> {
+        let id = self.get_id(path)?;
+        self.entry_mut(id)
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.index.contains_key(&Self::normalize_path(path))
+    }
+
+    pub fn path_of(&self, id: EntryId) -> Option<String> {
+        let mut names = Vec::new();
+        let mut e = self.entry(id)?;
+        while let Some(p) = e.parent {
+            names.push(e.name.as_str());
+            e = self.entry(p)?;
         }
+        names.reverse();
+        Some(format!("/{}", names.join("/")))
+    }
 
-        if self.type_.is_file() {
-            return Err(anyhow!("This function can only be called by directory."));
+    pub fn child_by_remote_name(&self, parent: EntryId, remote_name: &str) -> Option<EntryId> {
+        let parent = self.entry(parent)?;
+        // the local name is the same in most cases.
+        let by_name = parent
+            .children
+            .get(remote_name)
+            .copied()
+            .filter(|c| self.entry(*c).is_some_and(|e| e.remote_name.is_none()));
+        by_name.or_else(|| {
+            parent.children.values().copied().find(|c| {
+                self.entry(*c)
+                    .is_some_and(|e| e.remote_name.as_deref() == Some(remote_name))
+            })
+        })
+    }
+
+    pub fn children(&self, id: EntryId) -> Vec<EntryId> {
+        self.entry(id)
+            .map(|e| e.get_all_children())
+            .unwrap_or_default()
+    }
+
+    // `id` itself and everything under it, parents first.
+    pub fn descendants(&self, id: EntryId) -> Vec<EntryId> {
+        let mut res = Vec::new();
+        let mut stack = vec![id];
+        while let Some(i) = stack.pop() {
+            if let Some(e) = self.entry(i) {
+                res.push(i);
+                stack.extend(e.children.values().rev());
+            }
         }
+        res
+    }
 
-        let len = path_vec.len();
-        if let Some(e) = self.children.get(&path_vec[len - 1]) {
-            let e_weak = Arc::downgrade(e);
-            let e = e.lock().map_err(|_| LockError)?;
-            if e.type_.is_file() {
-                Ok(Some(e_weak))
-            } else {
-                // When the path specify directory
-                if len == 1 {
-                    Ok(Some(e_weak))
-                } else {
-                    e.get_rec(path_vec)
-                }
+    fn alloc(&mut self, entry: Entry) -> EntryId {
+        match self.free.pop() {
+            Some(id) => {
+                self.entries[id] = Some(entry);
+                id
+            }
+            None => {
+                self.entries.push(Some(entry));
+                self.entries.len() - 1
             }
-        } else {
-            Ok(None)
         }
     }
 
-    pub fn pop(&mut self, path: &str) -> Result<Option<ArcEntry>> {
-        if !self.is_root() {
-            return Ok(None);
+    // links a new entry under `parent`. A child with the same name is replaced.
+    pub fn add_child(&mut self, parent: EntryId, mut entry: Entry) -> Result<EntryId> {
+        let parent_path = self
+            .path_of(parent)
+            .with_context(|| InvalidPathError(parent.to_string()))?;
+        if self.entry(parent).is_none_or(|p| p.type_.is_file()) {
+            return Err(InvalidPathError(parent_path).into());
         }
 
-        let path_vec = Self::prepare_path_vec(path);
+        let name = entry.get_raw_name();
+        let path = Self::child_path(&parent_path, &name);
+        self.remove(&path);
+
+        entry.parent = Some(parent);
+        entry.children = BTreeMap::new();
+        let id = self.alloc(entry);
+        self.changed.insert(path.clone());
+        self.index.insert(path, id);
+        self.slot_mut(parent).unwrap().children.insert(name, id);
 
-        self.pop_rec(path_vec)
+        Ok(id)
     }
 
-    fn pop_rec(&mut self, mut path_vec: Vec<String>) -> Result<Option<ArcEntry>> {
-        let name_w = path_vec.pop();
-        if name_w.is_none() || self.get_raw_name() != name_w.unwrap() {
-            return Ok(None);
+    // The missing parents are created as directories.
+    // Returns the ids of the created entries, parents first and `entry` last.
+    pub fn insert(&mut self, path: &str, entry: Entry) -> Result<Vec<EntryId>> {
+        let path = Self::normalize_path(path);
+        if self.contains(&path) {
+            return Err(anyhow!("Already Exist!!"));
         }
-
-        if self.type_.is_file() {
-            return Err(anyhow!("This function can only be called by directory."));
+        if path2name(&path) != entry.get_raw_name() {
+            return Err(InvalidPathError(path).into());
         }
 
-        let len = path_vec.len();
-        if len > 1 {
-            // continue phase
-            if let Some(e) = self.children.get(&path_vec[len - 1]) {
-                let mut e = e.lock().map_err(|_| LockError)?;
-                if e.type_.is_file() {
-                    Ok(None)
-                } else {
-                    e.pop_rec(path_vec)
-                }
-            } else {
-                Ok(None)
-            }
-        } else {
-            // pop target
-            let entry = self.children.remove(&path_vec[0]);
+        let mut new_entries = self.make_parents(&path)?;
+        let parent = self.get_id(&parent_path(&path)).unwrap();
+        new_entries.push(self.add_child(parent, entry)?);
+
+        Ok(new_entries)
+    }
 
-            if let Some(entry) = entry {
-                {
-                    let mut entry_ref = entry.lock().map_err(|_| LockError)?;
-                    entry_ref.parent = None;
+    fn make_parents(&mut self, path: &str) -> Result<Vec<EntryId>> {
+        let mut new_entries = Vec::new();
+        let mut cur = Self::ROOT;
+        let names = path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>();
+        for name in names.iter().take(names.len().saturating_sub(1)) {
+            cur = match self.entry(cur).and_then(|e| e.children.get(*name)) {
+                Some(&id) => id,
+                None => {
+                    let id =
+                        self.add_child(cur, Entry::new(name.to_string(), EntryType::Directory))?;
+                    new_entries.push(id);
+                    id
                 }
-                Ok(Some(entry))
-            } else {
-                Ok(None)
+            };
+            if self.entry(cur).unwrap().type_.is_file() {
+                return Err(InvalidPathError(self.path_of(cur).unwrap()).into());
             }
         }
+
+        Ok(new_entries)
     }
 
-    pub fn append(
-        root_entry: &ArcEntry,
-        path: &str,
-        entry: ArcEntry,
-        append_mode: AppendMode,
-        overwrite: bool,
-    ) -> Result<Vec<WeakEntry>> {
-        {
-            let root_ref = root_entry.lock().map_err(|_| LockError)?;
-            if !root_ref.is_root() {
-                return Ok(Vec::new());
+    // removes the entry and everything under it. Returns the detached entry without children.
+    pub fn remove(&mut self, path: &str) -> Option<Entry> {
+        let id = self.get_id(path)?;
+        if id == Self::ROOT {
+            return None;
+        }
+
+        let path = self.path_of(id)?;
+        for i in self.descendants(id) {
+            if let Some(p) = self.path_of(i) {
+                self.index.remove(&p);
+                self.changed.insert(p);
             }
         }
+        for i in self.descendants(id).into_iter().skip(1) {
+            self.entries[i] = None;
+            self.free.push(i);
+        }
+        let mut entry = self.entries[id].take()?;
+        self.free.push(id);
+        if let Some(parent) = entry.parent.take().and_then(|p| self.slot_mut(p)) {
+            parent.children.remove(&entry.name);
+        }
+        debug!("removed {} from the tree", path);
+        entry.children = BTreeMap::new();
 
-        let target_parent_cand =
-            Entry::get(root_entry, drop_slash(path, &RE_HAS_LAST_SLASH).as_str())?;
+        Some(entry)
+    }
 
-        if !overwrite && target_parent_cand.is_some() {
-            return Err(anyhow!("Already Exist!!"));
+    // `mv from to`. Into `to` if it's a directory, otherwise renamed to `to` and overwrite it.
+    // Returns the id of the moved entry and the missing parents created as directories.
+    // Ok(None) if `from` doesn't exist.
+    pub fn move_entry(&mut self, from: &str, to: &str) -> Result<Option<(EntryId, Vec<EntryId>)>> {
+        let id = match self.get_id(from) {
+            Some(id) if id != Self::ROOT => id,
+            _ => return Ok(None),
+        };
+        let name = self.entry(id).unwrap().get_raw_name();
+
+        let to = Self::normalize_path(to);
+        let to = match self.get(&to) {
+            Some(e) if e.type_.is_dir() && self.get_id(&to) != Some(id) => {
+                Self::child_path(&to, &name)
+            }
+            _ => to,
+        };
+        let from_path = self.path_of(id).unwrap();
+        if to == from_path {
+            return Ok(Some((id, Vec::new())));
+        }
+        if to.starts_with(&format!("{}/", from_path)) {
+            return Err(InvalidPathError(to).into());
         }
 
-        let mut path_vec = Self::prepare_path_vec(path);
+        // unlink
+        let paths = self
+            .descendants(id)
+            .into_iter()
+            .filter_map(|i| self.path_of(i).map(|p| (i, p)))
+            .collect::<Vec<_>>();
+        for (_, p) in paths.iter() {
+            self.index.remove(p);
+            self.changed.insert(p.clone());
+        }
+        if let Some(parent) = self.entry(id).unwrap().parent {
+            self.slot_mut(parent).unwrap().children.remove(&name);
+        }
+        self.slot_mut(id).unwrap().parent = None;
+
+        let new_entries = match self.make_parents(&to) {
+            Ok(v) => v,
+            Err(e) => {
+                // put it back
+                let parent = self.get_id(&parent_path(&from_path)).unwrap();
+                self.relink(id, parent, &name, &from_path, &paths);
+                return Err(e);
+            }
+        };
+        self.remove(&to);
+        let new_parent = self.get_id(&parent_path(&to)).unwrap();
+        let new_name = path2name(&to);
+        self.relink(id, new_parent, &new_name, &from_path, &paths);
+
+        Ok(Some((id, new_entries)))
+    }
 
+    fn relink(
+        &mut self,
+        id: EntryId,
+        parent: EntryId,
+        name: &str,
+        old_top: &str,
+        paths: &[(EntryId, String)],
+    ) {
+        let new_top = Self::child_path(&self.path_of(parent).unwrap(), name);
         {
-            let mut entry_ref = entry.lock().map_err(|_| LockError)?;
-            let entry_name = entry_ref.get_raw_name();
-            if &path_vec[0] != &entry_name {
-                match append_mode {
-                    AppendMode::Move => {
-                        if_chain! {
-                            if let Some(w) = target_parent_cand;
-                            if let Some(e) = w.upgrade();
-                            let e_ref = e.lock().map_err(|_| LockError)?;
-                            if e_ref.type_.is_dir();
-                            then {
-                                path_vec.insert(0, entry_name);
-                            } else {
-                                entry_ref.set_name(&path_vec[0]);
-                            }
-                        }
-                    }
-                    AppendMode::Create => return Err(InvalidPathError(path.to_string()).into()),
-                }
+            let entry = self.slot_mut(id).unwrap();
+            // the remote name of a renamed entry is set by the caller if it differs.
+            if entry.name != name {
+                entry.name = name.to_string();
+                entry.remote_name = None;
             }
+            entry.parent = Some(parent);
+        }
+        self.slot_mut(parent)
+            .unwrap()
+            .children
+            .insert(name.to_string(), id);
+        for (i, p) in paths.iter() {
+            let new_path = format!("{}{}", new_top, &p[old_top.len()..]);
+            self.changed.insert(new_path.clone());
+            self.index.insert(new_path, *i);
+        }
+    }
+
+    // puts the whole `sub` at `path` in place of the current entry. Used by the refresh.
+    pub fn graft(&mut self, path: &str, sub: &Tree) -> Result<EntryId> {
+        let path = Self::normalize_path(path);
+        if path == "/" {
+            let old = std::mem::replace(self, sub.clone());
+            self.take_place_of(&old);
+            self.root_mut().name = "".to_string();
+            self.root_mut().remote_name = None;
+            return Ok(Self::ROOT);
         }
 
-        let mut new_local_entries = Vec::new();
-        Self::append_rec(
-            root_entry,
-            path_vec,
-            entry,
-            &mut new_local_entries,
-            append_mode,
-        )?;
+        self.remove(&path);
+        self.make_parents(&path)?;
+        let parent = self.get_id(&parent_path(&path)).unwrap();
+        let top = sub.root().clone().with_local_name(&path2name(&path));
+        let top_id = self.add_child(parent, top)?;
+        self.copy_children(sub, Tree::ROOT, top_id)?;
 
-        Ok(new_local_entries)
+        Ok(top_id)
     }
 
-    fn append_rec(
-        parent_entry: &ArcEntry,
-        mut path_vec: Vec<String>,
-        entry: ArcEntry,
-        new_local_entries: &mut Vec<WeakEntry>,
-        append_mode: AppendMode,
-    ) -> Result<()> {
-        let name_w = path_vec.pop();
-        {
-            let parent_ref = parent_entry.lock().map_err(|_| LockError)?;
-            let parent_name = parent_ref.get_raw_name();
-            if name_w.is_none() || &parent_name != name_w.unwrap().as_str() {
-                return Err(InvalidPathError(format!("{}", parent_name)).into());
-            }
-            if parent_ref.type_.is_file() {
-                return Err(anyhow!("This function can only be called by directory."));
+    fn copy_children(&mut self, sub: &Tree, from: EntryId, to: EntryId) -> Result<()> {
+        for c in sub.children(from) {
+            let id = self.add_child(to, sub.entry(c).unwrap().clone())?;
+            self.copy_children(sub, c, id)?;
+        }
+        Ok(())
+    }
+
+    // すんごく今更ですがなんで私は最終的にtype_とchildrenを分ける設計にしたのでしょうか...アホか
+    pub fn clear_children_because_wrong_type(&mut self, id: EntryId) {
+        let is_file = self.entry(id).is_some_and(|e| e.type_.is_file());
+        if !is_file {
+            return;
+        }
+        for c in self.children(id) {
+            if let Some(p) = self.path_of(c) {
+                self.remove(&p);
             }
         }
+    }
 
-        let len = path_vec.len();
-        if len > 1 {
-            // continue phase
-            let name = path_vec[len - 1].to_string();
-            let e_w = {
-                let parent_ref = parent_entry.lock().map_err(|_| LockError)?;
-                parent_ref.children.get(&name).map(|e| e.clone())
-            };
-            let e = if let Some(e) = e_w {
-                e
-            } else {
-                let new_entry = Arc::new(Mutex::new(Self::new(name, EntryType::Directory)));
-                new_local_entries.push(Arc::downgrade(&new_entry));
-                Self::append_child(parent_entry, new_entry.clone())?;
-                new_entry
-            };
+    pub fn get_tree(&self) -> String {
+        self.get_subtree(Self::ROOT)
+    }
 
-            let (is_file, e_name) = {
-                let e_ref = e.lock().map_err(|_| LockError)?;
-                (e_ref.type_.is_file(), e_ref.get_name())
-            };
-            if is_file {
-                Err(InvalidPathError(e_name).into())
-            } else {
-                Self::append_rec(&e, path_vec, entry, new_local_entries, append_mode)
-            }
-        } else {
-            // append target
-            if append_mode == AppendMode::Create {
-                new_local_entries.push(Arc::downgrade(&entry));
-            }
-            Self::append_child(parent_entry, entry)?;
+    pub fn get_subtree(&self, id: EntryId) -> String {
+        let mut res = String::new();
+
+        self.tree_rec(id, &mut res, "");
 
-            Ok(())
+        res
+    }
+
+    fn tree_rec(&self, id: EntryId, tree: &mut String, indent: &str) {
+        let e = match self.entry(id) {
+            Some(e) => e,
+            None => return,
+        };
+        tree.push_str(format!("{}\n", e).as_str());
+        let mut ch_iter = e.children.values().peekable();
+        while let Some(&c) = ch_iter.next() {
+            let has_next = ch_iter.peek().is_some();
+            tree.push_str(format!("{}{}", indent, if has_next { "├── " } else { "└── " }).as_str());
+            self.tree_rec(
+                c,
+                tree,
+                format!("{}{}   ", indent, if has_next { "|" } else { " " }).as_str(),
+            );
         }
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
-pub enum AppendMode {
-    Create,
-    Move,
+// "/a/b" -> "/a", "/a" -> "/"
+pub fn parent_path(path: &str) -> String {
+    let path = Tree::normalize_path(path);
+    match path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => path[..i].to_string(),
+    }
 }
 
 #[derive(Debug)]
@@ -546,8 +760,12 @@ pub enum Command {
     HardRepair,
     NormalRepair,
     NetworkConnect,
+    NetworkMaintenance,
     NetworkDisconnect,
+    RetryFailures,
+    ReleaseDeletes,
     Terminate(bool),
+    Control(control::ControlRequest, control::ControlReply),
     Error(anyhow::Error),
 }
 
@@ -556,20 +774,66 @@ mod tests {
     use crate::*;
 
     #[test]
-    fn path_vec_test1() {
-        let path = "/hoge/fuga/bar/test.md";
-
-        let v = Entry::prepare_path_vec(path);
-        assert_eq!(&v, &["test.md", "bar", "fuga", "hoge", ""]);
-
-        let path = "/hoge/fuga/bar/test/";
-
-        let v = Entry::prepare_path_vec(path);
-        assert_eq!(&v, &["test", "bar", "fuga", "hoge", ""]);
-
-        let path = "/";
+    fn tree_test1() {
+        let mut tree = Tree::new();
+        assert!(tree.is_empty());
+        let file = || Entry::new("test.md".to_string(), EntryType::File { etag: None });
+
+        let new_entries = tree.insert("/hoge/fuga/test.md", file()).unwrap();
+        assert_eq!(new_entries.len(), 3);
+        assert_eq!(tree.path_of(new_entries[2]).unwrap(), "/hoge/fuga/test.md");
+        assert!(tree.get("hoge/fuga/").unwrap().type_.is_dir());
+        assert!(tree.insert("/hoge/fuga/test.md", file()).is_err());
+        assert!(tree.insert("/hoge/fuga/test.md/a", file()).is_err());
+
+        // into the existing directory
+        tree.insert("/bar/", Entry::new("bar".to_string(), EntryType::Directory))
+            .unwrap();
+        tree.move_entry("/hoge/fuga", "/bar").unwrap().unwrap();
+        assert!(tree.get("/hoge/fuga").is_none());
+        assert!(tree.get("/bar/fuga/test.md").is_some());
+
+        // renamed
+        tree.move_entry("/bar/fuga/test.md", "/test2.md")
+            .unwrap()
+            .unwrap();
+        assert_eq!(tree.get("/test2.md").unwrap().get_raw_name(), "test2.md");
+        assert!(tree.move_entry("/none", "/a").unwrap().is_none());
+
+        assert!(tree.remove("/bar").is_some());
+        assert_eq!(tree.len(), 2);
+        assert_eq!(
+            tree.get_tree(),
+            "*/\n├── *hoge/\n└── *test2.md etag: None\n"
+        );
+        assert_eq!(parent_path("/hoge/fuga"), "/hoge");
+        assert_eq!(parent_path("/hoge"), "/");
+    }
 
-        let v = Entry::prepare_path_vec(path);
-        assert_eq!(&v, &[""]);
+    #[test]
+    fn path_test() {
+        assert_eq!(
+            Tree::normalize_path("/hoge/fuga/bar/test.md"),
+            "/hoge/fuga/bar/test.md"
+        );
+        assert_eq!(
+            Tree::normalize_path("hoge/fuga/bar/test/"),
+            "/hoge/fuga/bar/test"
+        );
+        assert_eq!(Tree::normalize_path("/"), "/");
+        assert_eq!(Tree::normalize_path(""), "/");
+
+        let mut tree = Tree::new();
+        let file = Entry::new("test.md".to_string(), EntryType::File { etag: None });
+        tree.insert("/hoge/fuga/bar/test.md", file).unwrap();
+        assert_eq!(tree.get_id("/"), Some(Tree::ROOT));
+        assert_eq!(tree.get_id(""), Some(Tree::ROOT));
+        let bar = tree.get_id("/hoge/fuga/bar/").unwrap();
+        assert_eq!(tree.path_of(bar).unwrap(), "/hoge/fuga/bar");
+        assert_eq!(
+            tree.get("hoge/fuga/bar/test.md").unwrap().get_raw_name(),
+            "test.md"
+        );
+        assert!(tree.get("/hoge/fuga/bar/test.md/").unwrap().type_.is_file());
     }
 }
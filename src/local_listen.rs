@@ -1,23 +1,31 @@
-use crate::errors::NcsError::*;
+use crate::attrs;
+use crate::errors::NcsError::{self, *};
+use crate::failures::FailedOp;
+use crate::history::{tree_etag, HistoryRecord, Source};
+use crate::logging::sync_event;
 use crate::meta::*;
+use crate::metrics::METRICS;
 use crate::nc_listen::NCEvent;
 use crate::repair::ModifiedPath;
+use crate::symlinks::LocalKind;
+use crate::throttle::LimitKind;
 use crate::*;
 use anyhow::Result;
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
 use notify::DebouncedEvent as DebEvent;
-use reqwest::{Method, Url};
+use reqwest::Method;
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc as std_mpsc;
-use std::sync::{Arc, Mutex};
-use std::time::Duration as StdDuration;
+use std::sync::Mutex;
+use std::time::{Duration as StdDuration, Instant};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio::sync::mpsc::Sender as TokioSender;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum LocalEvent {
     Create(PathBuf),
     Delete(PathBuf),
@@ -39,6 +47,23 @@ impl ModifiedPath for LocalEvent {
 }
 
 impl LocalEvent {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Create(_) => "create",
+            Self::Delete(_) => "delete",
+            Self::Modify(_) => "modify",
+            Self::Move(..) => "move",
+        }
+    }
+
+    fn has_file_name(&self, name: &str) -> bool {
+        let is = |p: &PathBuf| p.file_name().is_some_and(|n| n == name);
+        match self {
+            Self::Create(p) | Self::Delete(p) | Self::Modify(p) => is(p),
+            Self::Move(p, q) => is(p) || is(q),
+        }
+    }
+
     #[allow(dead_code)]
     fn strip_root(&mut self, root_path: &str) {
         *self = match self {
@@ -63,6 +88,144 @@ impl LocalEvent {
     }
 }
 
+// Holds local deletions back when too many of them happen in a short time.
+// A deletion opens a batch. The events of the batch wait until no deletion comes for
+// a while or the window closes. Then they are dealt in order, or the deletions
+// of the batch are held until the user's confirmation if there are too many.
+// The held ones are kept in `file` over restarts.
+pub struct DeleteGuard {
+    threshold: Option<usize>,
+    batch: Vec<LocalEvent>,
+    batch_start: Option<Instant>,
+    last_delete: Option<Instant>,
+    held: Vec<LocalEvent>,
+    file: Option<String>,
+}
+
+impl DeleteGuard {
+    const WINDOW: StdDuration = StdDuration::from_secs(60);
+    // no more deletion for this long, the batch is complete.
+    const QUIET: StdDuration = StdDuration::from_secs(5);
+    pub const TICK: StdDuration = StdDuration::from_secs(1);
+
+    // `threshold`: max deletions in a batch. None means no limit.
+    // `file`: where the held deletions are kept. None keeps them only in memory.
+    pub fn new(threshold: Option<usize>, file: Option<String>) -> Self {
+        let held = file
+            .as_deref()
+            .and_then(|f| fs::read_to_string(f).ok())
+            .and_then(|j| serde_json::from_str::<Vec<PathBuf>>(&j).ok())
+            .unwrap_or_default();
+        if !held.is_empty() {
+            warn!(
+                "{} local deletions are still held. (deletes confirm | deletes discard)",
+                held.len()
+            );
+        }
+        Self {
+            threshold,
+            batch: Vec::new(),
+            batch_start: None,
+            last_delete: None,
+            held: held.into_iter().map(LocalEvent::Delete).collect(),
+            file,
+        }
+    }
+
+    // returns the events which may be dealt now.
+    pub fn check(&mut self, ev: LocalEvent) -> Vec<LocalEvent> {
+        let is_delete = matches!(ev, LocalEvent::Delete(_));
+        if self.threshold.is_none() || (self.batch.is_empty() && !is_delete) {
+            return vec![ev];
+        }
+        if is_delete && !self.held.is_empty() {
+            self.held.push(ev);
+            self.save();
+            return Vec::new();
+        }
+
+        if is_delete {
+            let now = Instant::now();
+            self.batch_start.get_or_insert(now);
+            self.last_delete = Some(now);
+        }
+        self.batch.push(ev);
+        self.check_batch(false)
+    }
+
+    // the events of the batch if it's complete. Called every `TICK`.
+    pub fn due(&mut self) -> Vec<LocalEvent> {
+        let now = Instant::now();
+        let closed = matches!(self.last_delete, Some(t) if now.duration_since(t) >= Self::QUIET)
+            || matches!(self.batch_start, Some(t) if now.duration_since(t) >= Self::WINDOW);
+        if self.batch.is_empty() || !closed {
+            return Vec::new();
+        }
+        self.check_batch(true)
+    }
+
+    fn check_batch(&mut self, closed: bool) -> Vec<LocalEvent> {
+        let threshold = self.threshold.unwrap_or(usize::MAX);
+        let deletes = self
+            .batch
+            .iter()
+            .filter(|ev| matches!(ev, LocalEvent::Delete(_)))
+            .count();
+        if deletes <= threshold && !closed {
+            return Vec::new();
+        }
+
+        self.batch_start = None;
+        self.last_delete = None;
+        let batch = std::mem::take(&mut self.batch);
+        if deletes <= threshold {
+            return batch;
+        }
+
+        warn!(
+            "{} local deletions at once (more than {}). They are held until confirmation. (deletes confirm | deletes discard)",
+            deletes, threshold
+        );
+        let (held, rest): (Vec<_>, Vec<_>) = batch
+            .into_iter()
+            .partition(|ev| matches!(ev, LocalEvent::Delete(_)));
+        self.held.extend(held);
+        self.save();
+        rest
+    }
+
+    pub fn held(&self) -> &[LocalEvent] {
+        &self.held
+    }
+
+    pub fn release(&mut self) -> Vec<LocalEvent> {
+        let held = std::mem::take(&mut self.held);
+        self.save();
+        held
+    }
+
+    fn save(&self) {
+        let file = match &self.file {
+            Some(f) => f,
+            None => return,
+        };
+        let paths = self
+            .held
+            .iter()
+            .filter_map(|ev| match ev {
+                LocalEvent::Delete(p) => Some(p),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let res = serde_json::to_string_pretty(&paths)
+            .map_err(anyhow::Error::from)
+            .and_then(|j| write_atomic(file, format!("{}\n", j).as_bytes()));
+        if let Err(e) = res {
+            warn!("{}: can't keep the held deletions. | {:?}", file, e);
+        }
+    }
+}
+
 #[allow(dead_code)]
 fn fix_path(path: &Path) -> PathBuf {
     let s = path.to_string_lossy().replace("\\", "/");
@@ -133,12 +296,18 @@ pub async fn watching(
 
         // let _ = network::check(&com_tx, nc_info).await?;
 
+        let ignore_file_changed = items.iter().any(|item| item.has_file_name(NCSIGNORE_FILE));
+
         for mut item in items {
             item.strip_root(&local_info.root_path);
             // item.reformat_path();
             com_tx.send(Command::LocEvent(item)).await?;
         }
 
+        if ignore_file_changed {
+            com_tx.send(Command::UpdateExcFile).await?;
+        }
+
         if watcher_dead_flag {
             warn!("[[watcher dead flag]]");
             com_tx.send(Command::Terminate(true)).await?;
@@ -150,12 +319,14 @@ pub async fn watching(
 #[async_recursion]
 pub async fn deal_local_event(
     ev: LocalEvent,
-    root: &ArcEntry,
+    tree: &mut Tree,
     nc_info: &NCInfo,
     local_info: &LocalInfo,
     nc2l_cancel_map: &mut HashMap<String, usize>,
     l2nc_cancel_set: &mut HashSet<NCEvent>,
 ) -> Result<()> {
+    METRICS.record_event("local", ev.kind());
+    let source = Source::local(ev.kind());
     match ev {
         LocalEvent::Create(p) => {
             if !local_info.exc_checker.judge(&p) {
@@ -172,22 +343,27 @@ pub async fn deal_local_event(
 
             debug!("local_p: {:?}", local_p);
 
-            if !local_p.exists() {
+            let kind = local_info.local_kind(&local_p);
+            if kind == LocalKind::Missing {
                 debug!("LocEvent::Create({:?}) : but not found.", p);
                 return Ok(());
             }
+            if kind == LocalKind::Skip {
+                debug!("LocEvent::Create({:?}) : not synced symlink.", p);
+                return Ok(());
+            }
 
             let p_parent = p
                 .parent()
                 .ok_or_else(|| InvalidPathError("Something wrong.".to_string()))?;
             let p_parent_str = path2str(p_parent);
 
-            if Entry::get(root, &p_parent_str)?.is_none() {
+            if !tree.contains(&p_parent_str) {
                 debug!("Its parent is not registered yet.");
 
                 deal_local_event(
                     LocalEvent::Create(p_parent.to_path_buf()),
-                    root,
+                    tree,
                     nc_info,
                     local_info,
                     nc2l_cancel_map,
@@ -201,12 +377,12 @@ pub async fn deal_local_event(
             info!("LocEvent::Create({:?})", p);
 
             let p_str = path2str(&p);
-            if Entry::get(root, &p_str)?.is_some() {
+            if tree.contains(&p_str) {
                 info!("Already Exists.");
 
                 deal_local_event(
                     LocalEvent::Modify(p),
-                    root,
+                    tree,
                     nc_info,
                     local_info,
                     nc2l_cancel_map,
@@ -217,10 +393,15 @@ pub async fn deal_local_event(
                 return Ok(());
             }
 
-            let method = if local_p.is_file() {
-                NCMethod::Put(p_str.clone(), local_p.clone())
+            let remote_p_str = local_info.names.remote_path(tree, &p_str);
+            if let Err(e) = local_info.names.check_upload(&path2name(&remote_p_str)) {
+                warn!("{} is not uploaded. Rename it to sync. | {}", p_str, e);
+                return Err(e);
+            }
+            let method = if kind.is_file() {
+                NCMethod::Put(remote_p_str.clone(), local_p.clone())
             } else {
-                NCMethod::Mkcol(p_str.clone())
+                NCMethod::Mkcol(remote_p_str.clone())
             };
 
             let etag_w = comm_nc(nc_info, local_info, method).await?;
@@ -229,37 +410,50 @@ pub async fn deal_local_event(
                 .ok_or_else(|| InvalidPathError("Something wrong in notify path.".to_string()))?
                 .to_string_lossy()
                 .to_string();
-            let type_ = if local_p.is_file() {
+            let record = if kind.is_file() {
+                HistoryRecord::new(&p_str, source, "upload").etags(None, etag_w.clone())
+            } else {
+                HistoryRecord::new(&p_str, source, "mkdir_remote")
+            };
+            let type_ = if kind.is_file() {
                 EntryType::File { etag: etag_w }
             } else {
                 EntryType::Directory
             };
-            let new_entry = Arc::new(Mutex::new(Entry::new(name, type_)));
-            {
-                let mut e_ref = new_entry.lock().map_err(|_| LockError)?;
-                e_ref.status = EntryStatus::UpToDate;
+            let mut new_entry = Entry::new(name, type_).with_remote_name(&path2name(&remote_p_str));
+            if new_entry.type_.is_file() {
+                new_entry.mtime = mtime::local_mtime(&local_p);
             }
-            let _ = Entry::append(root, &p_str, new_entry, AppendMode::Create, false)?;
+            new_entry.status = EntryStatus::UpToDate;
+            let _ = tree.insert(&p_str, new_entry)?;
+            local_info.history.append(record);
 
-            l2nc_cancel_set.insert(NCEvent::Create(p_str.clone()));
+            l2nc_cancel_set.insert(NCEvent::Create(remote_p_str));
 
             if_chain! {
-                if local_p.is_dir();
+                if kind.is_dir();
                 if let Ok(readdir) = fs::read_dir(&local_p);
                 then {
                     for item in readdir {
                         if let Ok(path) = item {
                             let s = path.file_name();
                             let s = s.to_string_lossy();
-                            let c = format!("{}/{}", p_str, s);
-                            deal_local_event(
-                                LocalEvent::Create(Path::new(&c).to_path_buf()),
-                                root,
+                            let c = Path::new(&format!("{}/{}", p_str, s)).to_path_buf();
+                            let res = deal_local_event(
+                                LocalEvent::Create(c.clone()),
+                                tree,
                                 nc_info,
                                 local_info,
                                 nc2l_cancel_map,
                                 l2nc_cancel_set,
-                            ).await?;
+                            ).await;
+                            // a forbidden name doesn't stop the rest of the directory.
+                            match res {
+                                Err(e) if is_forbidden_name(&e) => {
+                                    local_info.failures.record_or_warn(FailedOp::Local(LocalEvent::Create(c)), &e);
+                                }
+                                res => res?,
+                            }
                         }
                     }
                 }
@@ -273,7 +467,7 @@ pub async fn deal_local_event(
 
             let local_p = get_localpath(&p, local_info);
 
-            if local_p.exists() {
+            if local_info.local_kind(&local_p).exists() {
                 debug!("LocEvent::Delete({:?}) : but still exists.", p);
                 return Ok(());
             }
@@ -281,18 +475,20 @@ pub async fn deal_local_event(
             info!("LocEvent::Delete({:?})", p);
 
             let p_str = path2str(&p);
-            if Entry::get(root, &p_str)?.is_none() {
+            if !tree.contains(&p_str) {
                 info!("Already Deleted.");
                 return Ok(());
             }
 
-            let _ = comm_nc(nc_info, local_info, NCMethod::Delete(p_str.clone())).await?;
-            {
-                let mut root_ref = root.lock().map_err(|_| LockError)?;
-                let _ = root_ref.pop(&p_str)?;
-            }
+            let old_etag = tree_etag(tree, &p_str);
+            let remote_p_str = local_info.names.remote_path(tree, &p_str);
+            let _ = comm_nc(nc_info, local_info, NCMethod::Delete(remote_p_str.clone())).await?;
+            let _ = tree.remove(&p_str);
+            local_info
+                .history
+                .append(HistoryRecord::new(&p_str, source, "delete_remote").etags(old_etag, None));
 
-            l2nc_cancel_set.insert(NCEvent::Delete(p_str));
+            l2nc_cancel_set.insert(NCEvent::Delete(remote_p_str));
         }
         LocalEvent::Modify(p) => {
             if !local_info.exc_checker.judge(&p) {
@@ -307,12 +503,13 @@ pub async fn deal_local_event(
 
             let local_p = get_localpath(&p, local_info);
 
-            if !local_p.exists() {
+            let kind = local_info.local_kind(&local_p);
+            if !kind.exists() {
                 debug!("LocEvent::Modify({:?}) : but not found.", p);
                 return Ok(());
             }
 
-            if local_p.is_dir() {
+            if kind.is_dir() {
                 debug!("LocEvent::Modify({:?}) : but this is dir.", p);
                 return Ok(());
             }
@@ -320,12 +517,12 @@ pub async fn deal_local_event(
             info!("LocEvent::Modify({:?})", p);
 
             let p_str = path2str(&p);
-            if Entry::get(root, &p_str)?.is_none() {
+            if !tree.contains(&p_str) {
                 info!("But not found.");
 
                 deal_local_event(
                     LocalEvent::Create(p),
-                    root,
+                    tree,
                     nc_info,
                     local_info,
                     nc2l_cancel_map,
@@ -336,25 +533,30 @@ pub async fn deal_local_event(
                 return Ok(());
             }
 
-            let etag_w =
-                comm_nc(nc_info, local_info, NCMethod::Put(p_str.clone(), local_p)).await?;
-            let entry_w = Entry::get(root, &p_str)?;
-            if_chain! {
-                if let Some(w) = entry_w;
-                if let Some(a) = w.upgrade();
-                then {
-                    let mut e_ref = a.lock().map_err(|_| LockError)?;
-                    e_ref.type_ = EntryType::File { etag: etag_w };
-                    e_ref.status = EntryStatus::UpToDate;
-                }
+            let old_etag = tree_etag(tree, &p_str);
+            let remote_p_str = local_info.names.remote_path(tree, &p_str);
+            let local_mtime = mtime::local_mtime(&local_p);
+            let etag_w = comm_nc(
+                nc_info,
+                local_info,
+                NCMethod::Put(remote_p_str.clone(), local_p),
+            )
+            .await?;
+            local_info.history.append(
+                HistoryRecord::new(&p_str, source, "upload").etags(old_etag, etag_w.clone()),
+            );
+            if let Some(e) = tree.get_mut(&p_str) {
+                e.type_ = EntryType::File { etag: etag_w };
+                e.mtime = local_mtime;
+                e.status = EntryStatus::UpToDate;
             }
 
-            l2nc_cancel_set.insert(NCEvent::Modify(p_str));
+            l2nc_cancel_set.insert(NCEvent::Modify(remote_p_str));
         }
         LocalEvent::Move(p, q) => {
             let local_p = get_localpath(&p, local_info);
 
-            if local_p.exists() {
+            if local_info.local_kind(&local_p).exists() {
                 debug!("LocEvent::Move({:?}, _) : but still exists.", p);
                 return Ok(());
             }
@@ -372,7 +574,7 @@ pub async fn deal_local_event(
                 );
                 deal_local_event(
                     LocalEvent::Create(q.clone()),
-                    root,
+                    tree,
                     nc_info,
                     local_info,
                     nc2l_cancel_map,
@@ -387,7 +589,7 @@ pub async fn deal_local_event(
                 );
                 deal_local_event(
                     LocalEvent::Delete(p.clone()),
-                    root,
+                    tree,
                     nc_info,
                     local_info,
                     nc2l_cancel_map,
@@ -400,12 +602,12 @@ pub async fn deal_local_event(
             info!("LocEvent::Move({:?}, {:?})", p, q);
 
             let p_str = path2str(&p);
-            if Entry::get(root, &p_str)?.is_none() {
+            if !tree.contains(&p_str) {
                 info!("But not found from_item.");
 
                 deal_local_event(
                     LocalEvent::Create(p),
-                    root,
+                    tree,
                     nc_info,
                     local_info,
                     nc2l_cancel_map,
@@ -417,30 +619,52 @@ pub async fn deal_local_event(
             }
 
             let q_str = path2str(&q);
+            let remote_p_str = local_info.names.remote_path(tree, &p_str);
+            let remote_q_str = local_info.names.remote_path(tree, &q_str);
+            if let Err(e) = local_info.names.check_upload(&path2name(&remote_q_str)) {
+                warn!("{} is not uploaded. Rename it to sync. | {}", q_str, e);
+                // the same as a move to an excluded name. == Delete(p)
+                deal_local_event(
+                    LocalEvent::Delete(p),
+                    tree,
+                    nc_info,
+                    local_info,
+                    nc2l_cancel_map,
+                    l2nc_cancel_set,
+                )
+                .await?;
+                local_info
+                    .failures
+                    .record(FailedOp::Local(LocalEvent::Create(q)), &e)?;
+                return Ok(());
+            }
 
             let _ = comm_nc(
                 nc_info,
                 local_info,
-                NCMethod::Move(p_str.clone(), q_str.clone()),
+                NCMethod::Move(remote_p_str.clone(), remote_q_str.clone()),
             )
             .await?;
-            let entry = {
-                let mut root_ref = root.lock().map_err(|_| LockError)?;
-                root_ref
-                    .pop(&p_str)?
-                    .ok_or_else(|| InvalidPathError("Something wrong.".to_string()))?
-            };
-
-            let _ = Entry::append(root, &q_str, entry, AppendMode::Move, true)?;
+            let (moved, _) = tree
+                .move_entry(&p_str, &q_str)?
+                .ok_or_else(|| InvalidPathError("Something wrong.".to_string()))?;
+            if tree.path_of(moved) == Some(Tree::normalize_path(&q_str)) {
+                local_info
+                    .names
+                    .set_remote_names(tree, &q_str, &remote_q_str);
+            }
+            local_info
+                .history
+                .append(HistoryRecord::new(&p_str, source, "move_remote").to(&q_str));
 
             let cancel_target = if p.file_name() == q.file_name() {
-                let q_parent = q
+                let q_parent = Path::new(&remote_q_str)
                     .parent()
                     .ok_or_else(|| InvalidPathError("Something wrong.".to_string()))?;
                 let q_parent_str = path2str(q_parent);
-                NCEvent::Move(p_str, q_parent_str)
+                NCEvent::Move(remote_p_str, q_parent_str)
             } else {
-                NCEvent::Move(p_str, q_str)
+                NCEvent::Move(remote_p_str, remote_q_str)
             };
 
             l2nc_cancel_set.insert(cancel_target);
@@ -450,6 +674,10 @@ pub async fn deal_local_event(
     Ok(())
 }
 
+fn is_forbidden_name(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<NcsError>(), Some(ForbiddenNameError(_)))
+}
+
 fn haveto_cancel_target(p: &Path, book: &mut HashMap<String, usize>) -> bool {
     let path_str = path2str(p);
     let count_w = book.remove(&path_str);
@@ -483,58 +711,216 @@ async fn comm_nc(
         NCMethod::Delete(ref target) => target.to_string(),
         NCMethod::Move(ref target, _) => target.to_string(),
     };
+    let (action, to) = match method {
+        NCMethod::Put(..) => ("upload", None),
+        NCMethod::Mkcol(_) => ("mkdir_remote", None),
+        NCMethod::Delete(_) => ("delete_remote", None),
+        NCMethod::Move(_, ref to) => ("move_remote", Some(to.to_string())),
+    };
 
-    let path = format!("{}{}", &nc_info.root_path, target)
-        .split("/")
-        .map(|v| v.to_string())
-        .collect::<Vec<String>>();
-
-    let mut url = Url::parse(&nc_info.host)?;
-    url.path_segments_mut().unwrap().extend(path);
-
-    // let client = Client::builder().https_only(true).build()?;
-    let ref client = local_info.req_client;
-
+    let client = &local_info.nc_client;
+    let mut upload_len = None;
+    let mut attrs_of = None;
     let reqbuil = match method {
         NCMethod::Put(_, file_path) => {
-            let buf = if let Ok(v) = fs::read(&file_path) {
-                v
-            } else {
-                vec![]
+            let read_err = |e: std::io::Error| anyhow!("{:?} | {:?}", file_path, e);
+            let descriptor = local_info
+                .symlinks
+                .link_descriptor(&file_path)
+                .map_err(read_err)?;
+            let len = match &descriptor {
+                Some(d) => d.len(),
+                None => fs::metadata(&file_path).map_err(read_err)?.len() as usize,
             };
+            let mtime = mtime::local_mtime(&file_path);
+
+            if nc_info.capabilities.supports_chunking() && len > CHUNK_SIZE {
+                let etag =
+                    chunked_upload(nc_info, local_info, &target, &file_path, len, mtime).await?;
+                upload_attrs(local_info, &target, &file_path).await;
+                sync_event(action, &target, None, "changed locally");
+                return Ok(etag);
+            }
 
-            // debug!("{:?} 's content : {:?}", file_path, buf);
-
-            client.request(Method::PUT, url.as_str()).body(buf)
+            let body = match descriptor {
+                Some(d) => {
+                    client
+                        .throttle()
+                        .acquire(LimitKind::Upload, d.len() as u64)
+                        .await;
+                    reqwest::Body::from(d)
+                }
+                None => {
+                    let file = tokio::fs::File::open(&file_path).await.map_err(read_err)?;
+                    client.upload_body(file)
+                }
+            };
+            upload_len = Some(len);
+            let req = client.dav_request(Method::PUT, &target)?;
+            attrs_of = Some(file_path);
+            with_mtime(req, mtime)
+                .header(reqwest::header::CONTENT_LENGTH, len)
+                .body(body)
         }
-        NCMethod::Mkcol(_) => client.request(Method::from_bytes(b"MKCOL").unwrap(), url.as_str()),
-        NCMethod::Delete(_) => client.request(Method::DELETE, url.as_str()),
+        NCMethod::Mkcol(_) => client.dav_request(Method::from_bytes(b"MKCOL").unwrap(), &target)?,
+        NCMethod::Delete(_) => client.dav_request(Method::DELETE, &target)?,
         NCMethod::Move(_, to_target) => {
-            let p = format!("{}{}", &nc_info.root_path, to_target)
-                .split("/")
-                .map(|v| v.to_string())
-                .collect::<Vec<String>>();
-            let mut to_url = Url::parse(&nc_info.host)?;
-            to_url.path_segments_mut().unwrap().extend(p);
+            let to_url = client.dav_url(&to_target)?;
 
             client
-                .request(Method::from_bytes(b"MOVE").unwrap(), url.as_str())
+                .dav_request(Method::from_bytes(b"MOVE").unwrap(), &target)?
                 .header("Destination", to_url.as_str())
         }
     };
 
-    let res = reqbuil
-        .basic_auth(&nc_info.username, Some(&nc_info.password))
-        .send()
-        .await?;
-
-    if !res.status().is_success() {
-        return Err(BadStatusError(res.status().as_u16()).into());
+    let res = client.send(reqbuil).await?;
+    if let Some(len) = upload_len {
+        METRICS.record_upload(len);
+    }
+    if let Some(file_path) = attrs_of {
+        upload_attrs(local_info, &target, &file_path).await;
     }
+    sync_event(action, &target, to.as_deref(), "changed locally");
 
+    Ok(get_etag_header(&res))
+}
+
+fn get_etag_header(res: &reqwest::Response) -> Option<String> {
     let headers = res.headers();
-    Ok(headers
-        .get("Etag")
+    headers
+        .get("OC-Etag")
+        .or_else(|| headers.get("Etag"))
         .and_then(|s| s.to_str().ok())
-        .map(|s| s.replace("\"", "")))
+        .map(|s| s.replace("\"", ""))
+}
+
+// The metadata is kept as a property of the uploaded file. (see attrs)
+// The content is already up, so a failure here is only warned.
+async fn upload_attrs(local_info: &LocalInfo, target: &str, file_path: &Path) {
+    let path = file_path
+        .strip_prefix(&local_info.root_path)
+        .map(path2str)
+        .unwrap_or_default();
+    let attrs = match local_info.attrs.read(&path, file_path) {
+        Some(a) => a,
+        None => return,
+    };
+    if let Err(e) = attrs::upload_attrs(&local_info.nc_client, target, &attrs).await {
+        warn!("{}: can't upload the attrs. | {:?}", target, e);
+    }
+}
+
+// the server keeps the local mtime instead of the upload time.
+fn with_mtime(req: reqwest::RequestBuilder, mtime: Option<i64>) -> reqwest::RequestBuilder {
+    match mtime {
+        Some(m) => req.header(mtime::MTIME_HEADER, m.to_string()),
+        None => req,
+    }
+}
+
+const CHUNK_SIZE: usize = 10 * 1024 * 1024;
+
+// chunked upload v1: MKCOL a transfer folder, PUT chunks into it, MOVE `.file` to the target.
+// Each chunk is read from the file as it is sent. The folder is removed if it fails.
+async fn chunked_upload(
+    nc_info: &NCInfo,
+    local_info: &LocalInfo,
+    target: &str,
+    file_path: &Path,
+    len: usize,
+    mtime: Option<i64>,
+) -> Result<Option<String>> {
+    let client = &local_info.nc_client;
+    let transfer_id = format!("ncs-{}", chrono::Local::now().format("%Y%m%d%H%M%S%f"));
+    let upload_dir = format!("{}{}/{}", NC_UPLOADS_PREFIX, nc_info.username, transfer_id);
+    debug!("chunked upload: {} via {}", target, upload_dir);
+
+    let mkcol = client.request(
+        Method::from_bytes(b"MKCOL").unwrap(),
+        client.url(&upload_dir)?,
+    );
+    client.send(mkcol).await?;
+
+    let res = send_chunks(local_info, target, file_path, len, mtime, &upload_dir).await;
+    if res.is_err() {
+        let url = client.url(&upload_dir)?;
+        if let Err(e) = client.send(client.request(Method::DELETE, url)).await {
+            warn!("{}: can't remove the upload folder. | {:?}", upload_dir, e);
+        }
+    }
+    let res = res?;
+    METRICS.record_upload(len);
+
+    Ok(get_etag_header(&res))
+}
+
+async fn send_chunks(
+    local_info: &LocalInfo,
+    target: &str,
+    file_path: &Path,
+    len: usize,
+    mtime: Option<i64>,
+    upload_dir: &str,
+) -> Result<reqwest::Response> {
+    let client = &local_info.nc_client;
+    let read_err = |e: std::io::Error| anyhow!("{:?} | {:?}", file_path, e);
+    for i in 0..len.div_ceil(CHUNK_SIZE) {
+        let mut file = tokio::fs::File::open(file_path).await.map_err(read_err)?;
+        file.seek(std::io::SeekFrom::Start((i * CHUNK_SIZE) as u64))
+            .await
+            .map_err(read_err)?;
+        let url = client.url(&format!("{}/{:08}", upload_dir, i))?;
+        let size = CHUNK_SIZE.min(len - i * CHUNK_SIZE);
+        let body = client.upload_body(file.take(size as u64));
+        let put = client
+            .request(Method::PUT, url)
+            .header(reqwest::header::CONTENT_LENGTH, size)
+            .body(body);
+        client.send(put).await?;
+    }
+
+    let to_url = client.dav_url(target)?;
+    let mv = client
+        .request(
+            Method::from_bytes(b"MOVE").unwrap(),
+            client.url(&format!("{}/.file", upload_dir))?,
+        )
+        .header("Destination", to_url.as_str());
+
+    client.send(with_mtime(mv, mtime)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delete_guard_test() {
+        let file = std::env::temp_dir().join(format!("ncs_held_test_{}", std::process::id()));
+        let file = file.to_str().unwrap().to_string();
+        let del = |p: &str| LocalEvent::Delete(PathBuf::from(p));
+
+        let mut guard = DeleteGuard::new(Some(2), Some(file.clone()));
+        assert!(guard.check(del("/a")).is_empty());
+        assert!(guard
+            .check(LocalEvent::Create(PathBuf::from("/b")))
+            .is_empty());
+        assert!(guard.check(del("/c")).is_empty());
+        // the third one makes the batch too big. Only the creation goes.
+        let evs = guard.check(del("/d"));
+        assert!(matches!(evs.as_slice(), [LocalEvent::Create(_)]));
+        assert_eq!(guard.held().len(), 3);
+        assert!(guard.check(del("/e")).is_empty());
+
+        let mut guard = DeleteGuard::new(Some(2), Some(file.clone()));
+        assert_eq!(guard.held().len(), 4);
+        assert_eq!(guard.release().len(), 4);
+        assert!(DeleteGuard::new(Some(2), Some(file.clone()))
+            .held()
+            .is_empty());
+        fs::remove_file(&file).unwrap();
+
+        let mut guard = DeleteGuard::new(None, None);
+        assert_eq!(guard.check(del("/a")).len(), 1);
+    }
 }
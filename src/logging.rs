@@ -0,0 +1,218 @@
+use chrono::{Local, NaiveDate};
+use log::{LevelFilter, Log, Metadata, Record};
+use once_cell::sync::OnceCell;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// target of the sync log. Its lines go to `sync-<YYYYmmdd>.log` as JSON.
+pub const SYNC_TARGET: &str = "ncs::sync";
+
+const SYNC_PREFIX: &str = "sync-";
+const DATE_FORMAT: &str = "%Y%m%d";
+
+static LOGGER: OnceCell<Logger> = OnceCell::new();
+
+struct LogFiles {
+    dir: PathBuf,
+    retention_days: Option<u32>,
+    date: NaiveDate,
+    general: File,
+    sync: File,
+}
+
+impl LogFiles {
+    fn open(dir: &Path, retention_days: Option<u32>) -> std::io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let date = Local::today().naive_local();
+        let open = |name: String| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(dir.join(name))
+        };
+        let today = date.format(DATE_FORMAT);
+
+        let files = Self {
+            dir: dir.to_path_buf(),
+            retention_days,
+            date,
+            general: open(format!("{}.log", today))?,
+            sync: open(format!("{}{}.log", SYNC_PREFIX, today))?,
+        };
+        files.remove_expired();
+
+        Ok(files)
+    }
+
+    fn rotate_if_needed(&mut self) -> std::io::Result<()> {
+        if Local::today().naive_local() != self.date {
+            *self = Self::open(&self.dir, self.retention_days)?;
+        }
+
+        Ok(())
+    }
+
+    // removes log files older than the retention days. The date is taken from the name.
+    fn remove_expired(&self) {
+        let days = match self.retention_days {
+            Some(d) => d,
+            None => return,
+        };
+        let oldest = self.date - chrono::Duration::days(days as i64);
+
+        let read_dir = match fs::read_dir(&self.dir) {
+            Ok(r) => r,
+            Err(_) => return,
+        };
+        for entry in read_dir.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let date = name
+                .trim_start_matches(SYNC_PREFIX)
+                .strip_suffix(".log")
+                .and_then(|d| NaiveDate::parse_from_str(d, DATE_FORMAT).ok());
+            if matches!(date, Some(d) if d < oldest) {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+// stderr is filtered by RUST_LOG as before. The files get the logs of ncs itself
+// at `NC_LOG_LEVEL` and above, once the directory is known.
+struct Logger {
+    stderr: env_logger::Logger,
+    level: LevelFilter,
+    files: Mutex<Option<LogFiles>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.stderr.enabled(metadata) || self.file_enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.stderr.matches(record) {
+            self.stderr.log(record);
+        }
+        if !self.file_enabled(record.metadata()) {
+            return;
+        }
+
+        let mut files = match self.files.lock() {
+            Ok(f) => f,
+            Err(_) => return,
+        };
+        let files = match files.as_mut() {
+            Some(f) => f,
+            None => return,
+        };
+        if let Err(e) = files.rotate_if_needed() {
+            eprintln!("can't rotate the log files. | {:?}", e);
+            return;
+        }
+
+        let _ = if record.target() == SYNC_TARGET {
+            writeln!(files.sync, "{}", record.args())
+        } else {
+            writeln!(
+                files.general,
+                "{} {:<5} {}: {}",
+                Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+                record.level(),
+                record.target(),
+                record.args()
+            )
+        };
+    }
+
+    fn flush(&self) {
+        self.stderr.flush();
+        if let Ok(mut files) = self.files.lock() {
+            if let Some(f) = files.as_mut() {
+                let _ = f.general.flush();
+                let _ = f.sync.flush();
+            }
+        }
+    }
+}
+
+impl Logger {
+    // logs of dependencies are left to stderr.
+    fn file_enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+            && (metadata.target() == "ncs" || metadata.target().starts_with("ncs::"))
+    }
+}
+
+pub fn init(level: LevelFilter) -> anyhow::Result<()> {
+    let stderr = env_logger::Builder::from_default_env().build();
+    let max_level = stderr.filter().max(level);
+    let logger = Logger {
+        stderr,
+        level,
+        files: Mutex::new(None),
+    };
+
+    LOGGER
+        .set(logger)
+        .map_err(|_| anyhow!("The logger is already initialized."))?;
+    log::set_logger(LOGGER.get().unwrap())?;
+    log::set_max_level(max_level);
+
+    Ok(())
+}
+
+// Starts writing to `dir`. Called again on rerun, the files are reopened.
+pub fn set_dir<P: AsRef<Path>>(dir: P, retention_days: Option<u32>) -> anyhow::Result<()> {
+    let logger = LOGGER
+        .get()
+        .ok_or_else(|| anyhow!("The logger is not initialized."))?;
+    let files = LogFiles::open(dir.as_ref(), retention_days)?;
+    *logger
+        .files
+        .lock()
+        .map_err(|_| anyhow!("Failed Locking."))? = Some(files);
+
+    Ok(())
+}
+
+// A line of the sync log: what was done to which file and why.
+// ex) {"time":"...","action":"download","path":"/a.txt","reason":"changed on the server"}
+pub fn sync_event(action: &str, path: &str, to: Option<&str>, reason: &str) {
+    let mut v = serde_json::json!({
+        "time": Local::now().to_rfc3339(),
+        "action": action,
+        "path": path,
+        "reason": reason,
+    });
+    if let Some(to) = to {
+        v["to"] = serde_json::Value::from(to);
+    }
+    log::info!(target: SYNC_TARGET, "{}", v);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_retention_test() {
+        let dir = std::env::temp_dir().join(format!("ncs_log_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["20200101.log", "sync-20200101.log", "memo.txt"] {
+            File::create(dir.join(name)).unwrap();
+        }
+
+        let files = LogFiles::open(&dir, Some(7)).unwrap();
+        let today = files.date.format(DATE_FORMAT);
+        assert!(!dir.join("20200101.log").exists());
+        assert!(!dir.join("sync-20200101.log").exists());
+        assert!(dir.join("memo.txt").exists());
+        assert!(dir.join(format!("{}.log", today)).exists());
+        assert!(dir.join(format!("sync-{}.log", today)).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,25 @@
 use crate::network::{self, NetworkStatus};
 use anyhow::Result;
 use dotenv::dotenv;
-use log::{debug, info};
+use log::{debug, info, warn, LevelFilter};
+use ncs::capabilities::{cached_capabilities, fetch_capabilities, ServerCapabilities};
+use ncs::control::{self, ControlRequest};
 use ncs::errors::NcsError::*;
+use ncs::failures::{self, FailedOp};
+use ncs::history::Source;
 use ncs::local_listen::*;
+use ncs::logging;
 use ncs::meta::*;
+use ncs::metrics::{self, METRICS};
+use ncs::names::{self, ForbiddenAction, ForbiddenNames, NamePolicy, Normalization};
+use ncs::nc_client::{NcClient, RetryPolicy};
 use ncs::nc_listen::*;
+use ncs::plan::SyncPlan;
+use ncs::stash::RetentionPolicy;
+use ncs::status::{self, QueueLengths, SyncRecord};
+use ncs::symlinks::SymlinkPolicy;
+use ncs::throttle::{self, LimitKind, Limits, Throttle};
+use ncs::tree_store::{LoadedTree, TreeStore};
 use ncs::*;
 use notify::{watcher, RecursiveMode, Watcher};
 use std::collections::{HashMap, HashSet};
@@ -34,14 +48,17 @@ macro_rules! terminate_send {
     };
 }
 
-async fn run() -> Result<bool> {
+// failed operations are checked for a retry at this interval.
+const RETRY_TICK_SECS: u64 = 30;
+
+async fn run(control_lines: ControlLines) -> Result<bool> {
     // Can't update these environment variables by rerun.
     let username = env::var("NC_USERNAME").expect("NC_USERNAME not found");
     let password = env::var("NC_PASSWORD").expect("NC_PASSWORD not found");
     let host = env::var("NC_HOST").expect("NC_HOST not found");
     let host = fix_host(&host);
 
-    let nc_info = NCInfo::new(username, password, host);
+    let mut nc_info = NCInfo::new(username, password, host);
 
     let local_root_path = env::var("LOCAL_ROOT").expect("LOCAL_ROOT not found");
     let mut client_builder = reqwest::Client::builder().https_only(true);
@@ -62,45 +79,151 @@ async fn run() -> Result<bool> {
         client_builder = client_builder.proxy(proxy);
     }
 
-    let client = client_builder.build()?;
+    let mut client = NcClient::new(&nc_info, client_builder.build()?);
+
+    if let Some(secs) = env::var("NC_TIMEOUT").ok().and_then(|v| v.parse().ok()) {
+        client.set_timeout(StdDuration::from_secs(secs));
+    }
+    client.set_throttle(throttle_from_env()?);
+    if let Some(max_retries) = env::var("NC_MAX_RETRIES").ok().and_then(|v| v.parse().ok()) {
+        client.set_retry_policy(RetryPolicy {
+            max_retries,
+            ..Default::default()
+        });
+    }
+
+    // Nothing is changed on the local or the server. What would be done is printed instead.
+    let dry_run = env::var("NC_DRY_RUN")
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+    if dry_run {
+        info!("dry-run mode. nothing is changed on the local or the server.");
+    }
+
+    let caps_cache =
+        LocalInfo::get_capabilitiesfile_name_raw(&drop_slash(&local_root_path, &RE_HAS_LAST_SLASH));
+    let capabilities = if network::is_online(&client).await {
+        fetch_capabilities(&client, (!dry_run).then_some(caps_cache.as_str())).await?
+    } else if let Some(caps) = cached_capabilities(&caps_cache) {
+        warn!("offline. the server capabilities of last time are used until next start.");
+        caps
+    } else {
+        warn!("offline. server capabilities are assumed until next start.");
+        ServerCapabilities::assumed()
+    };
+    capabilities.check_requirements()?;
+    nc_info.set_capabilities(capabilities);
+    client.set_dav_root(&nc_info.root_path);
 
     /*
     let test = client.get("https://google.com").send().await?.status();
     debug!("status: {:?}", test);
     */
 
-    let local_info = LocalInfo::new(local_root_path, client.clone())?;
+    let mut local_info = LocalInfo::new(local_root_path, client.clone())?;
+    local_info.set_retention_policy(retention_policy_from_env()?);
+    let name_policy = name_policy_from_env(
+        &local_info.get_metadir_name(),
+        &nc_info.capabilities.forbidden_names,
+    )?;
+    local_info.set_name_policy(name_policy);
+    local_info.set_symlink_policy(symlink_policy_from_env()?);
+
+    // NC_LOG_RETENTION_DAYS: days to keep the log files. 30 by default.
+    let log_retention = match env::var("NC_LOG_RETENTION_DAYS") {
+        Ok(v) => throttle::parse_limit(&v)?.map(|v| v as u32),
+        Err(_) => Some(30),
+    };
+    // the dry-run logs only to the console.
+    if !dry_run {
+        logging::set_dir(local_info.get_logdir_name(), log_retention)?;
+    }
 
     // debug!("log_file: {}", local_info.get_logfile_name());
 
-    let public_resource: PublicResource;
-    if Path::new(local_info.get_cachefile_name().as_str()).exists() {
-        // load cache
-        let ncs_cache = load_cache(&local_info)?;
-        let nc_state = NCState {
-            latest_activity_id: ncs_cache.latest_activity_id,
-        };
-        let root_entry = json_entry2entry(ncs_cache.root_entry)?;
-        public_resource = PublicResource::new(root_entry, nc_state);
+    let (mut tree_store, loaded) = if dry_run {
+        TreeStore::open_copy_and_load(
+            &local_info.get_treedb_name(),
+            &local_info.get_treebackup_name(),
+        )?
+    } else {
+        TreeStore::open_and_load(
+            &local_info.get_treedb_name(),
+            &local_info.get_treebackup_name(),
+        )?
+    };
+    let cache_file = local_info.get_cachefile_name();
+    let loaded = match loaded {
+        LoadedTree::Empty if Path::new(&cache_file).exists() => {
+            let res = if dry_run {
+                load_cache(&cache_file).and_then(|ncs_cache| {
+                    let tree = json_entry2tree(ncs_cache.root_entry)?;
+                    Ok((tree, ncs_cache.latest_activity_id))
+                })
+            } else {
+                tree_store.migrate_from_json(&cache_file)
+            };
+            match res {
+                Ok((tree, latest_activity_id)) => LoadedTree::Loaded(tree, latest_activity_id),
+                Err(e) => {
+                    warn!("can't read {}. | {:?}", cache_file, e);
+                    LoadedTree::Broken
+                }
+            }
+        }
+        loaded => loaded,
+    };
+
+    // local only entries found by a rebuild. They are uploaded by the main loop.
+    let mut untracked_events = Vec::new();
+    let mut public_resource: PublicResource;
+    if let LoadedTree::Loaded(tree, latest_activity_id) = loaded {
+        let nc_state = NCState { latest_activity_id };
+        public_resource = PublicResource::new(tree, nc_state);
+    } else if let LoadedTree::Broken = loaded {
+        if !network::is_online(&client).await {
+            return Err(NetworkOfflineError.into());
+        }
+
+        if dry_run {
+            println!("[dry-run] the saved state is broken. It will be rebuilt from the server and the local.");
+            return Ok(false);
+        }
+
+        warn!("the saved state is broken. rebuild it from the server and the local.");
+        let (mut tree, latest_activity_id, local_events) =
+            repair::rebuild(&local_info, &nc_info).await?;
+        tree_store.save(&mut tree, &latest_activity_id)?;
+        tree_store.flush()?;
+        untracked_events = local_events;
+        let nc_state = NCState { latest_activity_id };
+        public_resource = PublicResource::new(tree, nc_state);
     } else {
         // init
-        if !network::is_online(&nc_info, &client).await {
+        if !network::is_online(&client).await {
             return Err(NetworkOfflineError.into());
         }
 
-        let (root, latest_activity_id) = init(&nc_info, &local_info).await?;
-        let json_entry = {
-            let root_ref = root.lock().map_err(|_| LockError)?;
-            root2json_entry(&root_ref)?
-        };
-        save_cache(latest_activity_id.clone(), json_entry, &local_info)?;
+        if dry_run {
+            print_plan(
+                "first sync",
+                &repair::plan_init(&local_info, &nc_info).await?,
+            );
+            return Ok(false);
+        }
+
+        let (mut tree, latest_activity_id) = init(&nc_info, &local_info).await?;
+        tree_store.save(&mut tree, &latest_activity_id)?;
+        tree_store.flush()?;
         let nc_state = NCState {
             latest_activity_id: latest_activity_id,
         };
-        public_resource = PublicResource::new(root, nc_state);
+        public_resource = PublicResource::new(tree, nc_state);
     }
 
-    let public_resource = Arc::new(Mutex::new(public_resource));
+    let delete_threshold = env::var("NC_DELETE_THRESHOLD")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok());
 
     // to end with successful completion, watchers must be managed here.
 
@@ -130,6 +253,17 @@ async fn run() -> Result<bool> {
         }
     });
 
+    if !untracked_events.is_empty() {
+        let tx = com_tx.clone();
+        tokio::spawn(async move {
+            for ev in untracked_events {
+                if tx.send(Command::LocEvent(ev)).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
     let tx = com_tx.clone();
     let lci = local_info.clone();
     let updateexcfile_handle = tokio::spawn(async move {
@@ -140,15 +274,12 @@ async fn run() -> Result<bool> {
         }
     });
 
-    let nc_state = {
-        let pr_ref = public_resource.lock().map_err(|_| LockError)?;
-        pr_ref.nc_state.clone()
-    };
+    let nc_state = public_resource.nc_state.clone();
     let tx = com_tx.clone();
     let nci = nc_info.clone();
     let lci = local_info.clone();
     let nclisten_handle = tokio::spawn(async move {
-        let res = nclistening(tx.clone(), &nci, &lci, nc_state.clone()).await;
+        let res = nclistening(tx.clone(), &nci, &lci, nc_state.clone(), dry_run).await;
         if let Err(e) = res {
             info!("{:?}", e);
             terminate_send!(tx);
@@ -157,80 +288,186 @@ async fn run() -> Result<bool> {
 
     let tx = com_tx.clone();
     let control_handle = tokio::spawn(async move {
-        let mut ln = String::new();
-        let _ = std::io::stdin().read_line(&mut ln);
-        let com = match ln.trim() {
-            "RESET" => Command::HardRepair,
-            _ => Command::Terminate(false),
-        };
-        // まだRESETでsend errorの時を考慮してない
-        let res = tx.send(com).await;
-        if let Err(e) = res {
-            info!("{:?}", e);
-            terminate_send!(tx);
+        let mut lines = control_lines.lock().await;
+        while let Some(ln) = lines.recv().await {
+            // まだRESETでsend errorの時を考慮してない
+            match control::dispatch(&ln, &tx).await {
+                Ok(Some(reply)) => println!("{}", reply),
+                Ok(None) => (),
+                Err(e) => {
+                    info!("{:?}", e);
+                    return;
+                }
+            }
+        }
+
+        // stdin is closed.
+        let _ = tx.send(Command::Terminate(false)).await;
+    });
+
+    let tx = com_tx.clone();
+    let socket_path = local_info.get_control_socket_name();
+    let socket_handle = tokio::spawn(async move {
+        if let Err(e) = control::serve_socket(&socket_path, tx).await {
+            warn!("control socket is not available. | {:?}", e);
+        }
+    });
+
+    let tx = com_tx.clone();
+    let retry_handle = tokio::spawn(async move {
+        loop {
+            sleep(Duration::from_secs(RETRY_TICK_SECS)).await;
+            if tx.send(Command::RetryFailures).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    let delete_guard_on = delete_threshold.is_some();
+    let tx = com_tx.clone();
+    let release_handle = tokio::spawn(async move {
+        if !delete_guard_on {
+            return;
+        }
+        loop {
+            sleep(DeleteGuard::TICK).await;
+            if tx.send(Command::ReleaseDeletes).await.is_err() {
+                return;
+            }
         }
     });
 
-    let mut network_status = network::status(&nc_info, &client).await?;
+    let mut network_status = network::status_raw(&client).await;
     let mut nc2l_cancel_map = HashMap::new();
     let mut l2nc_cancel_set = HashSet::new();
     let mut offline_locevent_que: Vec<local_listen::LocalEvent> = Vec::new();
+    let mut delete_guard = DeleteGuard::new(
+        delete_threshold,
+        (!dry_run).then_some(local_info.get_helddeletes_name()),
+    );
+    let mut pending_exclude_reconcile = None;
+    let mut sync_record = SyncRecord::default();
     let mut retry = false;
     let mut error = None;
     while let Some(e) = com_rx.recv().await {
+        // the paths changed by the last command. The activity id goes with them.
+        // The dry-run writes to its temporary copy.
+        if public_resource.tree().has_changes() {
+            let latest_activity_id = public_resource.nc_state.latest_activity_id.clone();
+            if let Err(e) = tree_store.save(public_resource.tree_mut(), &latest_activity_id) {
+                warn!("can't save the tree. | {:?}", e);
+            }
+        }
+        METRICS.set_network(&network_status);
+        METRICS.set_queues(
+            offline_locevent_que.len(),
+            delete_guard.held().len(),
+            local_info.failures.count(),
+        );
         match e {
-            Command::LocEvent(ev) => match network_status {
+            Command::LocEvent(ev) => {
+                let evs = delete_guard.check(ev);
+                deal_local_events(
+                    evs,
+                    dry_run,
+                    &network_status,
+                    &mut public_resource,
+                    &nc_info,
+                    &local_info,
+                    &mut nc2l_cancel_map,
+                    &mut l2nc_cancel_set,
+                    &mut offline_locevent_que,
+                    &mut sync_record,
+                )
+                .await;
+            }
+            Command::ReleaseDeletes => {
+                let evs = delete_guard.due();
+                deal_local_events(
+                    evs,
+                    dry_run,
+                    &network_status,
+                    &mut public_resource,
+                    &nc_info,
+                    &local_info,
+                    &mut nc2l_cancel_map,
+                    &mut l2nc_cancel_set,
+                    &mut offline_locevent_que,
+                    &mut sync_record,
+                )
+                .await;
+            }
+            Command::NCEvents(ev_vec, new_state) => match network_status {
                 NetworkStatus::Connect => {
-                    let pr_ref = public_resource.lock().map_err(|_| LockError)?;
-                    let res = deal_local_event(
-                        ev,
-                        &pr_ref.root,
+                    debug!("NCEvents({:?})", new_state);
+
+                    if public_resource.nc_state.eq_or_newer_than(&new_state) {
+                        continue;
+                    }
+
+                    public_resource.nc_state = new_state;
+                    if dry_run {
+                        let mut plan = SyncPlan::new();
+                        plan.add_nc_events(&ev_vec);
+                        print_plan("server events", &plan);
+                        continue;
+                    }
+                    let source = Source::server(&public_resource.nc_state.latest_activity_id);
+                    let res = update_and_download(
+                        ev_vec,
+                        public_resource.tree_mut(),
                         &nc_info,
                         &local_info,
                         &mut nc2l_cancel_map,
                         &mut l2nc_cancel_set,
+                        false,
+                        &source,
                     )
                     .await;
+                    sync_record.record(&res);
                     if let Err(e) = res {
+                        METRICS.record_error(&e);
                         info!("{:?}", e);
                         // break;
                     }
                 }
-                NetworkStatus::Disconnect | NetworkStatus::Err(_) => {
-                    debug!("LocEvent({:?}) @ offline", ev);
-                    offline_locevent_que.push(ev);
+                NetworkStatus::Maintenance | NetworkStatus::Disconnect | NetworkStatus::Err(_) => {
+                    info!("It should be unreachable branch. something wrong.");
                 }
             },
-            Command::NCEvents(ev_vec, new_state) => match network_status {
-                NetworkStatus::Connect => {
-                    debug!("NCEvents({:?})", new_state);
-                    let mut pr_ref = public_resource.lock().map_err(|_| LockError)?;
-
-                    if pr_ref.nc_state.eq_or_newer_than(&new_state) {
+            Command::UpdateExcFile => {
+                let old_rules = match local_info.exc_checker.reload() {
+                    Ok(r) => r,
+                    Err(e) => {
+                        warn!("exclude rules are not reloaded. | {:?}", e);
                         continue;
                     }
-
-                    pr_ref.nc_state = new_state;
-                    let res = update_and_download(
-                        ev_vec,
-                        &pr_ref.root,
+                };
+                info!("exclude rules are reloaded.");
+                if dry_run {
+                    info!("the tree is not reconciled with the rules in the dry-run mode.");
+                    continue;
+                }
+                // The rules before the first unapplied change are what the tree was built with.
+                let old_rules = pending_exclude_reconcile.take().unwrap_or(old_rules);
+                if network_status.is_connect() {
+                    let res = repair::reconcile_excludes(
+                        &old_rules,
+                        public_resource.tree_mut(),
                         &nc_info,
                         &local_info,
                         &mut nc2l_cancel_map,
                         &mut l2nc_cancel_set,
-                        false,
                     )
                     .await;
                     if let Err(e) = res {
-                        info!("{:?}", e);
-                        // break;
+                        warn!("{:?}", e);
                     }
+                } else {
+                    pending_exclude_reconcile = Some(old_rules);
                 }
-                NetworkStatus::Disconnect | NetworkStatus::Err(_) => {
-                    info!("It should be unreachable branch. something wrong.");
-                }
-            },
-            Command::UpdateExcFile | Command::UpdateConfigFile => {
+            }
+            Command::UpdateConfigFile => {
                 retry = true;
                 break;
                 /*
@@ -244,6 +481,12 @@ async fn run() -> Result<bool> {
                 return Ok(true);
                 */
             }
+            Command::HardRepair if dry_run => {
+                match repair::plan_hard_repair(&local_info, &nc_info).await {
+                    Ok(plan) => print_plan("hard repair", &plan),
+                    Err(e) => warn!("{:?}", e),
+                }
+            }
             Command::HardRepair => {
                 drop(root_watcher);
                 drop(meta_watcher);
@@ -252,59 +495,146 @@ async fn run() -> Result<bool> {
                 watching_handle.await?;
                 updateexcfile_handle.await?;
                 control_handle.abort();
+                socket_handle.abort();
+                retry_handle.abort();
+                release_handle.abort();
+                // the db is in `.ncs` and deleted with it.
+                drop(tree_store);
                 repair::all_delete(&local_info)?;
                 return Ok(true);
             }
+            Command::NormalRepair if dry_run => {
+                match repair::plan_normal_repair(&local_info, &nc_info).await {
+                    Ok(plan) => print_plan("normal repair", &plan),
+                    Err(e) => warn!("{:?}", e),
+                }
+            }
             Command::NormalRepair => {
-                let events = {
-                    let mut pr_ref = public_resource.lock().map_err(|_| LockError)?;
-                    get_ncevents(&nc_info, &local_info, &mut pr_ref.nc_state).await?
+                let res = match get_ncevents(&nc_info, &local_info, &mut public_resource.nc_state)
+                    .await
+                {
+                    Ok(events) => {
+                        repair::normal_repair(&local_info, &nc_info, &mut public_resource, events)
+                            .await
+                    }
+                    Err(e) => Err(e),
                 };
-                repair::normal_repair(&local_info, &nc_info, &public_resource, events).await?;
+                // mostly the network. The repair is tried again later.
+                if let Err(e) = res {
+                    warn!(
+                        "normal repair failed. retry after {} secs. | {:?}",
+                        RETRY_TICK_SECS, e
+                    );
+                    let tx = com_tx.clone();
+                    tokio::spawn(async move {
+                        sleep(Duration::from_secs(RETRY_TICK_SECS)).await;
+                        let _ = tx.send(Command::NormalRepair).await;
+                    });
+                    continue;
+                }
                 sleep(Duration::from_secs(20)).await;
                 retry = true;
                 break;
             }
-            Command::NetworkConnect => match network_status {
-                NetworkStatus::Connect => (),
-                _ => {
-                    // Reconnect situation
-
-                    /*
-                    let nc_events;
-                    {
-                        let mut pr_ref = public_resource.lock().map_err(|_| LockError)?;
-                        nc_events =
-                            get_ncevents(&nc_info, &local_info, &mut pr_ref.nc_state).await?;
-                        info!("nc_state: {:?}", pr_ref.nc_state);
+            Command::NetworkConnect => {
+                match network_status {
+                    NetworkStatus::Connect => (),
+                    NetworkStatus::Maintenance => {
+                        // Maintenance is over. The server kept its state, so no repair is needed.
+                        info!("maintenance mode is over.");
+                        network_status = NetworkStatus::Connect;
+                        for ev in offline_locevent_que.drain(..) {
+                            let op = FailedOp::Local(ev.clone());
+                            let res = deal_local_event(
+                                ev,
+                                public_resource.tree_mut(),
+                                &nc_info,
+                                &local_info,
+                                &mut nc2l_cancel_map,
+                                &mut l2nc_cancel_set,
+                            )
+                            .await;
+                            sync_record.record(&res);
+                            local_info.failures.settle_or_warn(op, &res);
+                            if let Err(e) = res {
+                                info!("{:?}", e);
+                            }
+                        }
+                    }
+                    _ if dry_run => {
+                        let events = {
+                            get_ncevents(&nc_info, &local_info, &mut public_resource.nc_state).await
+                        };
+                        match events {
+                            Ok(events) => {
+                                let mut plan = SyncPlan::new();
+                                plan.add_nc_events(&events);
+                                print_plan("reconnect", &plan);
+                            }
+                            Err(e) => warn!("{:?}", e),
+                        }
+                        network_status = NetworkStatus::Connect;
                     }
-                    repair::normal_repair(&local_info, &nc_info, &public_resource, nc_events)
+                    _ => {
+                        // Reconnect situation
+
+                        /*
+                        let nc_events;
+                        {
+                            nc_events =
+                                get_ncevents(&nc_info, &local_info, &mut public_resource.nc_state).await?;
+                            info!("nc_state: {:?}", public_resource.nc_state);
+                        }
+                        repair::normal_repair(&local_info, &nc_info, &mut public_resource, nc_events)
+                            .await?;
+                        // network_status = NetworkStatus::Connect;
+                        sleep(Duration::from_secs(20)).await;
+                        */
+
+                        let res = repair::soft_repair(
+                            &local_info,
+                            &nc_info,
+                            &mut public_resource,
+                            offline_locevent_que.drain(..).collect(),
+                            com_tx.clone(),
+                            &mut nc2l_cancel_map,
+                            &mut l2nc_cancel_set,
+                        )
                         .await?;
-                    // network_status = NetworkStatus::Connect;
-                    sleep(Duration::from_secs(20)).await;
-                    */
+                        if res {
+                            retry = true;
+                            break;
+                        } else {
+                            network_status = NetworkStatus::Connect;
+                            sync_record.record(&Ok(()));
+                            retry = false;
+                        }
+                    }
+                }
 
-                    let res = repair::soft_repair(
-                        &local_info,
+                if let Some(old_rules) = pending_exclude_reconcile.take() {
+                    let res = repair::reconcile_excludes(
+                        &old_rules,
+                        public_resource.tree_mut(),
                         &nc_info,
-                        &public_resource,
-                        offline_locevent_que.drain(..).collect(),
-                        com_tx.clone(),
+                        &local_info,
                         &mut nc2l_cancel_map,
                         &mut l2nc_cancel_set,
                     )
-                    .await?;
-                    if res {
-                        retry = true;
-                        break;
-                    } else {
-                        network_status = NetworkStatus::Connect;
-                        retry = false;
+                    .await;
+                    if let Err(e) = res {
+                        warn!("{:?}", e);
                     }
                 }
-            },
+            }
+            Command::NetworkMaintenance => {
+                if network_status.is_connect() {
+                    info!("server is in maintenance mode. syncing is paused.");
+                    network_status = NetworkStatus::Maintenance;
+                }
+            }
             Command::NetworkDisconnect => match network_status {
-                NetworkStatus::Connect => {
+                NetworkStatus::Connect | NetworkStatus::Maintenance => {
                     // disconnect situation
                     nc2l_cancel_map = HashMap::new();
                     l2nc_cancel_set = HashSet::new();
@@ -312,6 +642,34 @@ async fn run() -> Result<bool> {
                 }
                 _ => (),
             },
+            Command::RetryFailures => {
+                if dry_run || !network_status.is_connect() {
+                    continue;
+                }
+                let due = match local_info.failures.take_due() {
+                    Ok(due) => due,
+                    Err(e) => {
+                        warn!("can't get the failed operations. | {:?}", e);
+                        continue;
+                    }
+                };
+                if due.is_empty() {
+                    continue;
+                }
+                info!("retry {} failed operations.", due.len());
+                for op in due {
+                    let res = failures::retry(
+                        op.clone(),
+                        public_resource.tree_mut(),
+                        &nc_info,
+                        &local_info,
+                        &mut nc2l_cancel_map,
+                        &mut l2nc_cancel_set,
+                    )
+                    .await;
+                    local_info.failures.settle_or_warn(op, &res);
+                }
+            }
             Command::Terminate(r) => {
                 retry = r;
                 break;
@@ -320,6 +678,125 @@ async fn run() -> Result<bool> {
                 error = Some(e);
                 break;
             }
+            Command::Control(req, reply) => {
+                if dry_run && req.is_mutating() {
+                    let _ = reply.send("not available in the dry-run mode.".to_string());
+                    continue;
+                }
+                // a large tree is serialized off the loop, from a snapshot.
+                if let ControlRequest::Tree = req {
+                    let snapshot = public_resource.snapshot();
+                    tokio::task::spawn_blocking(move || {
+                        let res = root2json_entry(&snapshot)
+                            .and_then(|j| Ok(serde_json::to_string_pretty(&j)?));
+                        let _ = reply.send(res.unwrap_or_else(|e| format!("{:?}", e)));
+                    });
+                    continue;
+                }
+                let throttle = local_info.nc_client.throttle();
+                let res = match req {
+                    ControlRequest::Help => Ok(control::HELP.to_string()),
+                    ControlRequest::ShowLimits => Ok(control::show_limits(throttle)),
+                    ControlRequest::SetLimit(kind, value) => {
+                        Ok(control::set_limit(throttle, kind, value))
+                    }
+                    ControlRequest::SetSchedule(rules) => {
+                        Ok(control::set_schedule(throttle, rules))
+                    }
+                    ControlRequest::TrashList => control::trash_list(&nc_info, &local_info).await,
+                    ControlRequest::TrashRestore(target) => {
+                        control::trash_restore(&nc_info, &local_info, &target).await
+                    }
+                    ControlRequest::StashList(prefix) => {
+                        control::stash_list(&local_info, prefix.as_deref())
+                    }
+                    ControlRequest::StashShow(id) => control::stash_show(&local_info, id),
+                    ControlRequest::StashCleanup => control::stash_cleanup(&local_info),
+                    ControlRequest::StashRestore(id) => control::stash_restore(&local_info, id),
+                    ControlRequest::VersionList(path) => {
+                        control::version_list(&nc_info, &local_info, public_resource.tree(), &path)
+                            .await
+                    }
+                    ControlRequest::VersionGet(id, path) => {
+                        let tree = public_resource.tree();
+                        control::version_get(&nc_info, &local_info, tree, &path, &id).await
+                    }
+                    ControlRequest::VersionRestore(id, path) => {
+                        control::version_restore(
+                            &nc_info,
+                            &local_info,
+                            public_resource.tree_mut(),
+                            &mut nc2l_cancel_map,
+                            &path,
+                            &id,
+                        )
+                        .await
+                    }
+                    ControlRequest::CheckExcludes(path) => {
+                        Ok(local_info.exc_checker.explain(&path))
+                    }
+                    ControlRequest::ShowHeldDeletes => {
+                        Ok(control::show_held_deletes(delete_guard.held()))
+                    }
+                    ControlRequest::ConfirmHeldDeletes => {
+                        let held = delete_guard.release();
+                        let n = held.len();
+                        deal_local_events(
+                            held,
+                            dry_run,
+                            &network_status,
+                            &mut public_resource,
+                            &nc_info,
+                            &local_info,
+                            &mut nc2l_cancel_map,
+                            &mut l2nc_cancel_set,
+                            &mut offline_locevent_que,
+                            &mut sync_record,
+                        )
+                        .await;
+                        Ok(format!("{} deletions are propagated.", n))
+                    }
+                    ControlRequest::DiscardHeldDeletes => {
+                        let reply = control::discard_held_deletes(
+                            delete_guard.release(),
+                            public_resource.tree_mut(),
+                            &nc_info,
+                            &local_info,
+                            &mut nc2l_cancel_map,
+                        )
+                        .await;
+                        Ok(reply)
+                    }
+                    ControlRequest::ShowFailures => control::show_failures(&local_info.failures),
+                    ControlRequest::RetryFailures => control::retry_failures(&local_info.failures),
+                    ControlRequest::History(path) => control::history(&local_info, &path),
+                    ControlRequest::Status => {
+                        let queues = QueueLengths {
+                            offline_local_events: offline_locevent_que.len(),
+                            held_deletes: delete_guard.held().len(),
+                            nc2l_cancel: nc2l_cancel_map.values().sum(),
+                            l2nc_cancel: l2nc_cancel_set.len(),
+                        };
+                        status::report(
+                            &network_status,
+                            queues,
+                            public_resource.nc_state.latest_activity_id.clone(),
+                            &sync_record,
+                            public_resource.tree(),
+                            &local_info.failures,
+                        )
+                    }
+                    ControlRequest::Repair {
+                        kind,
+                        dry_run: true,
+                    } => control::repair_plan(kind, &local_info, &nc_info).await,
+                    ControlRequest::Repair { dry_run: false, .. }
+                    | ControlRequest::Terminate
+                    | ControlRequest::Tree => unreachable!(),
+                };
+                let res = res.unwrap_or_else(|e| format!("{:?}", e));
+                let _ = reply.send(res);
+            }
             Command::PullEvent { .. } => (),
         }
     }
@@ -333,18 +810,26 @@ async fn run() -> Result<bool> {
     watching_handle.await?;
     updateexcfile_handle.await?;
     control_handle.abort();
+    socket_handle.abort();
+    retry_handle.abort();
+    release_handle.abort();
+    let _ = std::fs::remove_file(local_info.get_control_socket_name());
 
-    let pr_ref = public_resource.lock().map_err(|_| LockError)?;
-    let json_entry = {
-        let r = pr_ref.root.lock().map_err(|_| LockError)?;
-        debug!("\n{}", r.get_tree());
-        root2json_entry(&r)?
-    };
-    save_cache(
-        pr_ref.nc_state.latest_activity_id.clone(),
-        json_entry,
-        &local_info,
-    )?;
+    if dry_run {
+        return error.map_or(Ok(retry), Err);
+    }
+
+    debug!("\n{}", public_resource.tree().get_tree());
+    let latest_activity_id = public_resource.nc_state.latest_activity_id.clone();
+    tree_store.save(public_resource.tree_mut(), &latest_activity_id)?;
+    tree_store.flush()?;
+    if let Err(e) = tree_store.write_backup(
+        public_resource.tree(),
+        &public_resource.nc_state.latest_activity_id,
+        &local_info.get_treebackup_name(),
+    ) {
+        warn!("can't write the backup of the tree. | {:?}", e);
+    }
 
     if let Some(e) = error {
         return Err(e);
@@ -353,27 +838,217 @@ async fn run() -> Result<bool> {
     Ok(retry)
 }
 
+// Local events are dealt at once when online, queued for the repair when not.
+#[allow(clippy::too_many_arguments)]
+async fn deal_local_events(
+    evs: Vec<LocalEvent>,
+    dry_run: bool,
+    network_status: &NetworkStatus,
+    public_resource: &mut PublicResource,
+    nc_info: &NCInfo,
+    local_info: &LocalInfo,
+    nc2l_cancel_map: &mut HashMap<String, usize>,
+    l2nc_cancel_set: &mut HashSet<NCEvent>,
+    offline_locevent_que: &mut Vec<LocalEvent>,
+    sync_record: &mut SyncRecord,
+) {
+    for ev in evs {
+        if dry_run {
+            let mut plan = SyncPlan::new();
+            plan.add_local_event(&ev);
+            print_plan("local event", &plan);
+            continue;
+        }
+        match network_status {
+            NetworkStatus::Connect => {
+                let op = FailedOp::Local(ev.clone());
+                let res = deal_local_event(
+                    ev,
+                    public_resource.tree_mut(),
+                    nc_info,
+                    local_info,
+                    nc2l_cancel_map,
+                    l2nc_cancel_set,
+                )
+                .await;
+                sync_record.record(&res);
+                local_info.failures.settle_or_warn(op, &res);
+                if let Err(e) = res {
+                    info!("{:?}", e);
+                }
+            }
+            NetworkStatus::Maintenance => {
+                debug!("LocEvent({:?}) @ maintenance", ev);
+                offline_locevent_que.push(ev);
+            }
+            NetworkStatus::Disconnect | NetworkStatus::Err(_) => {
+                debug!("LocEvent({:?}) @ offline", ev);
+                offline_locevent_que.push(ev);
+            }
+        }
+    }
+}
+
+fn print_plan(title: &str, plan: &SyncPlan) {
+    println!("[dry-run] {}\n{}", title, plan);
+}
+
+fn throttle_from_env() -> Result<Throttle> {
+    let mut limits = Limits::default();
+    for (key, kind) in [
+        ("NC_UPLOAD_LIMIT", LimitKind::Upload),
+        ("NC_DOWNLOAD_LIMIT", LimitKind::Download),
+        ("NC_REQUEST_LIMIT", LimitKind::Requests),
+    ] {
+        if let Ok(v) = env::var(key) {
+            limits.set(kind, throttle::parse_limit(&v)?);
+        }
+    }
+
+    let schedule = match env::var("NC_THROTTLE_SCHEDULE") {
+        Ok(v) => throttle::parse_schedule(&v)?,
+        Err(_) => Vec::new(),
+    };
+
+    Ok(Throttle::new(limits, schedule))
+}
+
+// NC_LOG_LEVEL: level of the log files. (off, error, warn, info, debug, trace)
+// stderr still follows RUST_LOG.
+fn log_level_from_env() -> Result<LevelFilter> {
+    match env::var("NC_LOG_LEVEL") {
+        Ok(v) => v
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid NC_LOG_LEVEL: {}", v)),
+        Err(_) => Ok(LevelFilter::Info),
+    }
+}
+
+fn retention_policy_from_env() -> Result<RetentionPolicy> {
+    let mut policy = RetentionPolicy::default();
+    if let Ok(v) = env::var("NC_AUTOSTASH_MAX_AGE") {
+        policy.max_age_days = throttle::parse_limit(&v)?.map(|v| v as u32);
+    }
+    if let Ok(v) = env::var("NC_AUTOSTASH_MAX_SIZE") {
+        policy.max_bytes = throttle::parse_limit(&v)?;
+    }
+    if let Ok(v) = env::var("NC_AUTOSTASH_MAX_VERSIONS") {
+        policy.max_versions = throttle::parse_limit(&v)?.map(|v| v as usize);
+    }
+
+    Ok(policy)
+}
+
+// NC_NAME_NORMALIZATION: "nfc" maps names on the server to NFC on the local. "none" by default.
+// NC_CASE_COLLISIONS: "rename" gives names differing only in case within a directory
+// other local names, "off" doesn't. "auto" (default) renames them if the local volume
+// is case-insensitive.
+// NC_FORBIDDEN_NAMES: what is done with local names the server rejects. "skip" (default) leaves
+// them out with a warning in the failures, "escape" uploads them under an escaped name.
+fn name_policy_from_env(metadir: &str, forbidden: &ForbiddenNames) -> Result<NamePolicy> {
+    let mut policy = NamePolicy {
+        forbidden: forbidden.clone(),
+        ..Default::default()
+    };
+    if let Ok(v) = env::var("NC_NAME_NORMALIZATION") {
+        policy.normalization = match v.to_lowercase().as_str() {
+            "nfc" => Normalization::Nfc,
+            "none" => Normalization::None,
+            _ => return Err(anyhow::anyhow!("Invalid NC_NAME_NORMALIZATION: {}", v)),
+        };
+    }
+    policy.case_insensitive = match env::var("NC_CASE_COLLISIONS") {
+        Ok(v) if v.eq_ignore_ascii_case("rename") => true,
+        Ok(v) if v.eq_ignore_ascii_case("off") => false,
+        Ok(v) if !v.eq_ignore_ascii_case("auto") => {
+            return Err(anyhow::anyhow!("Invalid NC_CASE_COLLISIONS: {}", v))
+        }
+        _ => names::is_case_insensitive(metadir)?,
+    };
+    if let Ok(v) = env::var("NC_FORBIDDEN_NAMES") {
+        policy.on_forbidden = match v.to_lowercase().as_str() {
+            "skip" => ForbiddenAction::Skip,
+            "escape" => ForbiddenAction::Escape,
+            _ => return Err(anyhow::anyhow!("Invalid NC_FORBIDDEN_NAMES: {}", v)),
+        };
+    }
+
+    Ok(policy)
+}
+
+// NC_SYMLINKS: how local symlinks are synced. "ignore" (default) leaves them out, "follow" syncs
+// what they point to if it's in the root (loops are skipped), "link" uploads a small file with
+// the target and makes a symlink from it again on download.
+fn symlink_policy_from_env() -> Result<SymlinkPolicy> {
+    match env::var("NC_SYMLINKS") {
+        Ok(v) => match v.to_lowercase().as_str() {
+            "ignore" => Ok(SymlinkPolicy::Ignore),
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "link" => Ok(SymlinkPolicy::Descriptor),
+            _ => Err(anyhow::anyhow!("Invalid NC_SYMLINKS: {}", v)),
+        },
+        Err(_) => Ok(SymlinkPolicy::default()),
+    }
+}
+
+type ControlLines = Arc<tokio::sync::Mutex<tokio_mpsc::Receiver<String>>>;
+
+// stdin is read by one thread through all reruns.
+fn spawn_stdin_reader() -> ControlLines {
+    let (tx, rx) = tokio_mpsc::channel(8);
+    std::thread::spawn(move || {
+        let stdin = std::io::stdin();
+        let mut ln = String::new();
+        while let Ok(n) = stdin.read_line(&mut ln) {
+            if n == 0 || tx.blocking_send(ln.clone()).is_err() {
+                break;
+            }
+            ln.clear();
+        }
+    });
+    Arc::new(tokio::sync::Mutex::new(rx))
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv().ok();
-    env_logger::init();
+    logging::init(log_level_from_env()?)?;
+
+    // `ncs check-excludes <path>` checks the exclude rules without starting to sync.
+    let args = env::args().collect::<Vec<_>>();
+    if args.get(1).map(String::as_str) == Some("check-excludes") {
+        let local_root_path = env::var("LOCAL_ROOT").expect("LOCAL_ROOT not found");
+        let rules = ExcludeRules::new(&drop_slash(&local_root_path, &RE_HAS_LAST_SLASH))?;
+        for path in args.iter().skip(2) {
+            println!("{}", rules.explain(path));
+        }
+        return Ok(());
+    }
+
+    // `NC_METRICS_ADDR=127.0.0.1:9898` serves metrics at http://127.0.0.1:9898/metrics
+    if let Ok(addr) = env::var("NC_METRICS_ADDR") {
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(&addr).await {
+                warn!("metrics are not served. | {:?}", e);
+            }
+        });
+    }
+
+    let control_lines = spawn_stdin_reader();
 
-    while run().await? {}
+    while run(control_lines.clone()).await? {}
 
     Ok(())
 }
 
-async fn init(nc_info: &NCInfo, local_info: &LocalInfo) -> Result<(ArcEntry, String)> {
-    let root_entry = from_nc_all(nc_info, local_info, "/").await?;
-    let latest_activity_id = get_latest_activity_id(nc_info, local_info).await?;
+async fn init(nc_info: &NCInfo, local_info: &LocalInfo) -> Result<(Tree, String)> {
+    let mut tree = from_nc_all(nc_info, local_info, "/").await?;
+    let latest_activity_id = get_latest_activity_id(local_info).await?;
     debug!("{}", latest_activity_id);
 
-    init_local_entries(nc_info, local_info, &root_entry, "").await?;
+    init_local_entries(local_info, &mut tree, Tree::ROOT, "").await?;
 
-    {
-        let r = root_entry.lock().map_err(|_| LockError)?;
-        println!("\n{}", r.get_tree());
-    }
+    println!("\n{}", tree.get_tree());
 
-    Ok((root_entry, latest_activity_id))
+    Ok((tree, latest_activity_id))
 }
@@ -1,112 +1,204 @@
+use crate::attrs::AttrRules;
+use crate::capabilities::ServerCapabilities;
 use crate::errors::NcsError::*;
+use crate::failures::FailureRegistry;
+use crate::history::HistoryStore;
+use crate::names::NamePolicy;
+use crate::nc_client::NcClient;
+use crate::stash::{RetentionPolicy, StashIndex};
+use crate::symlinks::{LocalKind, SymlinkPolicy};
 use crate::*;
 use anyhow::Result;
 use chrono::prelude::*;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
 use notify::DebouncedEvent;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::sync::mpsc as std_mpsc;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex, RwLock};
 use std::{fs, io::Write};
 use tokio::sync::mpsc::Sender as TokioSender;
 // use std::time::Duration as StdDuration;
 // use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
 
 const NC_ROOT_PREFIX: &str = "/remote.php/dav/files/";
+const NC_LEGACY_ROOT: &str = "/remote.php/webdav";
+pub const NC_UPLOADS_PREFIX: &str = "/remote.php/dav/uploads/";
 pub const OCS_ROOT: &str = "/ocs/v2.php/apps/activity/api/v2/activity/all";
 
+// the format of NCSCache. 1 is `cache.json` of the older versions, which had no version.
+pub const CACHE_VERSION: u32 = 2;
+
+fn legacy_cache_version() -> u32 {
+    1
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NCSCache {
+    #[serde(default = "legacy_cache_version")]
+    pub version: u32,
     pub latest_activity_id: String,
     pub root_entry: JsonEntry,
 }
 
+impl NCSCache {
+    pub fn new(latest_activity_id: String, root_entry: JsonEntry) -> Self {
+        Self {
+            version: CACHE_VERSION,
+            latest_activity_id,
+            root_entry,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 pub enum JsonEntry {
     Dir {
         name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        remote_name: Option<String>,
         children: Vec<JsonEntry>,
     },
     File {
         name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        remote_name: Option<String>,
         etag: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mtime: Option<i64>,
     },
 }
 
-pub fn root2json_entry(root_entry: &Entry) -> Result<JsonEntry> {
-    if !root_entry.is_root() {
+pub fn root2json_entry(tree: &Tree) -> Result<JsonEntry> {
+    if !tree.root().is_root() {
         return Err(anyhow!("This function can be called by root entry."));
     }
 
-    entry2json_entry_rec(root_entry)
+    Ok(entry2json_entry_rec(tree, Tree::ROOT))
 }
 
-fn entry2json_entry_rec(entry: &Entry) -> Result<JsonEntry> {
+fn entry2json_entry_rec(tree: &Tree, id: EntryId) -> JsonEntry {
+    let entry = tree.entry(id).unwrap();
+    let remote_name = Some(entry.get_remote_raw_name()).filter(|r| r != &entry.get_raw_name());
     if entry.type_.is_file() {
-        Ok(JsonEntry::File {
+        JsonEntry::File {
             name: entry.get_name(),
+            remote_name,
             etag: entry.type_.get_etag(),
-        })
+            mtime: entry.mtime,
+        }
     } else {
         let children = entry
-            .children
-            .values()
-            .map(|c| -> Result<JsonEntry> {
-                let c_ref = c.lock().map_err(|_| LockError)?;
-                entry2json_entry_rec(&c_ref)
-            })
-            .collect::<Result<Vec<JsonEntry>>>()?;
-        Ok(JsonEntry::Dir {
+            .get_all_children()
+            .into_iter()
+            .map(|c| entry2json_entry_rec(tree, c))
+            .collect();
+        JsonEntry::Dir {
             name: entry.get_name(),
+            remote_name,
             children,
-        })
+        }
     }
 }
 
-pub fn json_entry2entry(json_entry: JsonEntry) -> Result<ArcEntry> {
+pub fn json_entry2tree(json_entry: JsonEntry) -> Result<Tree> {
+    let mut tree = Tree::new();
+    tree.root_mut().status = EntryStatus::UpToDate;
+    if let JsonEntry::Dir { children, .. } = json_entry {
+        for child in children.into_iter() {
+            json_entry2entry_rec(&mut tree, Tree::ROOT, child)?;
+        }
+    }
+
+    Ok(tree)
+}
+
+fn json_entry2entry_rec(tree: &mut Tree, parent: EntryId, json_entry: JsonEntry) -> Result<()> {
     match json_entry {
-        JsonEntry::Dir { name, children } => {
+        JsonEntry::Dir {
+            name,
+            remote_name,
+            children,
+        } => {
             let mut entry = Entry::new(name, EntryType::Directory);
+            if let Some(r) = remote_name {
+                entry.set_remote_name(&r);
+            }
             entry.status = EntryStatus::UpToDate;
-            let dir = Arc::new(Mutex::new(entry));
+            let dir = tree.add_child(parent, entry)?;
             for child in children.into_iter() {
-                let child = json_entry2entry(child)?;
-                Entry::append_child(&dir, child)?;
+                json_entry2entry_rec(tree, dir, child)?;
             }
-            Ok(dir)
         }
-        JsonEntry::File { name, etag } => {
+        JsonEntry::File {
+            name,
+            remote_name,
+            etag,
+            mtime,
+        } => {
             let type_ = EntryType::File { etag: Some(etag) };
             let mut entry = Entry::new(name, type_);
+            entry.mtime = mtime;
+            if let Some(r) = remote_name {
+                entry.set_remote_name(&r);
+            }
             entry.status = EntryStatus::UpToDate;
-            Ok(Arc::new(Mutex::new(entry)))
+            tree.add_child(parent, entry)?;
         }
     }
+
+    Ok(())
 }
 
-pub fn load_cache(local_info: &LocalInfo) -> Result<NCSCache> {
-    let j = fs::read_to_string(local_info.get_cachefile_name().as_str())?;
-    Ok(serde_json::from_str(&j)?)
+// `cache.json` of the older versions or the last-good backup of `tree.db`. (see tree_store)
+pub fn load_cache(cache_file: &str) -> Result<NCSCache> {
+    let j = fs::read_to_string(cache_file)?;
+    let ncs_cache: NCSCache = serde_json::from_str(&j)?;
+    if ncs_cache.version > CACHE_VERSION {
+        return Err(anyhow!(
+            "{}: unknown cache version {}.",
+            cache_file,
+            ncs_cache.version
+        ));
+    }
+
+    Ok(ncs_cache)
 }
 
-pub fn save_cache(
-    latest_activity_id: String,
-    root_entry: JsonEntry,
-    local_info: &LocalInfo,
-) -> Result<()> {
-    fs::create_dir_all(local_info.get_metadir_name().as_str())?;
+pub fn save_cache(ncs_cache: &NCSCache, cache_file: &str) -> Result<()> {
+    let j = serde_json::to_string(ncs_cache)?;
+    write_atomic(cache_file, format!("{}\n", j).as_bytes())
+}
 
-    let ncs_cache = NCSCache {
-        latest_activity_id,
-        root_entry,
-    };
-    let j = serde_json::to_string(&ncs_cache)?;
-    let mut cache_file = fs::File::create(local_info.get_cachefile_name().as_str())?;
-    writeln!(cache_file, "{}", j)?;
+// Written to a temporary file next to `path` and renamed over it,
+// so `path` has the old or the new content even on a crash.
+pub fn write_atomic<P: AsRef<Path>>(path: P, contents: &[u8]) -> Result<()> {
+    let path = path.as_ref();
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| InvalidPathError(format!("{:?} has no file name.", path)))?;
+    let tmp_path = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    {
+        let mut f = fs::File::create(&tmp_path)?;
+        f.write_all(contents)?;
+        f.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    // the rename itself.
+    if let Some(parent) = path.parent() {
+        if let Ok(dir) = fs::File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
 
     Ok(())
 }
@@ -116,33 +208,58 @@ pub struct LocalInfo {
     pub root_path: String,
     pub root_path_cano: PathBuf,
     pub exc_checker: meta::ExcludeChecker,
-    log_file_name: String,
-    pub req_client: reqwest::Client,
-    pub autostash_keep_span: u32,
+    pub nc_client: NcClient,
+    pub retention_policy: RetentionPolicy,
+    pub names: NamePolicy,
+    pub symlinks: SymlinkPolicy,
+    pub attrs: AttrRules,
+    pub stash_index: StashIndex,
+    pub failures: FailureRegistry,
+    pub history: HistoryStore,
 }
 
 impl LocalInfo {
-    const AUTOSTASH_KEEP_SPAN_DEFAULT: u32 = 7;
-
-    pub fn new(root_path: String, req_client: reqwest::Client) -> Result<Self> {
+    pub fn new(root_path: String, nc_client: NcClient) -> Result<Self> {
         let root_path = drop_slash(&root_path, &RE_HAS_LAST_SLASH);
         let root_path_cano = Path::new(&root_path).canonicalize()?;
         let exc_checker = meta::ExcludeChecker::new(&root_path)?;
-        let dt = Local::now();
-        let log_file_name = format!("{}.log", dt.format("%Y%m%d"));
+        let attrs = AttrRules::load(Self::get_attrsfile_name_raw(&root_path))?;
+        let stash_index = StashIndex::load(Self::get_stashindex_name_raw(&root_path));
+        let history = HistoryStore::new(Self::get_historyfile_name_raw(&root_path));
         Ok(Self {
             root_path,
             root_path_cano,
             exc_checker,
-            log_file_name,
-            req_client,
-            autostash_keep_span: Self::AUTOSTASH_KEEP_SPAN_DEFAULT,
+            nc_client,
+            retention_policy: RetentionPolicy::default(),
+            names: NamePolicy::default(),
+            symlinks: SymlinkPolicy::default(),
+            attrs,
+            stash_index,
+            failures: FailureRegistry::new(),
+            history,
         })
     }
 
-    pub fn set_autostash_keep_span(&mut self, span: u32) {
-        debug!("set autostash keep span to {}", span);
-        self.autostash_keep_span = span;
+    pub fn set_retention_policy(&mut self, policy: RetentionPolicy) {
+        debug!("set autostash retention policy: {}", policy);
+        self.retention_policy = policy;
+    }
+
+    pub fn set_name_policy(&mut self, policy: NamePolicy) {
+        debug!("set name policy: {}", policy);
+        self.names = policy;
+    }
+
+    pub fn set_symlink_policy(&mut self, policy: SymlinkPolicy) {
+        debug!("set symlink policy: {}", policy);
+        self.symlinks = policy;
+    }
+
+    // what the local entry at `local_path` is to the sync, with the symlink policy.
+    pub fn local_kind(&self, local_path: &Path) -> LocalKind {
+        self.symlinks
+            .classify(local_path, Path::new(&self.root_path), &self.root_path_cano)
     }
 
     pub fn get_metadir_name(&self) -> String {
@@ -153,14 +270,38 @@ impl LocalInfo {
         format!("{}cache.json", self.get_metadir_name())
     }
 
+    pub fn get_treedb_name(&self) -> String {
+        format!("{}tree.db", self.get_metadir_name())
+    }
+
+    pub fn get_treebackup_name(&self) -> String {
+        format!("{}tree.last-good.json", self.get_metadir_name())
+    }
+
     pub fn get_excludefile_name(&self) -> String {
         format!("{}excludes.json", self.get_metadir_name())
     }
 
+    pub fn get_attrsfile_name(&self) -> String {
+        format!("{}attrs.json", self.get_metadir_name())
+    }
+
     pub fn get_stashpath_name(&self) -> String {
         format!("{}stash", self.get_metadir_name())
     }
 
+    pub fn get_stashindex_name(&self) -> String {
+        format!("{}stash.json", self.get_metadir_name())
+    }
+
+    pub fn get_helddeletes_name(&self) -> String {
+        format!("{}held_deletes.json", self.get_metadir_name())
+    }
+
+    pub fn get_historyfile_name(&self) -> String {
+        format!("{}history.jsonl", self.get_metadir_name())
+    }
+
     pub fn get_autostashpath_name(&self) -> String {
         format!("{}autostash", self.get_metadir_name())
     }
@@ -170,14 +311,32 @@ impl LocalInfo {
         format!("{}/{}", self.get_autostashpath_name(), dt.format("%Y%m%d"))
     }
 
+    // where downloads are written before being moved into place.
+    pub fn get_downloadpath_name(&self) -> String {
+        format!("{}download", self.get_metadir_name())
+    }
+
+    pub fn get_logdir_name(&self) -> String {
+        format!("{}log", self.get_metadir_name())
+    }
+
+    // of today. The file is switched at midnight by the logger.
     pub fn get_logfile_name(&self) -> String {
-        format!("{}log/{}", self.get_metadir_name(), self.log_file_name)
+        format!(
+            "{}/{}.log",
+            self.get_logdir_name(),
+            Local::now().format("%Y%m%d")
+        )
     }
 
     pub fn get_keepalive_filename(&self) -> String {
         format!("{}.keepalive.txt", self.get_metadir_name())
     }
 
+    pub fn get_control_socket_name(&self) -> String {
+        format!("{}control.sock", self.get_metadir_name())
+    }
+
     pub fn get_metadir_name_raw(root_path: &str) -> String {
         format!("{}/.ncs/", root_path)
     }
@@ -189,12 +348,35 @@ impl LocalInfo {
     pub fn get_excludefile_name_raw(root_path: &str) -> String {
         format!("{}excludes.json", Self::get_metadir_name_raw(root_path))
     }
+
+    pub fn get_attrsfile_name_raw(root_path: &str) -> String {
+        format!("{}attrs.json", Self::get_metadir_name_raw(root_path))
+    }
+
+    pub fn get_capabilitiesfile_name_raw(root_path: &str) -> String {
+        format!("{}capabilities.json", Self::get_metadir_name_raw(root_path))
+    }
+
+    pub fn get_stashindex_name_raw(root_path: &str) -> String {
+        format!("{}stash.json", Self::get_metadir_name_raw(root_path))
+    }
+
+    pub fn get_historyfile_name_raw(root_path: &str) -> String {
+        format!("{}history.jsonl", Self::get_metadir_name_raw(root_path))
+    }
+}
+
+fn default_true() -> bool {
+    true
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct JsonExcludeList {
     pub blacks: Vec<String>,
     pub whites: Vec<String>,
+    // the built-in rule which excludes every name starting with ".". `.ncs` is always excluded.
+    #[serde(default = "default_true")]
+    pub ignore_dotfiles: bool,
 }
 
 impl JsonExcludeList {
@@ -202,6 +384,7 @@ impl JsonExcludeList {
         Self {
             blacks: Vec::new(),
             whites: Vec::new(),
+            ignore_dotfiles: true,
         }
     }
 
@@ -216,14 +399,10 @@ impl JsonExcludeList {
     }
 
     pub fn save_excludelist(&self, root_path: &str) -> Result<()> {
-        fs::create_dir_all(LocalInfo::get_metadir_name_raw(root_path).as_str())?;
         let f_path = LocalInfo::get_excludefile_name_raw(root_path);
 
         let j = serde_json::to_string_pretty(self)?;
-        let mut exc_file = fs::File::create(&f_path)?;
-        writeln!(exc_file, "{}", j)?;
-
-        Ok(())
+        write_atomic(&f_path, format!("{}\n", j).as_bytes())
     }
 }
 
@@ -245,7 +424,10 @@ pub async fn exc_list_update_watching(
         let c = {
             let rx_ref = rx.lock().map_err(|_| LockError)?;
             match rx_ref.recv() {
-                Ok(DebouncedEvent::Create(p)) | Ok(DebouncedEvent::Write(p)) => {
+                // editors and `write_atomic` replace the file by a rename.
+                Ok(DebouncedEvent::Create(p))
+                | Ok(DebouncedEvent::Write(p))
+                | Ok(DebouncedEvent::Rename(_, p)) => {
                     if p.file_name() == Some(exc_file) {
                         Some(Command::UpdateExcFile)
                     } else {
@@ -265,73 +447,341 @@ pub async fn exc_list_update_watching(
     }
 }
 
+pub const NCSIGNORE_FILE: &str = ".ncsignore";
+const METADIR: &str = ".ncs";
+
+// Shared by the clones of LocalInfo. `reload` swaps the rules in place.
 #[derive(Clone)]
 pub struct ExcludeChecker {
-    blacks: Vec<Regex>,
-    whites: Vec<Regex>,
+    rules: Arc<RwLock<ExcludeRules>>,
 }
 
 impl ExcludeChecker {
     pub fn new(root_path: &str) -> Result<Self> {
-        let raw_list = JsonExcludeList::from_json(root_path)?;
-        let mut blacks = raw_list
-            .blacks
-            .into_iter()
-            .filter_map(|s| Regex::new(&s).ok())
-            .collect::<Vec<_>>();
-        blacks.push(Regex::new(r"^\.").unwrap());
-        blacks.push(Regex::new(r"^~").unwrap());
-        let whites = raw_list
-            .whites
-            .into_iter()
-            .filter_map(|s| Regex::new(&s).ok())
-            .collect::<Vec<_>>();
-        Ok(Self { blacks, whites })
+        Ok(Self {
+            rules: Arc::new(RwLock::new(ExcludeRules::new(root_path)?)),
+        })
+    }
+
+    // returns the previous rules. If the new ones are invalid, the current ones are kept.
+    pub fn reload(&self) -> Result<ExcludeRules> {
+        let new_rules = {
+            let rules = self.rules.read().map_err(|_| LockError)?;
+            ExcludeRules::new(&path2str(&rules.root_path))?
+        };
+        let mut rules = self.rules.write().map_err(|_| LockError)?;
+
+        Ok(std::mem::replace(&mut *rules, new_rules))
+    }
+
+    pub fn judge<P>(&self, p: P) -> bool
+    where
+        P: AsRef<Path>,
+    {
+        match self.rules.read() {
+            Ok(rules) => rules.judge(p),
+            Err(_) => false,
+        }
+    }
+
+    pub fn judge_dir<P>(&self, p: P, is_dir: bool) -> bool
+    where
+        P: AsRef<Path>,
+    {
+        match self.rules.read() {
+            Ok(rules) => rules.judge_dir(p, is_dir),
+            Err(_) => false,
+        }
+    }
+
+    pub fn explain<P>(&self, p: P) -> String
+    where
+        P: AsRef<Path>,
+    {
+        match self.rules.read() {
+            Ok(rules) => rules.explain(p),
+            Err(_) => format!("{}", LockError),
+        }
     }
+}
 
+// A pattern which failed to compile. `list` is "blacks", "whites" or the path of a `.ncsignore`.
+// For a `.ncsignore`, `index` is the line number.
+#[derive(Debug, Clone)]
+pub struct PatternError {
+    pub list: String,
+    pub index: usize,
+    pub pattern: String,
+    pub error: String,
+}
+
+impl std::fmt::Display for PatternError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}[{}] {:?}: {}",
+            self.list, self.index, self.pattern, self.error
+        )
+    }
+}
+
+// (regex, where it came from)
+type LabeledRegex = (Regex, String);
+
+fn compile_regexes(
+    list: &str,
+    patterns: Vec<String>,
+    errors: &mut Vec<PatternError>,
+) -> Vec<LabeledRegex> {
+    patterns
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, pattern)| match Regex::new(&pattern) {
+            Ok(r) => Some((r, format!("{}[{}] {:?}", list, index, pattern))),
+            Err(e) => {
+                errors.push(PatternError {
+                    list: list.to_string(),
+                    index,
+                    pattern,
+                    error: e.to_string(),
+                });
+                None
+            }
+        })
+        .collect()
+}
+
+#[derive(Clone)]
+pub struct ExcludeRules {
+    root_path: PathBuf,
+    blacks: Vec<LabeledRegex>,
+    whites: Vec<LabeledRegex>,
+    // (directory relative to the root, rules of its `.ncsignore`). Shallower first.
+    ignores: Vec<(PathBuf, Gitignore)>,
+}
+
+impl ExcludeRules {
+    // Every invalid pattern is reported. Nothing is loaded if there is one.
+    pub fn new(root_path: &str) -> Result<Self> {
+        let raw_list = JsonExcludeList::from_json(root_path).map_err(|e| {
+            InvalidExcludeError(format!(
+                "{}: {}",
+                LocalInfo::get_excludefile_name_raw(root_path),
+                e
+            ))
+        })?;
+
+        let mut errors = Vec::new();
+        let mut blacks = compile_regexes("blacks", raw_list.blacks, &mut errors);
+        if raw_list.ignore_dotfiles {
+            let r = Regex::new(r"^\.").unwrap();
+            blacks.push((r, "built-in dotfile rule (ignore_dotfiles)".to_string()));
+        }
+        blacks.push((
+            Regex::new(r"^~").unwrap(),
+            "built-in \"~\" rule".to_string(),
+        ));
+        let whites = compile_regexes("whites", raw_list.whites, &mut errors);
+
+        let mut rules = Self {
+            root_path: PathBuf::from(root_path),
+            blacks,
+            whites,
+            ignores: Vec::new(),
+        };
+        rules.load_ignore_files(Path::new(""), &mut errors);
+
+        if !errors.is_empty() {
+            let report = errors
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>()
+                .join("\n");
+            return Err(InvalidExcludeError(report).into());
+        }
+
+        Ok(rules)
+    }
+
+    // Read `.ncsignore` files from `dir` downward. Like git, excluded directories are not looked into.
+    fn load_ignore_files(&mut self, dir: &Path, errors: &mut Vec<PatternError>) {
+        let local_dir = self.root_path.join(dir);
+
+        let ignore_file = local_dir.join(NCSIGNORE_FILE);
+        if ignore_file.is_file() {
+            let list = path2str(&dir.join(NCSIGNORE_FILE));
+            let mut builder = GitignoreBuilder::new(&local_dir);
+            match fs::read_to_string(&ignore_file) {
+                Ok(text) => {
+                    for (index, line) in text.lines().enumerate() {
+                        if let Err(e) = builder.add_line(Some(ignore_file.clone()), line) {
+                            errors.push(PatternError {
+                                list: list.clone(),
+                                index: index + 1,
+                                pattern: line.to_string(),
+                                error: e.to_string(),
+                            });
+                        }
+                    }
+                }
+                Err(e) => errors.push(PatternError {
+                    list: list.clone(),
+                    index: 0,
+                    pattern: String::new(),
+                    error: e.to_string(),
+                }),
+            }
+            match builder.build() {
+                Ok(gi) => self.ignores.push((dir.to_path_buf(), gi)),
+                Err(e) => errors.push(PatternError {
+                    list,
+                    index: 0,
+                    pattern: String::new(),
+                    error: e.to_string(),
+                }),
+            }
+        }
+
+        let read_dir = match fs::read_dir(&local_dir) {
+            Ok(r) => r,
+            Err(_) => return,
+        };
+        for entry in read_dir.flatten() {
+            let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+            if !is_dir {
+                continue;
+            }
+            let child = dir.join(entry.file_name());
+            if self.judge_dir(&child, true) {
+                self.load_ignore_files(&child, errors);
+            }
+        }
+    }
+
+    // `p` is relative to the root ("/a/b", "a/b") or a local path under the root.
+    fn relative_path(&self, p: &Path) -> PathBuf {
+        let p = p.strip_prefix(&self.root_path).unwrap_or(p);
+        p.components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect()
+    }
+
+    // Whether the last component is a directory is looked up on the local disk.
+    // Use `judge_dir` when the type is known. (e.g. remote entries)
     pub fn judge<P>(&self, p: P) -> bool
     where
         P: AsRef<Path>,
     {
-        let path = p.as_ref();
+        self.decide(p.as_ref(), None).is_ok()
+    }
+
+    pub fn judge_dir<P>(&self, p: P, is_dir: bool) -> bool
+    where
+        P: AsRef<Path>,
+    {
+        self.decide(p.as_ref(), Some(is_dir)).is_ok()
+    }
+
+    // Which rule includes or excludes the path.
+    pub fn explain<P>(&self, p: P) -> String
+    where
+        P: AsRef<Path>,
+    {
+        let path = self.relative_path(p.as_ref());
+        match self.decide(p.as_ref(), None) {
+            Ok(Some(rule)) => format!("{}: included by {}", path2str(&path), rule),
+            Ok(None) => format!("{}: included (no rule matched)", path2str(&path)),
+            Err(rule) => format!("{}: excluded by {}", path2str(&path), rule),
+        }
+    }
+
+    // Ok(rule which included it explicitly) or Err(rule which excluded it)
+    fn decide(
+        &self,
+        p: &Path,
+        is_dir: Option<bool>,
+    ) -> std::result::Result<Option<String>, String> {
+        let path = self.relative_path(p);
+
+        if path.starts_with(METADIR) {
+            return Err(format!("the meta directory ({})", METADIR));
+        }
+
+        let mut included_by = None;
         'compcheck: for c in path.components() {
             let s = c.as_os_str().to_string_lossy();
-            for r in self.whites.iter() {
+            for (r, label) in self.whites.iter() {
                 if r.is_match(&s) {
+                    included_by = Some(format!("{} (at {:?})", label, s));
                     continue 'compcheck;
                 }
             }
 
-            for r in self.blacks.iter() {
+            for (r, label) in self.blacks.iter() {
                 if r.is_match(&s) {
-                    return false;
+                    return Err(format!("{} (at {:?})", label, s));
                 }
             }
         }
 
-        /*
-        // check its name.
-        let s_w = path.file_name();
-        let s = if let Some(v) = s_w {
-            v.to_string_lossy()
-        } else {
-            return false;
-        };
+        match self.ignored_by(&path, is_dir) {
+            Some(Ok(rule)) => Err(rule),
+            Some(Err(rule)) => Ok(Some(rule)),
+            None => Ok(included_by),
+        }
+    }
+
+    // Each ancestor directory is checked first. Files under an excluded directory can't be re-included.
+    // Some(Ok(ignoring rule)) or Some(Err(whitelisting rule))
+    fn ignored_by(
+        &self,
+        path: &Path,
+        is_dir: Option<bool>,
+    ) -> Option<std::result::Result<String, String>> {
+        if self.ignores.is_empty() {
+            return None;
+        }
 
-        for r in self.white_files.iter() {
-            if r.is_match(&s) {
-                return true;
+        let comps = path.components().collect::<Vec<_>>();
+        let mut cur = PathBuf::new();
+        let mut res = None;
+        for (i, c) in comps.iter().enumerate() {
+            cur.push(c);
+            let cur_is_dir = if i + 1 < comps.len() {
+                true
+            } else {
+                is_dir.unwrap_or_else(|| self.root_path.join(&cur).is_dir())
+            };
+            res = self.matched(&cur, cur_is_dir);
+            if let Some(Ok(_)) = res {
+                return res;
             }
         }
 
-        for r in self.black_files.iter() {
-            if r.is_match(&s) {
-                return false;
+        res
+    }
+
+    // A deeper `.ncsignore` takes precedence. In a file, the last matching pattern wins.
+    fn matched(&self, path: &Path, is_dir: bool) -> Option<std::result::Result<String, String>> {
+        for (dir, gi) in self.ignores.iter().rev() {
+            if path == dir || !path.starts_with(dir) {
+                continue;
+            }
+            let describe = |glob: &ignore::gitignore::Glob| {
+                format!(
+                    "{} {:?}",
+                    path2str(&dir.join(NCSIGNORE_FILE)),
+                    glob.original()
+                )
+            };
+            match gi.matched(self.root_path.join(path), is_dir) {
+                Match::Ignore(glob) => return Some(Ok(describe(glob))),
+                Match::Whitelist(glob) => return Some(Err(describe(glob))),
+                Match::None => (),
             }
         }
-        */
 
-        true
+        None
     }
 }
 
@@ -341,6 +791,7 @@ pub struct NCInfo {
     pub password: String,
     pub host: String,
     pub root_path: String,
+    pub capabilities: ServerCapabilities,
 }
 
 impl NCInfo {
@@ -353,6 +804,109 @@ impl NCInfo {
             password,
             host,
             root_path,
+            capabilities: ServerCapabilities::assumed(),
         }
     }
+
+    pub fn set_capabilities(&mut self, capabilities: ServerCapabilities) {
+        if !capabilities.dav {
+            info!("remote.php/dav is not available. use {}", NC_LEGACY_ROOT);
+            self.root_path = NC_LEGACY_ROOT.to_string();
+        }
+        self.capabilities = capabilities;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ncsignore_test() {
+        let root = std::env::temp_dir().join(format!("ncs_ncsignore_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for d in ["build", "src/build", "vendor/x", "sub"] {
+            fs::create_dir_all(root.join(d)).unwrap();
+        }
+        fs::write(
+            root.join(NCSIGNORE_FILE),
+            "/build/\n*.o\n!vendor/**/*.o\n**/tmp\n",
+        )
+        .unwrap();
+        fs::write(root.join("sub").join(NCSIGNORE_FILE), "secret.txt\n").unwrap();
+
+        let checker = ExcludeChecker::new(&path2str(&root)).unwrap();
+
+        // anchoring and directory-only
+        assert!(!checker.judge_dir("/build", true));
+        assert!(checker.judge_dir("/build", false));
+        assert!(checker.judge_dir("/src/build", true));
+        assert!(!checker.judge("/build/x.txt"));
+        // negation
+        assert!(!checker.judge("/a.o"));
+        assert!(!checker.judge("/src/a.o"));
+        assert!(checker.judge("/vendor/x/a.o"));
+        // **
+        assert!(!checker.judge_dir("/a/b/tmp", true));
+        // nested
+        assert!(!checker.judge("/sub/secret.txt"));
+        assert!(checker.judge("/secret.txt"));
+        // built-in rules
+        assert!(!checker.judge("/.gitignore"));
+        assert!(!checker.judge("/~memo.txt"));
+        assert!(!checker.judge(root.join(".ncs/cache.json")));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn exclude_validation_test() {
+        let root = std::env::temp_dir().join(format!(
+            "ncs_exclude_validation_test_{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&root);
+        let root_s = path2str(&root);
+        fs::create_dir_all(&root).unwrap();
+
+        let list = JsonExcludeList {
+            blacks: vec![r"\.tmp$".to_string(), "(unclosed".to_string()],
+            whites: vec![r"^\.gitignore$".to_string()],
+            ignore_dotfiles: true,
+        };
+        list.save_excludelist(&root_s).unwrap();
+        fs::write(root.join(NCSIGNORE_FILE), "*.log\nbad[\n").unwrap();
+
+        let err = ExcludeRules::new(&root_s).err().unwrap().to_string();
+        assert!(err.contains(r#"blacks[1] "(unclosed""#));
+        assert!(err.contains(r#"/.ncsignore[2] "bad[""#));
+
+        // the previous rules are kept.
+        fs::write(root.join(NCSIGNORE_FILE), "*.log\n").unwrap();
+        let list = JsonExcludeList {
+            blacks: vec![r"\.tmp$".to_string()],
+            ..list
+        };
+        list.save_excludelist(&root_s).unwrap();
+        let checker = ExcludeChecker::new(&root_s).unwrap();
+        fs::write(root.join(NCSIGNORE_FILE), "bad[\n").unwrap();
+        assert!(checker.reload().is_err());
+        assert!(!checker.judge("/a.log"));
+
+        assert_eq!(
+            checker.explain("/a.txt"),
+            "/a.txt: included (no rule matched)"
+        );
+        assert!(checker
+            .explain("/a.tmp")
+            .contains(r#"excluded by blacks[0]"#));
+        assert!(checker
+            .explain("/.gitignore")
+            .contains("included by whites[0]"));
+        assert!(checker
+            .explain("/x/a.log")
+            .contains(r#"excluded by /.ncsignore "*.log""#));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
 }
@@ -0,0 +1,289 @@
+use crate::errors::NcsError;
+use crate::network::NetworkStatus;
+use anyhow::Result;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+const NETWORK_STATES: [&str; 4] = ["connect", "maintenance", "disconnect", "error"];
+
+// counters by label. The key is the label part of a line. ex) `source="local",type="create"`
+#[derive(Debug, Default)]
+struct LabeledCounter(Mutex<BTreeMap<String, u64>>);
+
+impl LabeledCounter {
+    fn inc(&self, labels: String) {
+        if let Ok(mut map) = self.0.lock() {
+            *map.entry(labels).or_insert(0) += 1;
+        }
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "counter");
+        if let Ok(map) = self.0.lock() {
+            for (labels, v) in map.iter() {
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels, v);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    bytes_uploaded: AtomicU64,
+    bytes_downloaded: AtomicU64,
+    files_uploaded: AtomicU64,
+    files_downloaded: AtomicU64,
+    events: LabeledCounter,
+    repairs: LabeledCounter,
+    errors: LabeledCounter,
+    offline_local_events: AtomicU64,
+    held_deletes: AtomicU64,
+    failures: AtomicU64,
+    // index of NETWORK_STATES
+    network_state: AtomicU8,
+    poll_count: AtomicU64,
+    poll_micros_sum: AtomicU64,
+    poll_micros_last: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record_upload(&self, bytes: usize) {
+        self.bytes_uploaded
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.files_uploaded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_download(&self, bytes: usize) {
+        self.bytes_downloaded
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.files_downloaded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // `source` is "local" or "nc". `kind` is the event variant.
+    pub fn record_event(&self, source: &str, kind: &str) {
+        self.events
+            .inc(format!("source=\"{}\",type=\"{}\"", source, kind));
+    }
+
+    pub fn record_repair(&self, kind: &str) {
+        self.repairs.inc(format!("kind=\"{}\"", kind));
+    }
+
+    // counted by the NcsError variant in the chain. "Other" if there is none.
+    pub fn record_error(&self, e: &anyhow::Error) {
+        let name = e
+            .chain()
+            .find_map(|c| c.downcast_ref::<NcsError>())
+            .map_or_else(|| "Other".to_string(), NcsError::name);
+        self.errors.inc(format!("variant=\"{}\"", name));
+    }
+
+    pub fn record_poll(&self, latency: Duration) {
+        let micros = latency.as_micros() as u64;
+        self.poll_count.fetch_add(1, Ordering::Relaxed);
+        self.poll_micros_sum.fetch_add(micros, Ordering::Relaxed);
+        self.poll_micros_last.store(micros, Ordering::Relaxed);
+    }
+
+    pub fn set_queues(&self, offline_local_events: usize, held_deletes: usize, failures: usize) {
+        self.offline_local_events
+            .store(offline_local_events as u64, Ordering::Relaxed);
+        self.held_deletes
+            .store(held_deletes as u64, Ordering::Relaxed);
+        self.failures.store(failures as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_network(&self, status: &NetworkStatus) {
+        let i = match status {
+            NetworkStatus::Connect => 0,
+            NetworkStatus::Maintenance => 1,
+            NetworkStatus::Disconnect => 2,
+            NetworkStatus::Err(_) => 3,
+        };
+        self.network_state.store(i, Ordering::Relaxed);
+    }
+
+    // Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "ncs_uploaded_bytes_total",
+                "Bytes uploaded to the server.",
+                &self.bytes_uploaded,
+            ),
+            (
+                "ncs_downloaded_bytes_total",
+                "Bytes downloaded from the server.",
+                &self.bytes_downloaded,
+            ),
+            (
+                "ncs_uploaded_files_total",
+                "Files uploaded to the server.",
+                &self.files_uploaded,
+            ),
+            (
+                "ncs_downloaded_files_total",
+                "Files downloaded from the server.",
+                &self.files_downloaded,
+            ),
+        ];
+        for (name, help, v) in counters.iter() {
+            header(&mut out, name, help, "counter");
+            let _ = writeln!(out, "{} {}", name, v.load(Ordering::Relaxed));
+        }
+
+        self.events
+            .render(&mut out, "ncs_events_total", "Events processed.");
+        self.repairs
+            .render(&mut out, "ncs_repairs_total", "Repairs run.");
+        self.errors
+            .render(&mut out, "ncs_errors_total", "Errors by NcsError variant.");
+
+        let gauges = [
+            (
+                "ncs_offline_local_events",
+                "Local events queued while offline.",
+                &self.offline_local_events,
+            ),
+            (
+                "ncs_held_deletes",
+                "Local deletions held by the mass deletion guard.",
+                &self.held_deletes,
+            ),
+            (
+                "ncs_failures",
+                "Failed operations waiting for a retry or flagged.",
+                &self.failures,
+            ),
+        ];
+        for (name, help, v) in gauges.iter() {
+            header(&mut out, name, help, "gauge");
+            let _ = writeln!(out, "{} {}", name, v.load(Ordering::Relaxed));
+        }
+
+        header(
+            &mut out,
+            "ncs_network_state",
+            "1 for the current network state.",
+            "gauge",
+        );
+        let current = self.network_state.load(Ordering::Relaxed) as usize;
+        for (i, state) in NETWORK_STATES.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "ncs_network_state{{state=\"{}\"}} {}",
+                state,
+                (i == current) as u8
+            );
+        }
+
+        let secs = |micros: &AtomicU64| micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        header(
+            &mut out,
+            "ncs_poll_latency_seconds",
+            "Latency of polling the activities.",
+            "summary",
+        );
+        let _ = writeln!(
+            out,
+            "ncs_poll_latency_seconds_sum {}",
+            secs(&self.poll_micros_sum)
+        );
+        let _ = writeln!(
+            out,
+            "ncs_poll_latency_seconds_count {}",
+            self.poll_count.load(Ordering::Relaxed)
+        );
+        header(
+            &mut out,
+            "ncs_poll_latency_last_seconds",
+            "Latency of the last poll.",
+            "gauge",
+        );
+        let _ = writeln!(
+            out,
+            "ncs_poll_latency_last_seconds {}",
+            secs(&self.poll_micros_last)
+        );
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, type_: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, type_);
+}
+
+// A minimal HTTP server. Only `GET /metrics` is answered.
+pub async fn serve(addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("metrics: http://{}/metrics", addr);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(stream).await {
+                debug!("metrics: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn serve_connection(mut stream: TcpStream) -> Result<()> {
+    let mut buf = vec![0; 1024];
+    let n = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let target = request.split_whitespace().take(2).collect::<Vec<_>>();
+
+    let (status, body) = match target.as_slice() {
+        ["GET", "/metrics"] => ("200 OK", METRICS.render()),
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+    let res = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(res.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_test() {
+        let metrics = Metrics::default();
+        metrics.record_upload(10);
+        metrics.record_upload(5);
+        metrics.record_event("local", "create");
+        metrics.record_error(&anyhow::Error::from(NcsError::ServerError(502)).context("put"));
+        metrics.record_error(&anyhow!("something"));
+        metrics.set_network(&NetworkStatus::Disconnect);
+
+        let text = metrics.render();
+        assert!(text.contains("ncs_uploaded_bytes_total 15\n"));
+        assert!(text.contains("ncs_uploaded_files_total 2\n"));
+        assert!(text.contains("ncs_events_total{source=\"local\",type=\"create\"} 1\n"));
+        assert!(text.contains("ncs_errors_total{variant=\"ServerError\"} 1\n"));
+        assert!(text.contains("ncs_errors_total{variant=\"Other\"} 1\n"));
+        assert!(text.contains("ncs_network_state{state=\"disconnect\"} 1\n"));
+        assert!(text.contains("ncs_network_state{state=\"connect\"} 0\n"));
+    }
+}
@@ -0,0 +1,58 @@
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+// Modification times are kept in unix seconds. It's what `X-OC-MTime` takes
+// and what `getlastmodified` can tell.
+
+pub const MTIME_HEADER: &str = "X-OC-MTime";
+
+// `getlastmodified` of PROPFIND and Last-Modified of GET. ex) "Wed, 21 Oct 2015 07:28:00 GMT"
+pub fn parse_http_date(v: &str) -> Option<i64> {
+    chrono::DateTime::parse_from_rfc2822(v.trim())
+        .ok()
+        .map(|d| d.timestamp())
+}
+
+pub fn local_mtime<P: AsRef<Path>>(path: P) -> Option<i64> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    let secs = match modified.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    };
+    Some(secs)
+}
+
+pub fn set_local_mtime<P: AsRef<Path>>(path: P, mtime: i64) -> io::Result<()> {
+    let time = if mtime >= 0 {
+        UNIX_EPOCH + Duration::from_secs(mtime as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(mtime.unsigned_abs())
+    };
+    let file = fs::OpenOptions::new().write(true).open(path)?;
+    file.set_modified(time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mtime_test() {
+        assert_eq!(
+            parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(1445412480)
+        );
+        assert_eq!(parse_http_date("yesterday"), None);
+
+        let path = std::env::temp_dir().join(format!("ncs_mtime_test_{}", std::process::id()));
+        fs::write(&path, b"x").unwrap();
+        set_local_mtime(&path, 1445412480).unwrap();
+        assert_eq!(local_mtime(&path), Some(1445412480));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(local_mtime(&path), None);
+    }
+}
@@ -0,0 +1,397 @@
+use crate::errors::NcsError::*;
+use crate::*;
+use anyhow::Result;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use unicode_normalization::UnicodeNormalization;
+use urlencoding::decode;
+
+// the tail of an escaped name on the server. ex) ".htaccess" -> "%2Ehtaccess.ncs-escaped"
+const ESCAPED_SUFFIX: &str = ".ncs-escaped";
+
+// How names are mapped between the server and the local.
+// The tree is keyed by the local names. An entry whose name on the server differs
+// keeps it as its remote name, so every request to the server goes by that.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Normalization {
+    None,
+    // macOS clients upload names in NFD. They are NFC on the local.
+    Nfc,
+}
+
+// what is done with a local name the server rejects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForbiddenAction {
+    // not uploaded. It stays in the failures until it's renamed.
+    Skip,
+    // uploaded under an escaped name, which is turned back on the way down.
+    Escape,
+}
+
+// The forbidden filename rules of the server. (files capabilities)
+// Names, basenames and extensions are compared case-insensitively.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForbiddenNames {
+    pub names: Vec<String>,
+    // the part before the first dot. ex) "con" of "con.txt"
+    pub basenames: Vec<String>,
+    pub characters: Vec<String>,
+    // ex) ".part"
+    pub extensions: Vec<String>,
+}
+
+impl Default for ForbiddenNames {
+    // the default of Nextcloud, for the servers which don't tell.
+    fn default() -> Self {
+        Self {
+            names: vec![".htaccess".to_string()],
+            basenames: Vec::new(),
+            characters: Vec::new(),
+            extensions: Vec::new(),
+        }
+    }
+}
+
+impl ForbiddenNames {
+    // why the server rejects `name`. None if it doesn't.
+    pub fn check(&self, name: &str) -> Option<String> {
+        let lower = name.to_lowercase();
+        if self.names.iter().any(|n| n.to_lowercase() == lower) {
+            return Some(format!("{:?} is a forbidden name", name));
+        }
+        // a leading dot doesn't start the extension.
+        let start = lower.chars().next().map_or(0, char::len_utf8);
+        let basename = match lower[start..].find('.') {
+            Some(i) => &lower[..start + i],
+            None => lower.as_str(),
+        };
+        if self.basenames.iter().any(|b| b.to_lowercase() == basename) {
+            return Some(format!("{:?} is a forbidden basename", basename));
+        }
+        if let Some(c) = self
+            .characters
+            .iter()
+            .find(|c| !c.is_empty() && name.contains(c.as_str()))
+        {
+            return Some(format!("{:?} is a forbidden character", c));
+        }
+        if let Some(e) = self
+            .extensions
+            .iter()
+            .find(|e| lower.ends_with(&e.to_lowercase()))
+        {
+            return Some(format!("{:?} is a forbidden extension", e));
+        }
+        if name.ends_with(char::is_whitespace) {
+            return Some("names must not end with a space".to_string());
+        }
+
+        None
+    }
+
+    // Every forbidden character, "%" and the trailing spaces are percent-encoded.
+    // A forbidden name or basename gets its first character encoded too.
+    // Only names with the suffix are unescaped, so the others may have "%" as it is.
+    pub fn escape(&self, name: &str) -> String {
+        let keep_len = name.trim_end().len();
+        let mut escaped = String::new();
+        for (i, c) in name.char_indices() {
+            let forbidden =
+                c == '%' || i >= keep_len || self.characters.iter().any(|f| f.contains(c));
+            if forbidden {
+                escaped.push_str(&percent_encode(c));
+            } else {
+                escaped.push(c);
+            }
+        }
+        if self.check(&escaped).is_some() {
+            if let Some(c) = escaped.chars().next().filter(|c| *c != '%') {
+                escaped = format!("{}{}", percent_encode(c), &escaped[c.len_utf8()..]);
+            }
+        }
+
+        format!("{}{}", escaped, ESCAPED_SUFFIX)
+    }
+
+    pub fn unescape(name: &str) -> Option<String> {
+        let escaped = name.strip_suffix(ESCAPED_SUFFIX)?;
+        decode(escaped).ok().map(|s| s.to_string())
+    }
+}
+
+fn percent_encode(c: char) -> String {
+    let mut buf = [0; 4];
+    c.encode_utf8(&mut buf)
+        .bytes()
+        .map(|b| format!("%{:02X}", b))
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamePolicy {
+    pub normalization: Normalization,
+    // the local volume can't hold names differing only in case within a directory.
+    pub case_insensitive: bool,
+    pub forbidden: ForbiddenNames,
+    pub on_forbidden: ForbiddenAction,
+}
+
+impl Default for NamePolicy {
+    fn default() -> Self {
+        Self {
+            normalization: Normalization::None,
+            case_insensitive: false,
+            forbidden: ForbiddenNames::default(),
+            on_forbidden: ForbiddenAction::Skip,
+        }
+    }
+}
+
+impl fmt::Display for NamePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let normalization = match self.normalization {
+            Normalization::None => "none",
+            Normalization::Nfc => "nfc",
+        };
+        let on_forbidden = match self.on_forbidden {
+            ForbiddenAction::Skip => "skip",
+            ForbiddenAction::Escape => "escape",
+        };
+        write!(
+            f,
+            "normalization: {}, case collisions: {}, forbidden names: {}",
+            normalization,
+            if self.case_insensitive {
+                "rename"
+            } else {
+                "off"
+            },
+            on_forbidden
+        )
+    }
+}
+
+impl NamePolicy {
+    pub fn normalize(&self, name: &str) -> String {
+        match self.normalization {
+            Normalization::None => name.to_string(),
+            Normalization::Nfc => name.nfc().collect(),
+        }
+    }
+
+    // names of the same key are the same file on the local volume.
+    fn key(&self, name: &str) -> String {
+        let name = self.normalize(name);
+        if self.case_insensitive {
+            name.to_lowercase()
+        } else {
+            name
+        }
+    }
+
+    // the local name of a new entry `remote_name` under `parent`.
+    // It's renamed if it collides with another entry of the directory.
+    // `moving` is the entry being renamed, which doesn't collide with itself.
+    pub fn local_name(
+        &self,
+        tree: &Tree,
+        parent: Option<EntryId>,
+        remote_name: &str,
+        moving: Option<EntryId>,
+    ) -> String {
+        let unescaped = match self.on_forbidden {
+            ForbiddenAction::Escape => ForbiddenNames::unescape(remote_name),
+            ForbiddenAction::Skip => None,
+        };
+        let local_name = self.normalize(unescaped.as_deref().unwrap_or(remote_name));
+        let parent = match parent {
+            Some(p) => p,
+            None => return local_name,
+        };
+
+        let key = self.key(&local_name);
+        let collides = tree
+            .children(parent)
+            .into_iter()
+            .filter(|c| Some(*c) != moving)
+            .filter_map(|c| tree.entry(c))
+            .any(|e| self.key(&e.get_raw_name()) == key && e.get_remote_raw_name() != remote_name);
+        if collides {
+            let renamed = collision_name(&local_name, remote_name);
+            warn!(
+                "{:?} collides with another name in the directory. It's {:?} on the local.",
+                remote_name, renamed
+            );
+            renamed
+        } else {
+            local_name
+        }
+    }
+
+    pub fn remote_name(&self, local_name: &str) -> String {
+        let remote_name = self.normalize(local_name);
+        match self.on_forbidden {
+            ForbiddenAction::Escape if self.forbidden.check(&remote_name).is_some() => {
+                self.forbidden.escape(&remote_name)
+            }
+            _ => remote_name,
+        }
+    }
+
+    // called before a name goes up to the server. Err if the server would reject it.
+    pub fn check_upload(&self, remote_name: &str) -> Result<()> {
+        match self.forbidden.check(remote_name) {
+            Some(reason) => Err(ForbiddenNameError(format!("{}.", reason)).into()),
+            None => Ok(()),
+        }
+    }
+
+    // the local path of `remote_path`. Known entries keep their names,
+    // the rest are named by the policy.
+    pub fn local_path(&self, tree: &Tree, remote_path: &str, moving: Option<EntryId>) -> String {
+        let mut cur = Some(Tree::ROOT);
+        let mut local_path = String::new();
+        for name in remote_path.split('/').filter(|s| !s.is_empty()) {
+            let next = cur.and_then(|p| tree.child_by_remote_name(p, name));
+            let local_name = match next.and_then(|c| tree.entry(c)) {
+                Some(e) => e.get_raw_name(),
+                None => self.local_name(tree, cur, name, moving),
+            };
+            local_path.push('/');
+            local_path.push_str(&local_name);
+            cur = next;
+        }
+
+        Tree::normalize_path(&local_path)
+    }
+
+    pub fn remote_path(&self, tree: &Tree, local_path: &str) -> String {
+        let mut cur = Some(Tree::ROOT);
+        let mut remote_path = String::new();
+        for name in local_path.split('/').filter(|s| !s.is_empty()) {
+            let next = cur
+                .and_then(|p| tree.entry(p))
+                .and_then(|e| e.get_child(name));
+            let remote_name = match next.and_then(|c| tree.entry(c)) {
+                Some(e) => e.get_remote_raw_name(),
+                None => self.remote_name(name),
+            };
+            remote_path.push('/');
+            remote_path.push_str(&remote_name);
+            cur = next;
+        }
+
+        Tree::normalize_path(&remote_path)
+    }
+
+    // records the remote names of the entries on `local_path`. Used after an insert or a move.
+    pub fn set_remote_names(&self, tree: &mut Tree, local_path: &str, remote_path: &str) {
+        let local_names = local_path.split('/').filter(|s| !s.is_empty());
+        let remote_names = remote_path.split('/').filter(|s| !s.is_empty());
+        let mut cur = Tree::ROOT;
+        for (local_name, remote_name) in local_names.zip(remote_names) {
+            cur = match tree.entry(cur).and_then(|e| e.get_child(local_name)) {
+                Some(c) => c,
+                None => return,
+            };
+            tree.entry_mut(cur).unwrap().set_remote_name(remote_name);
+        }
+    }
+}
+
+// the same name every time for the same remote name. ex) "a (conflict 1a2b3c4d).txt"
+pub fn collision_name(local_name: &str, remote_name: &str) -> String {
+    let hash = format!("{:x}", Sha256::digest(remote_name.as_bytes()));
+    let tag = format!("conflict {}", &hash[..8]);
+    match local_name.rfind('.') {
+        Some(i) if i > 0 => format!("{} ({}){}", &local_name[..i], tag, &local_name[i..]),
+        _ => format!("{} ({})", local_name, tag),
+    }
+}
+
+// writes a probe file into `dir` and looks for it in upper case.
+pub fn is_case_insensitive<P: AsRef<Path>>(dir: P) -> Result<bool> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+    let probe = dir.join(".case-probe");
+    fs::write(&probe, b"")?;
+    let res = dir.join(".CASE-PROBE").exists();
+    fs::remove_file(&probe)?;
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_policy_test() {
+        let policy = NamePolicy {
+            normalization: Normalization::Nfc,
+            case_insensitive: true,
+            ..Default::default()
+        };
+        let mut tree = Tree::new();
+        let nfd = "cafe\u{301}.txt";
+        let nfc = "caf\u{e9}.txt";
+        let entry = Entry::new(nfd.to_string(), EntryType::File { etag: None });
+        let local_path = policy.local_path(&tree, &format!("/a/{}", nfd), None);
+        assert_eq!(local_path, format!("/a/{}", nfc));
+        tree.insert(&local_path, entry.with_local_name(nfc))
+            .unwrap();
+        assert_eq!(
+            policy.remote_path(&tree, &local_path),
+            format!("/a/{}", nfd)
+        );
+        assert_eq!(
+            policy.local_path(&tree, &format!("/a/{}", nfd), None),
+            local_path
+        );
+
+        // the same name in NFC and in upper case on the server.
+        let renamed = policy.local_path(&tree, &format!("/a/{}", nfc), None);
+        assert_eq!(renamed, format!("/a/{}", collision_name(nfc, nfc)));
+        assert_ne!(
+            policy.local_path(&tree, "/a/CAF\u{c9}.txt", None),
+            "/a/CAF\u{c9}.txt"
+        );
+        assert!(collision_name("a.txt", "A.txt").starts_with("a (conflict "));
+        assert!(collision_name(".bashrc", "x").starts_with(".bashrc (conflict "));
+    }
+
+    #[test]
+    fn forbidden_names_test() {
+        let forbidden = ForbiddenNames {
+            names: vec![".htaccess".to_string()],
+            basenames: vec!["con".to_string()],
+            characters: vec![":".to_string()],
+            extensions: vec![".part".to_string()],
+        };
+        for name in [".htaccess", "CON.txt", "a:b", "a.PART", "a "] {
+            assert!(forbidden.check(name).is_some(), "{}", name);
+            let escaped = forbidden.escape(name);
+            assert!(forbidden.check(&escaped).is_none(), "{}", escaped);
+            assert_eq!(ForbiddenNames::unescape(&escaped).unwrap(), name);
+        }
+        assert!(forbidden.check("console.txt").is_none());
+        assert!(forbidden.check("100%.txt").is_none());
+        assert!(ForbiddenNames::unescape("100%.txt").is_none());
+
+        let policy = NamePolicy {
+            forbidden,
+            on_forbidden: ForbiddenAction::Escape,
+            ..Default::default()
+        };
+        let remote_name = policy.remote_name("a:b");
+        assert!(policy.check_upload(&remote_name).is_ok());
+        assert_eq!(
+            policy.local_name(&Tree::new(), None, &remote_name, None),
+            "a:b"
+        );
+    }
+}
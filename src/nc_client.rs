@@ -0,0 +1,394 @@
+use crate::errors::NcsError;
+use crate::meta::NCInfo;
+use crate::throttle::{LimitKind, Throttle};
+use anyhow::Result;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use reqwest::{Body, Method, RequestBuilder, Response, StatusCode, Url};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::{sleep, Duration};
+
+pub const USER_AGENT: &str = concat!("ncs/", env!("CARGO_PKG_VERSION"));
+// an upload body is read in pieces of this size. Each one waits for the upload limit.
+const UPLOAD_PIECE: usize = 64 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    // a longer Retry-After is not waited for. The response is returned as it is.
+    pub max_retry_after: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 4,
+            base_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(30),
+            max_retry_after: Duration::from_secs(300),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // exponential backoff with "equal jitter": a random delay in [delay/2, delay].
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .checked_mul(1u32 << attempt.min(16))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        let half = exp / 2;
+        let jitter_range = (exp - half).as_millis() as u64;
+        let jitter = if jitter_range == 0 {
+            0
+        } else {
+            random_u64() % (jitter_range + 1)
+        };
+        half + Duration::from_millis(jitter)
+    }
+
+    // the server's Retry-After as it is, or the backoff if it sent none.
+    // None if it asks to wait too long.
+    fn delay(&self, retry_after: Option<Duration>, attempt: u32) -> Option<Duration> {
+        match retry_after {
+            Some(d) if d > self.max_retry_after => None,
+            Some(d) => Some(d),
+            None => Some(self.backoff(attempt)),
+        }
+    }
+}
+
+fn random_u64() -> u64 {
+    // RandomState is seeded randomly per instance, which is enough for jitter.
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    hasher.finish()
+}
+
+// Every request to Nextcloud goes through this client.
+// It owns url construction, auth, user agent, timeouts and retries.
+#[derive(Clone)]
+pub struct NcClient {
+    client: reqwest::Client,
+    host: String,
+    dav_root: String,
+    username: String,
+    password: String,
+    timeout: Duration,
+    retry_policy: RetryPolicy,
+    throttle: Throttle,
+}
+
+impl NcClient {
+    const TIMEOUT_DEFAULT: Duration = Duration::from_secs(60);
+
+    pub fn new(nc_info: &NCInfo, client: reqwest::Client) -> Self {
+        Self {
+            client,
+            host: nc_info.host.clone(),
+            dav_root: nc_info.root_path.clone(),
+            username: nc_info.username.clone(),
+            password: nc_info.password.clone(),
+            timeout: Self::TIMEOUT_DEFAULT,
+            retry_policy: RetryPolicy::default(),
+            throttle: Throttle::default(),
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        debug!("set request timeout to {:?}", timeout);
+        self.timeout = timeout;
+    }
+
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        debug!("set retry policy to {:?}", retry_policy);
+        self.retry_policy = retry_policy;
+    }
+
+    pub fn set_dav_root(&mut self, dav_root: &str) {
+        self.dav_root = dav_root.to_string();
+    }
+
+    pub fn set_throttle(&mut self, throttle: Throttle) {
+        self.throttle = throttle;
+    }
+
+    pub fn throttle(&self) -> &Throttle {
+        &self.throttle
+    }
+
+    // The limit holds within one transfer too, not only between them.
+    // A streamed body can't be cloned, so it's not retried by `send`.
+    // It has no length of its own. Without Content-Length it goes out chunked,
+    // which some servers (nginx with php-fpm) save as an empty file.
+    pub fn upload_body<R>(&self, reader: R) -> Body
+    where
+        R: AsyncRead + Send + Sync + Unpin + 'static,
+    {
+        let state = Some((reader, self.throttle.clone()));
+        let stream = futures_util::stream::unfold(state, |state| async move {
+            let (mut reader, throttle) = state?;
+            let mut buf = vec![0u8; UPLOAD_PIECE];
+            match reader.read(&mut buf).await {
+                Ok(0) => None,
+                Ok(n) => {
+                    buf.truncate(n);
+                    throttle.acquire(LimitKind::Upload, n as u64).await;
+                    Some((Ok(buf), Some((reader, throttle))))
+                }
+                Err(e) => Some((Err::<Vec<u8>, io::Error>(e), None)),
+            }
+        });
+        Body::wrap_stream(stream)
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    // `path` is relative to the host, e.g. "/ocs/v2.php/cloud/capabilities".
+    pub fn url(&self, path: &str) -> Result<Url> {
+        let mut url = Url::parse(&self.host)?;
+        {
+            let mut segments = url
+                .path_segments_mut()
+                .map_err(|_| NcsError::InvalidPathError(self.host.clone()))?;
+            segments
+                .pop_if_empty()
+                .extend(path.split('/').filter(|s| !s.is_empty()));
+        }
+        Ok(url)
+    }
+
+    // `path` is relative to the user's dav root, e.g. "/dir/file.txt".
+    pub fn dav_url(&self, path: &str) -> Result<Url> {
+        self.url(&format!("{}{}", self.dav_root, path))
+    }
+
+    pub fn request(&self, method: Method, url: Url) -> RequestBuilder {
+        self.client
+            .request(method, url)
+            .basic_auth(&self.username, Some(&self.password))
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .timeout(self.timeout)
+    }
+
+    pub fn dav_request(&self, method: Method, path: &str) -> Result<RequestBuilder> {
+        Ok(self.request(method, self.dav_url(path)?))
+    }
+
+    pub fn ocs_request(&self, method: Method, path: &str) -> Result<RequestBuilder> {
+        Ok(self
+            .request(method, self.url(path)?)
+            .header("OCS-APIRequest", "true"))
+    }
+
+    // Sends with retries and maps unsuccessful status to NcsError.
+    pub async fn send(&self, req: RequestBuilder) -> Result<Response> {
+        let res = self.send_raw(req).await?;
+        let status = res.status();
+        if status.is_success() {
+            Ok(res)
+        } else {
+            Err(NcsError::from_status(status).into())
+        }
+    }
+
+    // Sends with retries. The final response is returned whatever its status is.
+    // MOVE, DELETE and POST may have been done even if no response came back,
+    // so they are only retried when the connection couldn't be made.
+    pub async fn send_raw(&self, mut req: RequestBuilder) -> Result<Response> {
+        let idempotent = req
+            .try_clone()
+            .and_then(|r| r.build().ok())
+            .is_none_or(|r| is_idempotent(r.method()));
+        let mut attempt = 0;
+        loop {
+            let has_next = attempt < self.retry_policy.max_retries;
+            let (current, next) = match req.try_clone() {
+                Some(r) if has_next => (r, Some(req)),
+                _ => (req, None),
+            };
+
+            self.throttle.acquire(LimitKind::Requests, 1).await;
+            let res = current.send().await;
+            let next = match next {
+                Some(n) => n,
+                None => return Ok(res?),
+            };
+
+            let delay = match res {
+                Ok(res) if idempotent && is_retryable_status(res.status()) => {
+                    let delay = match self.retry_policy.delay(retry_after(&res), attempt) {
+                        Some(d) => d,
+                        None => return Ok(res),
+                    };
+                    warn!(
+                        "{} {} : status {}. retry after {:?}.",
+                        res.url().path(),
+                        attempt,
+                        res.status(),
+                        delay
+                    );
+                    delay
+                }
+                Ok(res) => return Ok(res),
+                Err(e) if e.is_connect() || (idempotent && e.is_timeout()) => {
+                    let delay = self.retry_policy.backoff(attempt);
+                    warn!("{:?}\nretry after {:?}.", e, delay);
+                    delay
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            sleep(delay).await;
+            attempt += 1;
+            req = next;
+        }
+    }
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    !matches!(method.as_str(), "MOVE" | "DELETE" | "POST")
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+// Retry-After is only honored on 429 and 503.
+fn retry_after(res: &Response) -> Option<Duration> {
+    if !matches!(
+        res.status(),
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    ) {
+        return None;
+    }
+
+    let v = res
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    parse_retry_after(v, chrono::Utc::now())
+}
+
+fn parse_retry_after(v: &str, now: chrono::DateTime<chrono::Utc>) -> Option<Duration> {
+    if let Ok(secs) = v.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = chrono::DateTime::parse_from_rfc2822(v).ok()?;
+    let secs = (date.with_timezone(&chrono::Utc) - now)
+        .num_seconds()
+        .max(0);
+    Some(Duration::from_secs(secs as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_after_test() {
+        let now = chrono::DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:29:00 GMT", now),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::from_secs(0))
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+
+        let policy = RetryPolicy::default();
+        assert_eq!(
+            policy.delay(Some(Duration::from_secs(120)), 0),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(policy.delay(Some(Duration::from_secs(3600)), 0), None);
+        assert!(policy.delay(None, 10).unwrap() <= policy.max_delay);
+        assert!(!is_idempotent(&Method::from_bytes(b"MOVE").unwrap()));
+        assert!(is_idempotent(&Method::PUT));
+    }
+
+    #[test]
+    fn url_test() {
+        let nc_info = NCInfo::new(
+            "user".to_string(),
+            "pass".to_string(),
+            "https://example.com/nextcloud/".to_string(),
+        );
+        let client = NcClient::new(&nc_info, reqwest::Client::new());
+
+        assert_eq!(
+            client.dav_url("/dir/a b.txt").unwrap().as_str(),
+            "https://example.com/nextcloud/remote.php/dav/files/user/dir/a%20b.txt"
+        );
+        assert_eq!(
+            client.url("/status.php").unwrap().as_str(),
+            "https://example.com/nextcloud/status.php"
+        );
+    }
+
+    #[tokio::test]
+    async fn upload_body_length_test() {
+        use tokio::io::AsyncWriteExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut req = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !req.ends_with(b"hello") {
+                let n = stream.read(&mut buf).await.unwrap();
+                req.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 201 Created\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(req).unwrap().to_lowercase()
+        });
+
+        let nc_info = NCInfo::new(
+            "user".to_string(),
+            "pass".to_string(),
+            format!("http://{}/", addr),
+        );
+        let client = NcClient::new(&nc_info, reqwest::Client::new());
+        let req = client
+            .dav_request(Method::PUT, "/a.txt")
+            .unwrap()
+            .header(reqwest::header::CONTENT_LENGTH, 5)
+            .body(client.upload_body(&b"hello"[..]));
+        assert!(client.send(req).await.unwrap().status().is_success());
+
+        let head = server.await.unwrap();
+        assert!(head.contains("content-length: 5"));
+        assert!(!head.contains("transfer-encoding"));
+    }
+}
@@ -1,18 +1,26 @@
-use crate::errors::NcsError::*;
+use crate::attrs;
+use crate::errors::NcsError::{self, *};
+use crate::failures::FailedOp;
+use crate::history::{entry_etag, tree_etag, HistoryRecord, Source};
+use crate::logging::sync_event;
 use crate::meta::*;
+use crate::metrics::METRICS;
 use crate::repair::ModifiedPath;
+use crate::stash::{StashCause, StashReason, DELETE_CAUSE};
+use crate::throttle::LimitKind;
 use crate::*;
 use anyhow::{Context, Result};
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
 use once_cell::sync::Lazy;
 use regex::Regex;
-use reqwest::{Method, Url};
+use reqwest::{Method, StatusCode};
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::hash::Hash;
-use std::io;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{fs, io};
+use tokio::io::AsyncWriteExt;
 use tokio::sync::mpsc;
 use tokio::time::{sleep, Duration};
 use urlencoding::decode;
@@ -22,6 +30,7 @@ pub const WEBDAV_BODY: &str = r#"<?xml version="1.0"?>
   <d:prop>
         <d:getetag />
         <d:getcontenttype />
+        <d:getlastmodified />
   </d:prop>
 </d:propfind>
 "#;
@@ -94,45 +103,43 @@ pub async fn get_etag_from_nc(
     }
 }
 
-pub async fn from_nc_all(
-    nc_info: &NCInfo,
-    local_info: &LocalInfo,
-    target: &str,
-) -> Result<ArcEntry> {
+// The root of the result is the target.
+pub async fn from_nc_all(nc_info: &NCInfo, local_info: &LocalInfo, target: &str) -> Result<Tree> {
     let target = add_head_slash(&target);
     let top_entry = from_nc(nc_info, local_info, &target).await?;
-    let top_entry = Arc::new(Mutex::new(top_entry));
+    let mut tree = Tree::with_root(top_entry);
 
-    get_children_rec(nc_info, local_info, &top_entry, "").await?;
+    get_children_rec(nc_info, local_info, &mut tree, Tree::ROOT, "").await?;
 
-    Ok(top_entry)
+    Ok(tree)
 }
 
 pub async fn from_nc_all_in_the_middle(
     nc_info: &NCInfo,
     local_info: &LocalInfo,
     target: &str,
-) -> Result<ArcEntry> {
+) -> Result<Tree> {
     let target = add_head_slash(&target);
     let top_entry = from_nc(nc_info, local_info, &target).await?;
-    let top_entry = Arc::new(Mutex::new(top_entry));
+    let mut tree = Tree::with_root(top_entry);
 
-    const RE_REMOVE_CHILDPART: Lazy<Regex> = Lazy::new(|| Regex::new("^(.*/)[^/]+$").unwrap());
+    static RE_REMOVE_CHILDPART: Lazy<Regex> = Lazy::new(|| Regex::new("^(.*/)[^/]+$").unwrap());
     let p_str: &str = &RE_REMOVE_CHILDPART.replace(&target, "$1");
 
-    get_children_rec(nc_info, local_info, &top_entry, p_str).await?;
+    get_children_rec(nc_info, local_info, &mut tree, Tree::ROOT, p_str).await?;
 
-    Ok(top_entry)
+    Ok(tree)
 }
 
 #[async_recursion]
 async fn get_children_rec(
     nc_info: &NCInfo,
     local_info: &LocalInfo,
-    parent_entry: &ArcEntry,
+    tree: &mut Tree,
+    parent: EntryId,
     ancestor_path: &str,
 ) -> Result<()> {
-    let parent_name = parent_entry.lock().map_err(|_| LockError)?.get_name();
+    let parent_name = tree.entry(parent).unwrap().get_remote_name();
     let ancestor_path = format!("{}{}", ancestor_path, parent_name);
     let children_entries = comm_nc(nc_info, local_info, &ancestor_path)
         .await?
@@ -140,67 +147,46 @@ async fn get_children_rec(
         .filter(|c| c.get_name().as_str() != &parent_name);
 
     for c in children_entries {
-        let c = Arc::new(Mutex::new(c));
-        get_children_rec(nc_info, local_info, &c, &ancestor_path).await?;
-        Entry::append_child(parent_entry, c)?;
+        let local_name = local_info
+            .names
+            .local_name(tree, Some(parent), &c.get_raw_name(), None);
+        let c = tree.add_child(parent, c.with_local_name(&local_name))?;
+        get_children_rec(nc_info, local_info, tree, c, &ancestor_path).await?;
     }
 
     Ok(())
 }
 
 async fn comm_nc(nc_info: &NCInfo, local_info: &LocalInfo, target: &str) -> Result<Vec<Entry>> {
-    let target = add_head_slash(target);
-    let target = drop_slash(&target, &RE_HAS_LAST_SLASH);
-
-    let path = format!("{}{}", &nc_info.root_path, target)
-        .split("/")
-        .map(|v| v.to_string())
-        .collect::<Vec<String>>();
-
-    let mut url = Url::parse(&nc_info.host)?;
-    url.path_segments_mut().unwrap().extend(path);
-
-    // let client = Client::builder().https_only(true).build()?;
-    let ref client = local_info.req_client;
-
-    let mut res_w = Err(anyhow!("dummy error"));
-    for _ in 0u8..3 {
-        let res = client
-            .request(Method::from_bytes(b"PROPFIND").unwrap(), url.as_str())
-            .basic_auth(&nc_info.username, Some(&nc_info.password))
-            .header("Depth", "Infinity")
-            .body(WEBDAV_BODY)
-            .send()
-            .await?;
-
-        res_w = if res.status().is_success() {
-            Ok(res)
-        } else {
-            Err(BadStatusError(res.status().as_u16()).into())
-        };
-        if res_w.is_ok() {
-            break;
-        }
-        sleep(Duration::from_millis(100)).await;
-    }
-    let res = match res_w {
-        Ok(r) => r,
-        Err(e) => return Err(e),
-    };
-
-    let text = res.text_with_charset("utf-8").await?;
+    let text = propfind(local_info, target).await?;
 
     let document: roxmltree::Document = roxmltree::Document::parse(&text)?;
     let responses = webdav_xml2responses(&document, &nc_info.root_path);
     let responses = responses
         .into_iter()
-        .filter(|e| local_info.exc_checker.judge(e.get_raw_name()))
+        .filter(|(path, e)| local_info.exc_checker.judge_dir(path, e.type_.is_dir()))
+        .map(|(_, e)| e)
         .collect();
 
     Ok(responses)
 }
 
-fn webdav_xml2responses(document: &roxmltree::Document, root_path: &str) -> Vec<Entry> {
+async fn propfind(local_info: &LocalInfo, target: &str) -> Result<String> {
+    let target = add_head_slash(target);
+    let target = drop_slash(&target, &RE_HAS_LAST_SLASH);
+
+    let client = &local_info.nc_client;
+    let req = client
+        .dav_request(Method::from_bytes(b"PROPFIND").unwrap(), &target)?
+        .header("Depth", "Infinity")
+        .body(WEBDAV_BODY);
+    let res = client.send(req).await?;
+
+    Ok(res.text_with_charset("utf-8").await?)
+}
+
+// (path from the root, entry)
+fn webdav_xml2responses(document: &roxmltree::Document, root_path: &str) -> Vec<(String, Entry)> {
     document
         .root_element()
         .children()
@@ -209,9 +195,11 @@ fn webdav_xml2responses(document: &roxmltree::Document, root_path: &str) -> Vec<
                 return None;
             }
 
+            let mut path_w = None;
             let mut name_w = None;
             let mut etag_w = None;
             let mut type_w = None;
+            let mut mtime_w = None;
 
             for m in n.children() {
                 match m.tag_name().name() {
@@ -220,6 +208,7 @@ fn webdav_xml2responses(document: &roxmltree::Document, root_path: &str) -> Vec<
                             let path = href.replace(&root_path, "");
                             let path = decode(&path).ok()?;
                             name_w = Some(path2name(&path));
+                            path_w = Some(path.to_string());
                         }
                     }
                     "propstat" => {
@@ -228,6 +217,9 @@ fn webdav_xml2responses(document: &roxmltree::Document, root_path: &str) -> Vec<
                                 "getetag" => {
                                     etag_w = d.text().and_then(|s| Some(s.replace("\"", "")));
                                 }
+                                "getlastmodified" => {
+                                    mtime_w = d.text().and_then(mtime::parse_http_date);
+                                }
                                 "getcontenttype" => {
                                     type_w = match d.text() {
                                         Some(ref s) if s != &"" => {
@@ -244,17 +236,21 @@ fn webdav_xml2responses(document: &roxmltree::Document, root_path: &str) -> Vec<
                 }
             }
             if_chain! {
+                if let Some(path) = path_w;
                 if let Some(name) = name_w;
                 if let Some(etag) = etag_w;
                 if let Some(type_) = type_w;
                 then {
-                    let type_ = if let EntryType::File {..} = type_ {
-                        EntryType::File { etag: Some(etag) }
+                    let mut entry = if let EntryType::File {..} = type_ {
+                        Entry::new(name, EntryType::File { etag: Some(etag) })
                     } else {
-                        type_
+                        Entry::new(name, type_)
                     };
+                    if entry.type_.is_file() {
+                        entry.mtime = mtime_w;
+                    }
 
-                    Some(Entry::new(name, type_))
+                    Some((path, entry))
                 } else {
                     None
                 }
@@ -313,57 +309,34 @@ async fn get_all_sub_path_rec(
     }
 }
 
+// (path from the root, is_dir) of every included remote entry. Shallower first.
+pub async fn list_remote_paths(
+    nc_info: &NCInfo,
+    local_info: &LocalInfo,
+) -> Result<Vec<(String, bool)>> {
+    let mut v = comm_nc_for_path(nc_info, local_info, "/")
+        .await?
+        .into_iter()
+        .map(|p| (drop_slash(&p.path, &RE_HAS_LAST_SLASH), p.is_dir))
+        .filter(|(p, _)| !p.is_empty() && p != "/")
+        .collect::<Vec<_>>();
+    v.sort_by_key(|(p, _)| p.len());
+
+    Ok(v)
+}
+
 async fn comm_nc_for_path(
     nc_info: &NCInfo,
     local_info: &LocalInfo,
     target: &str,
 ) -> Result<Vec<PathAndIsDir>> {
-    let target = add_head_slash(target);
-    let target = drop_slash(&target, &RE_HAS_LAST_SLASH);
-
-    let path = format!("{}{}", &nc_info.root_path, target)
-        .split("/")
-        .map(|v| v.to_string())
-        .collect::<Vec<String>>();
-
-    let mut url = Url::parse(&nc_info.host)?;
-    url.path_segments_mut().unwrap().extend(path);
-
-    // let client = Client::builder().https_only(true).build()?;
-    let ref client = local_info.req_client;
-
-    let mut res_w = Err(anyhow!("dummy error"));
-    for _ in 0u8..3 {
-        let res = client
-            .request(Method::from_bytes(b"PROPFIND").unwrap(), url.as_str())
-            .basic_auth(&nc_info.username, Some(&nc_info.password))
-            .header("Depth", "Infinity")
-            .body(WEBDAV_BODY)
-            .send()
-            .await?;
-
-        res_w = if res.status().is_success() {
-            Ok(res)
-        } else {
-            Err(BadStatusError(res.status().as_u16()).into())
-        };
-        if res_w.is_ok() {
-            break;
-        }
-        sleep(Duration::from_millis(100)).await;
-    }
-    let res = match res_w {
-        Ok(r) => r,
-        Err(e) => return Err(e),
-    };
-
-    let text = res.text_with_charset("utf-8").await?;
+    let text = propfind(local_info, target).await?;
 
     let document: roxmltree::Document = roxmltree::Document::parse(&text)?;
     let responses = webdav_xml2paths(&document, &nc_info.root_path);
     let responses = responses
         .into_iter()
-        .filter(|p| local_info.exc_checker.judge(&p.path))
+        .filter(|p| local_info.exc_checker.judge_dir(&p.path, p.is_dir))
         .collect();
 
     Ok(responses)
@@ -422,18 +395,86 @@ fn webdav_xml2paths(document: &roxmltree::Document, root_path: &str) -> Vec<Path
 
 // ==================== ↑ need refactoring ↑ ========================================
 
-fn save_file<R: io::Read + ?Sized>(
-    r: &mut R,
+fn save_link(
+    target: &Path,
+    path: &str,
+    local_info: &LocalInfo,
+    stash: bool,
+    cause: StashCause,
+) -> Result<()> {
+    let filename = format!("{}{}", local_info.root_path, path);
+    fileope::save_link(target, &filename, stash, cause, local_info)?;
+
+    Ok(())
+}
+
+fn save_file_from(
+    tmp: &Path,
     path: &str,
     local_info: &LocalInfo,
     stash: bool,
+    cause: StashCause,
 ) -> Result<()> {
     let filename = format!("{}{}", local_info.root_path, path);
-    fileope::save_file(r, &filename, stash, local_info)?;
+    fileope::save_file_from(tmp, &filename, stash, cause, local_info)?;
 
     Ok(())
 }
 
+static DOWNLOAD_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+// a fresh file in `.ncs/download` the body is written to.
+fn download_tempfile(local_info: &LocalInfo) -> Result<PathBuf> {
+    let dir = local_info.get_downloadpath_name();
+    fs::create_dir_all(&dir).map_err(|e| anyhow!("{:?} | {:?}", dir, e))?;
+    let seq = DOWNLOAD_SEQ.fetch_add(1, Ordering::Relaxed);
+
+    Ok(Path::new(&dir).join(format!("{}-{}.part", std::process::id(), seq)))
+}
+
+// writes the body to `tmp` chunk by chunk as it arrives. Returns the size.
+async fn write_body(
+    res: &mut reqwest::Response,
+    tmp: &Path,
+    local_info: &LocalInfo,
+) -> Result<usize> {
+    let mut out = tokio::fs::File::create(tmp)
+        .await
+        .map_err(|e| anyhow!("{:?} | {:?}", tmp, e))?;
+    let mut size = 0;
+    while let Some(chunk) = res.chunk().await? {
+        local_info
+            .nc_client
+            .throttle()
+            .acquire(LimitKind::Download, chunk.len() as u64)
+            .await;
+        out.write_all(&chunk)
+            .await
+            .map_err(|e| anyhow!("{:?} | {:?}", tmp, e))?;
+        size += chunk.len();
+    }
+    out.flush()
+        .await
+        .map_err(|e| anyhow!("{:?} | {:?}", tmp, e))?;
+
+    Ok(size)
+}
+
+// A failure here is only warned. The content is already saved.
+async fn restore_attrs(local_info: &LocalInfo, path: &str, filename: &str, remote_path: &str) {
+    let res = match attrs::download_attrs(&local_info.nc_client, remote_path).await {
+        Ok(Some(a)) => local_info
+            .attrs
+            .apply(path, Path::new(filename), &a)
+            .map_err(anyhow::Error::from),
+        Ok(None) => Ok(()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = res {
+        warn!("{}: can't restore the attrs. | {:?}", path, e);
+    }
+}
+
 fn create_dir_all(path: &str, local_info: &LocalInfo) -> Result<()> {
     let dirname = format!("{}{}", local_info.root_path, path);
     fileope::create_dir_all(&dirname)?;
@@ -456,9 +497,9 @@ fn move_entry(from_path: &str, to_path: &str, stash: bool, local_info: &LocalInf
     Ok(())
 }
 
-fn remove_entry(path: &str, stash: bool, local_info: &LocalInfo) -> Result<()> {
+fn remove_entry(path: &str, stash: bool, cause: StashCause, local_info: &LocalInfo) -> Result<()> {
     let path = format!("{}{}", local_info.root_path, path);
-    fileope::remove_entry(path, stash, local_info)?;
+    fileope::remove_entry(path, stash, cause, local_info)?;
 
     Ok(())
 }
@@ -471,36 +512,37 @@ fn check_local_entry_is_dir(path: &str, local_info: &LocalInfo) -> bool {
 
 #[async_recursion(?Send)]
 pub async fn init_local_entries(
-    nc_info: &NCInfo,
     local_info: &LocalInfo,
-    entry: &ArcEntry,
+    tree: &mut Tree,
+    id: EntryId,
     ancestor_path: &str,
 ) -> Result<()> {
-    let mut entry_ref = entry.lock().map_err(|_| LockError)?;
+    let entry = tree.entry(id).with_context(|| WeakUpgradeError)?;
 
-    if entry_ref.status == EntryStatus::UpToDate {
+    if entry.status == EntryStatus::UpToDate {
         return Ok(());
     }
 
-    let full_path = format!("{}{}", ancestor_path, entry_ref.get_name());
-    match &entry_ref.type_ {
-        &EntryType::Directory => {
+    let full_path = format!("{}{}", ancestor_path, entry.get_name());
+    match entry.type_ {
+        EntryType::Directory => {
             create_dir_all(&full_path, local_info)?;
-            entry_ref.status = EntryStatus::UpToDate;
-            for c in entry_ref.children.values() {
-                init_local_entries(nc_info, local_info, c, &full_path).await?;
+            tree.entry_mut(id).unwrap().status = EntryStatus::UpToDate;
+            for c in tree.children(id) {
+                init_local_entries(local_info, tree, c, &full_path).await?;
             }
         }
-        &EntryType::File { .. } => {
+        EntryType::File { .. } => {
             create_dir_all(ancestor_path, local_info)?;
-            let res =
-                download_file_in_rec(nc_info, local_info, &mut entry_ref, ancestor_path).await;
+            let remote_path = local_info.names.remote_path(tree, &full_path);
+            let entry = tree.entry_mut(id).unwrap();
+            let res = download_file_raw(local_info, entry, &full_path, &remote_path, false).await;
             match res {
                 Ok(()) => {
-                    entry_ref.status = EntryStatus::UpToDate;
+                    entry.status = EntryStatus::UpToDate;
                 }
                 Err(e) => {
-                    entry_ref.status = EntryStatus::Error;
+                    entry.status = EntryStatus::Error;
                     return Err(e);
                 }
             }
@@ -511,30 +553,19 @@ pub async fn init_local_entries(
 }
 
 async fn download_file_raw(
-    nc_info: &NCInfo,
     local_info: &LocalInfo,
     entry: &mut Entry,
     full_path: &str,
+    remote_path: &str,
     stash: bool,
 ) -> Result<()> {
     if entry.type_.is_dir() {
         return Err(anyhow!("Not file entry!!"));
     }
 
-    let mut url = Url::parse(&nc_info.host)?;
-    let path_v = format!("{}{}", nc_info.root_path, full_path)
-        .split("/")
-        .map(|v| v.to_string())
-        .collect::<Vec<String>>();
-    url.path_segments_mut().unwrap().extend(path_v);
-
-    // let client = Client::builder().https_only(true).build()?;
-    let ref client = local_info.req_client;
-
-    let data_res = client
-        .request(Method::GET, url.as_str())
-        .basic_auth(&nc_info.username, Some(&nc_info.password))
-        .send()
+    let client = &local_info.nc_client;
+    let mut data_res = client
+        .send(client.dav_request(Method::GET, remote_path)?)
         .await?;
 
     let new_etag = data_res
@@ -543,69 +574,96 @@ async fn download_file_raw(
         .with_context(|| format!("Can't get new etag."))
         .and_then(|v| v.to_str().with_context(|| "Can't get new etag."))
         .map(|v| v.to_string().replace("\"", ""))?;
+    let old_etag = entry.type_.get_etag();
+    // the one of PROPFIND if the response has none.
+    let server_mtime = data_res
+        .headers()
+        .get("Last-Modified")
+        .and_then(|v| v.to_str().ok())
+        .and_then(mtime::parse_http_date)
+        .or(entry.mtime);
+
+    let tmp = download_tempfile(local_info)?;
+    let size = match write_body(&mut data_res, &tmp, local_info).await {
+        Ok(size) => size,
+        Err(e) => {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+    };
+    let old_etag = Some(old_etag.as_str()).filter(|e| !e.is_empty());
+    let cause = StashCause::new(StashReason::DownloadOverwrite, old_etag);
+    let saved = match local_info.symlinks.parse_descriptor_file(&tmp) {
+        Some(target) => {
+            let _ = fs::remove_file(&tmp);
+            save_link(&target, full_path, local_info, stash, cause)
+        }
+        None => save_file_from(&tmp, full_path, local_info, stash, cause),
+    };
+    if let Err(e) = saved {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    let filename = format!("{}{}", local_info.root_path, full_path);
+    if !fs::symlink_metadata(&filename).is_ok_and(|m| m.file_type().is_symlink()) {
+        if local_info.attrs.applies_to(full_path) {
+            restore_attrs(local_info, full_path, &filename, remote_path).await;
+        }
+        if let Some(m) = server_mtime {
+            if let Err(e) = mtime::set_local_mtime(&filename, m) {
+                warn!("{}: can't set the mtime. | {:?}", full_path, e);
+            }
+        }
+        entry.mtime = mtime::local_mtime(&filename);
+    }
+    METRICS.record_download(size);
+    let reason = if old_etag.is_some() {
+        "changed on the server"
+    } else {
+        "new on the server"
+    };
+    sync_event("download", full_path, None, reason);
+
+    // only after saved. A failed download keeps the old etag to be retried.
     entry.type_ = EntryType::File {
         etag: Some(new_etag),
     };
 
-    let bytes = data_res.bytes().await?;
-    save_file(&mut bytes.as_ref(), full_path, local_info, stash)?;
-
     Ok(())
 }
 
-async fn download_file_in_rec(
-    nc_info: &NCInfo,
-    local_info: &LocalInfo,
-    entry: &mut Entry,
-    ancestor_path: &str,
-) -> Result<()> {
-    let full_path = format!("{}{}", ancestor_path, entry.get_name());
-
-    download_file_raw(nc_info, local_info, entry, &full_path, false).await
-}
-
+// Ok(None) if the entry is already of the etag on the server or removed.
 pub async fn download_file_with_check_etag(
     nc_info: &NCInfo,
     local_info: &LocalInfo,
-    entry: &ArcEntry,
+    tree: &mut Tree,
+    full_path: &str,
     stash: bool,
 ) -> Result<Option<String>> {
-    let full_path = Entry::get_path(entry)?;
-
-    let nc_etag = get_etag_from_nc(nc_info, local_info, &full_path).await;
+    let remote_path = local_info.names.remote_path(tree, full_path);
+    let nc_etag = get_etag_from_nc(nc_info, local_info, &remote_path).await;
 
-    {
-        let mut entry_ref = entry.lock().map_err(|_| LockError)?;
-        if_chain! {
-            if let Some(etag) = nc_etag;
-            if entry_ref.type_ != EntryType::File { etag: Some(etag) };
-            then {
-                debug!("Need to download.");
-                download_file_raw(nc_info, local_info, &mut entry_ref, &full_path, stash).await?;
-                return Ok(Some(full_path));
-            }
+    let entry = match tree.get_mut(full_path) {
+        Some(e) => e,
+        None => return Ok(None),
+    };
+    if_chain! {
+        if let Some(etag) = nc_etag;
+        if entry.type_ != EntryType::File { etag: Some(etag) };
+        then {
+            debug!("Need to download.");
+            download_file_raw(local_info, entry, full_path, &remote_path, stash).await?;
+            return Ok(Some(full_path.to_string()));
         }
     }
 
     Ok(None)
 }
 
-pub async fn get_latest_activity_id(nc_info: &NCInfo, local_info: &LocalInfo) -> Result<String> {
-    let mut url = Url::parse(&nc_info.host)?;
-    let path_v = OCS_ROOT
-        .split("/")
-        .map(|v| v.to_string())
-        .collect::<Vec<String>>();
-    url.path_segments_mut().unwrap().extend(path_v);
-
-    // let client = Client::builder().https_only(true).build()?;
-    let ref client = local_info.req_client;
-
+pub async fn get_latest_activity_id(local_info: &LocalInfo) -> Result<String> {
+    let client = &local_info.nc_client;
     let res = client
-        .request(Method::GET, url.as_str())
-        .basic_auth(&nc_info.username, Some(&nc_info.password))
-        .header("OCS-APIRequest", "true")
-        .send()
+        .send(client.ocs_request(Method::GET, OCS_ROOT)?)
         .await?;
 
     res.headers()
@@ -623,6 +681,17 @@ pub enum NCEvent {
     Move(String, String),
 }
 
+impl NCEvent {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Create(_) => "create",
+            Self::Delete(_) => "delete",
+            Self::Modify(_) => "modify",
+            Self::Move(..) => "move",
+        }
+    }
+}
+
 impl ModifiedPath for NCEvent {
     type Item = String;
 
@@ -641,39 +710,40 @@ pub async fn get_ncevents(
     local_info: &LocalInfo,
     nc_state: &mut NCState,
 ) -> Result<Vec<NCEvent>> {
-    let mut url = Url::parse(&nc_info.host)?;
-    let path_v = OCS_ROOT
-        .split("/")
-        .map(|v| v.to_string())
-        .collect::<Vec<String>>();
-    url.path_segments_mut().unwrap().extend(path_v);
+    let started = std::time::Instant::now();
+    let res = get_ncevents_raw(nc_info, local_info, nc_state).await;
+    METRICS.record_poll(started.elapsed());
 
-    // let client = Client::builder().https_only(true).build()?;
-    let ref client = local_info.req_client;
+    res
+}
+
+async fn get_ncevents_raw(
+    nc_info: &NCInfo,
+    local_info: &LocalInfo,
+    nc_state: &mut NCState,
+) -> Result<Vec<NCEvent>> {
+    let client = &local_info.nc_client;
 
     let mut latest_activity_id = nc_state.latest_activity_id.to_string();
     let mut responses = vec![];
     let mut err = None;
     loop {
-        let res = client
-            .request(Method::GET, url.as_str())
-            .query(&[("since", latest_activity_id.as_str()), ("sort", "asc")])
-            .basic_auth(&nc_info.username, Some(&nc_info.password))
-            .header("OCS-APIRequest", "true")
-            .send()
-            .await?;
+        let req = client
+            .ocs_request(Method::GET, OCS_ROOT)?
+            .query(&[("since", latest_activity_id.as_str()), ("sort", "asc")]);
+        let res = client.send_raw(req).await?;
 
         let s = res.status();
         if !s.is_success() {
-            if s.as_u16() != 304 {
-                err = Some(Err(BadStatusError(s.as_u16()).into()));
+            if s != StatusCode::NOT_MODIFIED {
+                err = Some(Err(NcsError::from_status(s).into()));
             }
             break;
             /*
             if s.as_u16() == 304 {
                 return Ok(vec![]);
             } else {
-                return Err(BadStatusError(s.as_u16()).into());
+                return Err(NcsError::from_status(s).into());
             }
             */
         }
@@ -835,42 +905,38 @@ async fn ncevents_xml2responses(
     Ok(res)
 }
 
-fn touch_targets(update_targets: Vec<WeakEntry>, local_info: &LocalInfo) -> anyhow::Result<()> {
+fn touch_targets(tree: &mut Tree, update_targets: Vec<EntryId>, local_info: &LocalInfo) {
     for target in update_targets.into_iter() {
-        if let Some(e) = target.upgrade() {
-            let path = Entry::get_path(&e)?;
-            let mut e_ref = e.lock().map_err(|_| LockError)?;
-            let res = touch_entry(&path, e_ref.type_.is_file(), local_info);
+        if let Some(path) = tree.path_of(target) {
+            let e = tree.entry_mut(target).unwrap();
+            let res = touch_entry(&path, e.type_.is_file(), local_info);
             match res {
                 Ok(_) => {
-                    e_ref.status = EntryStatus::UpToDate;
+                    e.status = EntryStatus::UpToDate;
                 }
                 Err(er) => {
-                    e_ref.status = EntryStatus::Error;
+                    e.status = EntryStatus::Error;
                     warn!("{:?}", er);
                 }
             }
         }
     }
-
-    Ok(())
 }
 
+// returns the paths of the files to be downloaded.
 async fn update_tree(
     nc_info: &NCInfo,
     local_info: &LocalInfo,
     mut nc_events: Vec<NCEvent>,
-    root_entry: &ArcEntry,
+    tree: &mut Tree,
     stash: bool,
-) -> Result<Vec<WeakEntry>> {
+    source: &Source,
+) -> Result<Vec<String>> {
     let mut download_targets = Vec::new();
 
     nc_events.reverse();
     while let Some(event) = nc_events.pop() {
-        {
-            let root_ref = root_entry.lock().map_err(|_| LockError)?;
-            debug!("\n{}", root_ref.get_tree());
-        }
+        debug!("\n{}", tree.get_tree());
 
         match event {
             NCEvent::Create(path) => {
@@ -881,35 +947,31 @@ async fn update_tree(
                     continue;
                 }
 
-                let path = drop_slash(&path, &RE_HAS_LAST_SLASH);
-                if Entry::get(root_entry, &path)?.is_some() {
+                let remote_path = Tree::normalize_path(&path);
+                let path = local_info.names.local_path(tree, &remote_path, None);
+                if tree.contains(&path) {
                     continue;
                 }
                 let name = path2name(&path);
-                let type_ = if ncpath_is_file(nc_info, local_info, &path).await {
+                let type_ = if ncpath_is_file(nc_info, local_info, &remote_path).await {
                     EntryType::File { etag: None }
                 } else {
                     EntryType::Directory
                 };
-                let new_entry = Arc::new(Mutex::new(Entry::new(name, type_)));
-                let update_targets = Entry::append(
-                    root_entry,
-                    &path,
-                    new_entry.clone(),
-                    AppendMode::Create,
-                    false,
-                )?;
-                let filop_res = touch_targets(update_targets, local_info);
-                if let Err(e) = filop_res {
-                    warn!("{:?}", e);
-                }
-                {
-                    let mut new_entry_ref = new_entry.lock().map_err(|_| LockError)?;
-                    if new_entry_ref.type_.is_file() {
-                        new_entry_ref.status = EntryStatus::NeedUpdate;
-                        let new_entry_w = Arc::downgrade(&new_entry);
-                        download_targets.push(new_entry_w);
+                let is_file = type_.is_file();
+                let update_targets = tree.insert(&path, Entry::new(name, type_))?;
+                local_info.names.set_remote_names(tree, &path, &remote_path);
+                touch_targets(tree, update_targets, local_info);
+                if is_file {
+                    if let Some(e) = tree.get_mut(&path) {
+                        e.status = EntryStatus::NeedUpdate;
                     }
+                    download_targets.push(path);
+                } else {
+                    // files are recorded when downloaded.
+                    local_info
+                        .history
+                        .append(HistoryRecord::new(&path, source.clone(), "mkdir"));
                 }
             }
             NCEvent::Delete(path) => {
@@ -920,13 +982,16 @@ async fn update_tree(
                     continue;
                 }
 
-                let path = drop_slash(&path, &RE_HAS_LAST_SLASH);
-                let mut root_ref = root_entry.lock().map_err(|_| LockError)?;
-                let _ = root_ref.pop(&path)?;
-                let filop_res = remove_entry(&path, stash, local_info);
+                let path = local_info.names.local_path(tree, &path, None);
+                let old_etag = tree.remove(&path).and_then(|e| entry_etag(&e));
+                let cause = StashCause::new(StashReason::Delete, old_etag.as_deref());
+                let filop_res = remove_entry(&path, stash, cause, local_info);
                 if let Err(e) = filop_res {
                     warn!("{:?}", e);
                 }
+                local_info.history.append(
+                    HistoryRecord::new(&path, source.clone(), "delete").etags(old_etag, None),
+                );
             }
             NCEvent::Modify(path) => {
                 debug!("Modify {}", path);
@@ -936,21 +1001,12 @@ async fn update_tree(
                     continue;
                 }
 
-                let path = drop_slash(&path, &RE_HAS_LAST_SLASH);
-                let w = Entry::get(root_entry, &path)?;
-                if_chain! {
-                    if let Some(w) = w;
-                    if let Some(e) = w.upgrade();
-                    then {
-                        let is_file = {
-                            let mut e_ref = e.lock().map_err(|_| LockError)?;
-                            debug!("NeedUpdate substituted.");
-                            e_ref.status = EntryStatus::NeedUpdate;
-                            e_ref.type_.is_file()
-                        };
-                        if is_file {
-                            download_targets.push(Arc::downgrade(&e));
-                        }
+                let path = local_info.names.local_path(tree, &path, None);
+                if let Some(e) = tree.get_mut(&path) {
+                    debug!("NeedUpdate substituted.");
+                    e.status = EntryStatus::NeedUpdate;
+                    if e.type_.is_file() {
+                        download_targets.push(path);
                     }
                 }
             }
@@ -962,22 +1018,22 @@ async fn update_tree(
                     continue;
                 }
 
-                let to_path = drop_slash(&to_path, &RE_HAS_LAST_SLASH);
-                let from_path = drop_slash(&from_path, &RE_HAS_LAST_SLASH);
-                let target_w = {
-                    let mut root_ref = root_entry.lock().map_err(|_| LockError)?;
-                    root_ref.pop(&from_path)?
-                };
+                let remote_to_path = Tree::normalize_path(&to_path);
+                let from_path = local_info.names.local_path(tree, &from_path, None);
+                let to_path =
+                    local_info
+                        .names
+                        .local_path(tree, &remote_to_path, tree.get_id(&from_path));
                 // .with_context(|| InvalidPathError(format!("{} => {}", from_path, to_path)));
 
-                let target = match target_w {
+                let (target, update_targets) = match tree.move_entry(&from_path, &to_path)? {
                     Some(v) => v,
                     None => {
                         warn!(
                             "from_path({}) is not found.\nI'll try create entries. but This operation will cause strange result.",
                             from_path
                         );
-                        let mut v = get_all_sub_path(nc_info, local_info, &to_path).await;
+                        let mut v = get_all_sub_path(nc_info, local_info, &remote_to_path).await;
                         v.reverse();
                         for p in v {
                             nc_events.push(NCEvent::Create(p));
@@ -985,11 +1041,14 @@ async fn update_tree(
                         continue;
                     }
                 };
+                let target_path = tree.path_of(target).with_context(|| WeakUpgradeError)?;
+                if target_path == to_path {
+                    local_info
+                        .names
+                        .set_remote_names(tree, &to_path, &remote_to_path);
+                }
 
-                let update_targets =
-                    Entry::append(root_entry, &to_path, target.clone(), AppendMode::Move, true)?;
-
-                touch_targets(update_targets, local_info)?;
+                touch_targets(tree, update_targets, local_info);
 
                 let res = move_entry(&from_path, &to_path, stash, local_info);
                 match res {
@@ -997,18 +1056,18 @@ async fn update_tree(
                         warn!("{:?}", er);
                     }
                     Ok(_) => {
+                        local_info.history.append(
+                            HistoryRecord::new(&from_path, source.clone(), "move").to(&to_path),
+                        );
                         debug!("try_fix_entry_type@move");
-                        fix_entry_type_rec(&target, &mut download_targets, nc_info, local_info)
-                            .await?;
-                        /*
-                        let path = Entry::get_path(&target)?;
-                        let have_to_download =
-                            try_fix_entry_type(&target, &path, nc_info, local_info).await?;
-                        if have_to_download {
-                            let w = Arc::downgrade(&target);
-                            download_targets.push(w);
-                        }
-                        */
+                        fix_entry_type_rec(
+                            tree,
+                            &target_path,
+                            &mut download_targets,
+                            nc_info,
+                            local_info,
+                        )
+                        .await?;
                     }
                 }
             }
@@ -1019,65 +1078,71 @@ async fn update_tree(
 
 #[async_recursion]
 async fn fix_entry_type_rec(
-    target: &ArcEntry,
-    download_targets: &mut Vec<WeakEntry>,
+    tree: &mut Tree,
+    path: &str,
+    download_targets: &mut Vec<String>,
     nc_info: &NCInfo,
     local_info: &LocalInfo,
 ) -> Result<()> {
-    let path = Entry::get_path(&target)?;
-    let have_to_download = try_fix_entry_type(&target, &path, nc_info, local_info).await?;
+    let have_to_download = try_fix_entry_type(tree, path, nc_info, local_info).await?;
     if have_to_download {
-        let w = Arc::downgrade(&target);
-        download_targets.push(w);
+        download_targets.push(path.to_string());
     }
-    let (is_dir, children) = {
-        let target_ref = target.lock().map_err(|_| LockError)?;
-        (target_ref.type_.is_dir(), target_ref.get_all_children())
+    let children = match tree.get_id(path) {
+        Some(id) if tree.entry(id).unwrap().type_.is_dir() => tree
+            .children(id)
+            .into_iter()
+            .filter_map(|c| tree.path_of(c))
+            .collect(),
+        _ => Vec::new(),
     };
-    if is_dir {
-        for child in children {
-            if let Some(child) = child.upgrade() {
-                let e = fix_entry_type_rec(&child, download_targets, nc_info, local_info).await;
-                debug!("{:?}", e);
-            }
-        }
+    for child in children {
+        let e = fix_entry_type_rec(tree, &child, download_targets, nc_info, local_info).await;
+        debug!("{:?}", e);
     }
 
     Ok(())
 }
 
 async fn try_fix_entry_type(
-    target: &ArcEntry,
+    tree: &mut Tree,
     path: &str,
     nc_info: &NCInfo,
     local_info: &LocalInfo,
 ) -> Result<bool> {
-    let res = from_nc(nc_info, local_info, path).await;
+    let remote_path = local_info.names.remote_path(tree, path);
+    let res = from_nc(nc_info, local_info, &remote_path).await;
 
     let nc_entry = match res {
         Ok(entry) => entry,
         Err(_) => return Ok(false),
     };
 
-    let mut target_ref = target.lock().map_err(|_| LockError)?;
-    if target_ref.type_.is_same_type(&nc_entry.type_) {
+    let id = match tree.get_id(path) {
+        Some(id) => id,
+        None => return Ok(false),
+    };
+    let target = tree.entry_mut(id).unwrap();
+    if target.type_.is_same_type(&nc_entry.type_) {
         return Ok(false);
     }
 
-    target_ref.status = EntryStatus::NeedUpdate;
+    target.status = EntryStatus::NeedUpdate;
 
-    target_ref.type_ = if target_ref.type_.is_file() {
+    target.type_ = if target.type_.is_file() {
         EntryType::Directory
     } else {
         EntryType::File { etag: None }
     };
+    let is_file = target.type_.is_file();
+    tree.clear_children_because_wrong_type(id);
 
     debug!("{}", path);
-    remove_entry(&path, false, local_info)?;
+    remove_entry(path, false, DELETE_CAUSE, local_info)?;
     debug!("removed. will touch");
-    touch_entry(&path, target_ref.type_.is_file(), local_info)?;
+    touch_entry(path, is_file, local_info)?;
 
-    Ok(target_ref.type_.is_file())
+    Ok(is_file)
 }
 
 pub async fn nclistening(
@@ -1085,21 +1150,25 @@ pub async fn nclistening(
     nc_info: &NCInfo,
     local_info: &LocalInfo,
     mut nc_state: NCState,
+    dry_run: bool,
 ) -> Result<()> {
+    let mut network_status = None;
     loop {
         if tx.is_closed() {
             return Ok(());
         }
 
-        let dt = chrono::Local::now();
-        let timestamp = dt.format("%Y-%m-%d %H:%M:%S").to_string();
+        if !dry_run {
+            let dt = chrono::Local::now();
+            let timestamp = dt.format("%Y-%m-%d %H:%M:%S").to_string();
 
-        let mut contents = timestamp.as_bytes();
-        let filename = local_info.get_keepalive_filename();
-        let mut out = std::fs::File::create(&filename)?;
-        io::copy(&mut contents, &mut out)?;
+            let mut contents = timestamp.as_bytes();
+            let filename = local_info.get_keepalive_filename();
+            let mut out = std::fs::File::create(&filename)?;
+            io::copy(&mut contents, &mut out)?;
+        }
 
-        if !network::check(&tx, nc_info, &local_info.req_client).await? {
+        if !network::check(&tx, &local_info.nc_client, &mut network_status).await? {
             sleep(Duration::from_secs(10)).await;
             continue;
         }
@@ -1114,46 +1183,60 @@ pub async fn nclistening(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn update_and_download(
     events: Vec<NCEvent>,
-    root: &ArcEntry,
+    tree: &mut Tree,
     nc_info: &NCInfo,
     local_info: &LocalInfo,
     nc2l_cancel_map: &mut HashMap<String, usize>,
     l2nc_cancel_set: &mut HashSet<NCEvent>,
     stash: bool,
+    source: &Source,
 ) -> Result<()> {
     let events = events
         .into_iter()
         .filter(|ev| !l2nc_cancel_set.remove(ev))
         .collect::<Vec<_>>();
     debug!("events: {:?}", events);
-    let download_targets = update_tree(nc_info, local_info, events, root, stash).await?;
-    for target in download_targets.into_iter() {
-        if let Some(e) = target.upgrade() {
-            {
-                let e_ref = e.lock().map_err(|_| LockError)?;
-                if e_ref.type_.is_dir() {
-                    continue;
-                }
-            }
-            debug!("download target: {:?}", e);
-            let r = download_file_with_check_etag(nc_info, local_info, &e, stash).await;
-            let target_path = match r {
-                Ok(path) => path,
-                Err(e) => {
-                    warn!("{:?}", e);
-                    continue;
+    for ev in events.iter() {
+        METRICS.record_event("nc", ev.kind());
+    }
+    let download_targets = update_tree(nc_info, local_info, events, tree, stash, source).await?;
+    for full_path in download_targets.into_iter() {
+        // removed or turned into a directory by a later event.
+        let old_etag = match tree.get(&full_path) {
+            Some(e) if e.type_.is_file() => entry_etag(e),
+            _ => continue,
+        };
+        debug!("download target: {:?}", full_path);
+        let r = download_file_with_check_etag(nc_info, local_info, tree, &full_path, stash).await;
+        let target_path = match r {
+            Ok(path) => path,
+            Err(err) => {
+                warn!("{:?}", err);
+                if let Some(e) = tree.get_mut(&full_path) {
+                    e.status = EntryStatus::Error;
                 }
-            };
-            {
-                let mut e_ref = e.lock().map_err(|_| LockError)?;
-                e_ref.status = EntryStatus::UpToDate;
-            }
-            if let Some(item) = target_path {
-                let counter = nc2l_cancel_map.entry(item).or_insert(0);
-                *counter += 1;
+                local_info
+                    .failures
+                    .record_or_warn(FailedOp::Download(full_path), &err);
+                continue;
             }
+        };
+        if let Some(e) = tree.get_mut(&full_path) {
+            e.status = EntryStatus::UpToDate;
+        }
+        local_info.failures.resolve_or_warn(&full_path);
+        if target_path.is_some() {
+            local_info.history.append(
+                HistoryRecord::new(&full_path, source.clone(), "download")
+                    .etags(old_etag, tree_etag(tree, &full_path)),
+            );
+        }
+        if let Some(item) = target_path {
+            let counter = nc2l_cancel_map.entry(item).or_insert(0);
+            *counter += 1;
         }
     }
 
@@ -1166,7 +1249,7 @@ pub async fn update_and_download(
 pub async fn refresh<P>(
     target: P,
     is_recursive: bool,
-    root: &ArcEntry,
+    tree: &mut Tree,
     nc_info: &NCInfo,
     local_info: &LocalInfo,
     nc2l_cancel_map: &mut HashMap<String, usize>,
@@ -1187,27 +1270,20 @@ where
 
     let target_str = path2str(target_path);
     debug!("refresh beep {:?}", target_str);
-    const RE_REMOVE_CHILDPART: Lazy<Regex> = Lazy::new(|| Regex::new("^(.*)/[^/]+$").unwrap());
+    static RE_REMOVE_CHILDPART: Lazy<Regex> = Lazy::new(|| Regex::new("^(.*)/[^/]+$").unwrap());
     let p_str: &str = &RE_REMOVE_CHILDPART.replace(&target_str, "$1");
 
-    let parent_entry = if_chain! {
-        if let Ok(Some(w)) = Entry::get(root, p_str);
-        if let Some(entry) = w.upgrade();
-        then {
-            entry
-        } else {
-            info!("[refresh] no such parent: {:?}", p_str);
-            return Ok(());
-        }
-    };
+    if !tree.get(p_str).is_some_and(|e| e.type_.is_dir()) {
+        info!("[refresh] no such parent: {:?}", p_str);
+        return Ok(());
+    }
 
-    let target_entry = from_nc_all_in_the_middle(nc_info, local_info, &target_str).await?;
-    Entry::append_child(&parent_entry, target_entry.clone())?;
+    let old_etag = tree_etag(tree, &target_str);
+    let remote_path = local_info.names.remote_path(tree, &target_str);
+    let sub_tree = from_nc_all_in_the_middle(nc_info, local_info, &remote_path).await?;
+    let target_id = tree.graft(&target_str, &sub_tree)?;
 
-    let is_file = {
-        let entry = target_entry.lock().map_err(|_| LockError)?;
-        entry.type_.is_file()
-    };
+    let is_file = sub_tree.root().type_.is_file();
 
     // If app had failed to get the file and the dir made instead.
     // It must repaired.
@@ -1233,41 +1309,40 @@ where
     */
 
     if is_file {
-        remove_entry(&target_str, stash, local_info)?;
+        let cause = StashCause::new(StashReason::DownloadOverwrite, None);
+        remove_entry(&target_str, stash, cause, local_info)?;
 
         {
-            let mut entry = target_entry.lock().map_err(|_| LockError)?;
+            let remote_path = local_info.names.remote_path(tree, &target_str);
+            let entry = tree.entry_mut(target_id).unwrap();
             // already stashed pre-process.
-            download_file_raw(nc_info, local_info, &mut entry, &target_str, false).await?;
+            download_file_raw(local_info, entry, &target_str, &remote_path, false).await?;
         }
+        local_info.history.append(
+            HistoryRecord::new(&target_str, Source::Refresh, "download")
+                .etags(old_etag, tree_etag(tree, &target_str)),
+        );
         let counter = nc2l_cancel_map.entry(target_str).or_insert(0);
         *counter += 1;
     } else {
         if !check_local_entry_is_dir(&target_str, local_info) {
-            remove_entry(&target_str, true, local_info)?;
+            remove_entry(&target_str, true, DELETE_CAUSE, local_info)?;
             touch_entry(&target_str, false, local_info)?;
         }
 
-        let children = {
-            let entry = target_entry.lock().map_err(|_| LockError)?;
-            entry.get_all_children()
-        };
-
-        for child in children.into_iter() {
-            if let Some(child) = child.upgrade() {
-                let r = refresh_rec(
-                    &child,
-                    &target_str,
-                    is_recursive,
-                    nc_info,
-                    local_info,
-                    nc2l_cancel_map,
-                    stash,
-                )
-                .await;
-                if let Err(e) = r {
-                    res = Err(e);
-                }
+        for child in tree.children(target_id).into_iter() {
+            let r = refresh_rec(
+                tree,
+                child,
+                &target_str,
+                is_recursive,
+                local_info,
+                nc2l_cancel_map,
+                stash,
+            )
+            .await;
+            if let Err(e) = r {
+                res = Err(e);
             }
         }
     }
@@ -1277,10 +1352,10 @@ where
 
 #[async_recursion(?Send)]
 async fn refresh_rec(
-    target_entry: &ArcEntry,
+    tree: &mut Tree,
+    target_id: EntryId,
     parent_str: &str,
     is_recursive: bool,
-    nc_info: &NCInfo,
     local_info: &LocalInfo,
     nc2l_cancel_map: &mut HashMap<String, usize>,
     stash: bool,
@@ -1288,48 +1363,48 @@ async fn refresh_rec(
     let mut res = Ok(());
 
     let (name, is_file) = {
-        let entry = target_entry.lock().map_err(|_| LockError)?;
+        let entry = tree.entry(target_id).with_context(|| WeakUpgradeError)?;
         (entry.get_raw_name(), entry.type_.is_file())
     };
 
     let target_str = format!("{}/{}", parent_str, name);
 
     if is_file {
-        remove_entry(&target_str, stash, local_info)?;
+        let cause = StashCause::new(StashReason::DownloadOverwrite, None);
+        remove_entry(&target_str, stash, cause, local_info)?;
 
         {
-            let mut entry = target_entry.lock().map_err(|_| LockError)?;
+            let remote_path = local_info.names.remote_path(tree, &target_str);
+            let entry = tree.entry_mut(target_id).unwrap();
             // already stashed pre-process.
-            download_file_raw(nc_info, local_info, &mut entry, &target_str, false).await?;
+            download_file_raw(local_info, entry, &target_str, &remote_path, false).await?;
         }
+        // the old entry is already replaced. Its etag is not known here.
+        local_info.history.append(
+            HistoryRecord::new(&target_str, Source::Refresh, "download")
+                .etags(None, tree.entry(target_id).and_then(entry_etag)),
+        );
         let counter = nc2l_cancel_map.entry(target_str).or_insert(0);
         *counter += 1;
     } else if is_recursive {
         if !check_local_entry_is_dir(&target_str, local_info) {
-            remove_entry(&target_str, true, local_info)?;
+            remove_entry(&target_str, true, DELETE_CAUSE, local_info)?;
             touch_entry(&target_str, false, local_info)?;
         }
 
-        let children = {
-            let entry = target_entry.lock().map_err(|_| LockError)?;
-            entry.get_all_children()
-        };
-
-        for child in children.into_iter() {
-            if let Some(child) = child.upgrade() {
-                let r = refresh_rec(
-                    &child,
-                    &target_str,
-                    is_recursive,
-                    nc_info,
-                    local_info,
-                    nc2l_cancel_map,
-                    stash,
-                )
-                .await;
-                if let Err(e) = r {
-                    res = Err(e);
-                }
+        for child in tree.children(target_id).into_iter() {
+            let r = refresh_rec(
+                tree,
+                child,
+                &target_str,
+                is_recursive,
+                local_info,
+                nc2l_cancel_map,
+                stash,
+            )
+            .await;
+            if let Err(e) = r {
+                res = Err(e);
             }
         }
     }
@@ -1,89 +1,133 @@
-use crate::meta::NCInfo;
+use crate::nc_client::NcClient;
 use crate::*;
 use anyhow::{Error, Result};
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
+use reqwest::Method;
+use serde::Deserialize;
 use std::time::Duration;
 use tokio::sync::mpsc;
 
+const STATUS_PATH: &str = "/status.php";
+
+#[derive(Debug)]
 pub enum NetworkStatus {
     Connect,
+    // The server answers but is in maintenance mode. Syncing is paused without repairs.
+    Maintenance,
     Disconnect,
+    // Something answered, but it was not a usable Nextcloud (5xx, captive portal, not installed...).
     Err(Error),
 }
 
+// Disconnect and Err are the same state because both of them mean we can't sync.
 impl std::cmp::PartialEq for NetworkStatus {
     fn eq(&self, other: &Self) -> bool {
-        match self {
-            &Self::Connect => match other {
-                &Self::Connect => true,
-                _ => false,
-            },
-            _ => match other {
-                &Self::Connect => false,
-                _ => true,
-            },
-        }
+        matches!(
+            (self, other),
+            (Self::Connect, Self::Connect)
+                | (Self::Maintenance, Self::Maintenance)
+                | (
+                    Self::Disconnect | Self::Err(_),
+                    Self::Disconnect | Self::Err(_)
+                )
+        )
     }
 }
 
 impl std::cmp::Eq for NetworkStatus {}
 
-pub async fn status_raw(nc_info: &NCInfo, client: &reqwest::Client) -> NetworkStatus {
-    // let res = reqwest::get(&nc_info.host).await;
+impl NetworkStatus {
+    pub fn is_connect(&self) -> bool {
+        matches!(self, Self::Connect)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ServerStatus {
+    installed: bool,
+    maintenance: bool,
+}
+
+pub async fn status_raw(client: &NcClient) -> NetworkStatus {
+    let url = match client.url(STATUS_PATH) {
+        Ok(url) => url,
+        Err(e) => return NetworkStatus::Err(e),
+    };
     let res = client
-        .get(&nc_info.host)
+        .request(Method::GET, url)
         .timeout(Duration::from_secs(5))
         .send()
         .await;
 
-    match res {
-        Ok(_) => NetworkStatus::Connect,
-        Err(e) if e.is_connect() => NetworkStatus::Disconnect,
+    let res = match res {
+        Ok(res) => res,
+        Err(e) if e.is_connect() || e.is_timeout() => return NetworkStatus::Disconnect,
         Err(e) => {
             error!("{:?}", e);
-            NetworkStatus::Err(Error::new(e))
+            return NetworkStatus::Err(Error::new(e));
         }
+    };
+
+    if !res.status().is_success() {
+        return NetworkStatus::Err(errors::NcsError::from_status(res.status()).into());
     }
-}
 
-pub async fn is_online(nc_info: &NCInfo, client: &reqwest::Client) -> bool {
-    match status_raw(nc_info, client).await {
-        NetworkStatus::Connect => true,
-        _ => false,
+    // A captive portal answers 200 with a html page.
+    let text = match res.text().await {
+        Ok(t) => t,
+        Err(e) => return NetworkStatus::Err(Error::new(e)),
+    };
+    let status = match serde_json::from_str::<ServerStatus>(&text) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("{} is not a Nextcloud status: {:?}", STATUS_PATH, e);
+            return NetworkStatus::Err(Error::new(e));
+        }
+    };
+    debug!("{:?}", status);
+
+    if !status.installed {
+        NetworkStatus::Err(anyhow!("Nextcloud is not installed."))
+    } else if status.maintenance {
+        NetworkStatus::Maintenance
+    } else {
+        NetworkStatus::Connect
     }
 }
 
-pub async fn status(nc_info: &NCInfo, client: &reqwest::Client) -> Result<NetworkStatus> {
-    // let res = reqwest::get(&nc_info.host).await;
-    let res = client
-        .get(&nc_info.host)
-        .timeout(Duration::from_secs(5))
-        .send()
-        .await;
+pub async fn is_online(client: &NcClient) -> bool {
+    status_raw(client).await.is_connect()
+}
 
-    match res {
-        Ok(_) => Ok(NetworkStatus::Connect),
-        Err(e) if e.is_connect() => Ok(NetworkStatus::Disconnect),
-        Err(e) => {
-            error!("{:?}", e);
-            Err(e.into())
-        }
+pub async fn status(client: &NcClient) -> Result<NetworkStatus> {
+    match status_raw(client).await {
+        NetworkStatus::Err(e) => Err(e),
+        s => Ok(s),
     }
 }
 
+// Commands are sent only when the state changes from `prev_status`.
 pub async fn check(
     tx: &mpsc::Sender<Command>,
-    nc_info: &NCInfo,
-    client: &reqwest::Client,
+    client: &NcClient,
+    prev_status: &mut Option<NetworkStatus>,
 ) -> Result<bool> {
-    let res = is_online(nc_info, client).await;
+    let status = status_raw(client).await;
+    let res = status.is_connect();
 
-    if res {
-        tx.send(Command::NetworkConnect).await?;
-    } else {
-        tx.send(Command::NetworkDisconnect).await?;
+    if prev_status.as_ref() == Some(&status) {
+        return Ok(res);
     }
 
+    info!("network status: {:?} => {:?}", prev_status, status);
+    let com = match status {
+        NetworkStatus::Connect => Command::NetworkConnect,
+        NetworkStatus::Maintenance => Command::NetworkMaintenance,
+        NetworkStatus::Disconnect | NetworkStatus::Err(_) => Command::NetworkDisconnect,
+    };
+    tx.send(com).await?;
+    *prev_status = Some(status);
+
     Ok(res)
 }
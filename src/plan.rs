@@ -0,0 +1,146 @@
+use crate::local_listen::LocalEvent;
+use crate::nc_listen::NCEvent;
+use crate::*;
+use std::fmt;
+
+// What the sync engine would do. Used by the dry-run mode.
+// Paths are root relative with a head slash. ex) "/a/b.txt"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlanAction {
+    // server -> local
+    Download(String),
+    Mkdir(String),
+    // local -> server
+    Upload(String),
+    DeleteRemote(String),
+    MoveRemote(String, String),
+    // local only. `Delete` is only autostashed, `Stash` is kept in the stash.
+    Delete(String),
+    Stash(String),
+    Move(String, String),
+}
+
+impl fmt::Display for PlanAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlanAction::Download(p) => write!(f, "download        {}", p),
+            PlanAction::Mkdir(p) => write!(f, "mkdir           {}", p),
+            PlanAction::Upload(p) => write!(f, "upload          {}", p),
+            PlanAction::DeleteRemote(p) => write!(f, "delete (server) {}", p),
+            PlanAction::MoveRemote(from, to) => write!(f, "move (server)   {} -> {}", from, to),
+            PlanAction::Delete(p) => write!(f, "delete          {}", p),
+            PlanAction::Stash(p) => write!(f, "stash           {}", p),
+            PlanAction::Move(from, to) => write!(f, "move            {} -> {}", from, to),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SyncPlan {
+    pub actions: Vec<PlanAction>,
+}
+
+impl SyncPlan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, action: PlanAction) {
+        self.actions.push(action);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    // events from the server are applied to the local.
+    pub fn add_nc_events(&mut self, events: &[NCEvent]) {
+        for ev in events.iter() {
+            let action = match ev {
+                NCEvent::Create(p) | NCEvent::Modify(p) => PlanAction::Download(add_head_slash(p)),
+                NCEvent::Delete(p) => PlanAction::Delete(add_head_slash(p)),
+                NCEvent::Move(from, to) => {
+                    PlanAction::Move(add_head_slash(from), add_head_slash(to))
+                }
+            };
+            self.push(action);
+        }
+    }
+
+    // local events are applied to the server.
+    pub fn add_local_event(&mut self, ev: &LocalEvent) {
+        let action = match ev {
+            LocalEvent::Create(p) | LocalEvent::Modify(p) => PlanAction::Upload(path2str(p)),
+            LocalEvent::Delete(p) => PlanAction::DeleteRemote(path2str(p)),
+            LocalEvent::Move(from, to) => PlanAction::MoveRemote(path2str(from), path2str(to)),
+        };
+        self.push(action);
+    }
+
+    fn count<F>(&self, f: F) -> usize
+    where
+        F: Fn(&PlanAction) -> bool,
+    {
+        self.actions.iter().filter(|a| f(a)).count()
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "{} downloads, {} mkdirs, {} uploads, {} deletes, {} moves, {} stashes",
+            self.count(|a| matches!(a, PlanAction::Download(_))),
+            self.count(|a| matches!(a, PlanAction::Mkdir(_))),
+            self.count(|a| matches!(a, PlanAction::Upload(_))),
+            self.count(|a| matches!(a, PlanAction::Delete(_) | PlanAction::DeleteRemote(_))),
+            self.count(|a| matches!(a, PlanAction::Move(..) | PlanAction::MoveRemote(..))),
+            self.count(|a| matches!(a, PlanAction::Stash(_))),
+        )
+    }
+}
+
+impl fmt::Display for SyncPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "plan: nothing to do.");
+        }
+
+        write!(f, "plan: {}", self.summary())?;
+        for action in self.actions.iter() {
+            write!(f, "\n  {}", action)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn plan_test() {
+        let mut plan = SyncPlan::new();
+        assert_eq!(plan.to_string(), "plan: nothing to do.");
+
+        plan.add_nc_events(&[
+            NCEvent::Create("a/b.txt".to_string()),
+            NCEvent::Move("/c".to_string(), "/d".to_string()),
+        ]);
+        plan.add_local_event(&LocalEvent::Delete(PathBuf::from("e")));
+        plan.push(PlanAction::Stash("/f".to_string()));
+
+        assert_eq!(
+            plan.actions,
+            vec![
+                PlanAction::Download("/a/b.txt".to_string()),
+                PlanAction::Move("/c".to_string(), "/d".to_string()),
+                PlanAction::DeleteRemote("/e".to_string()),
+                PlanAction::Stash("/f".to_string()),
+            ]
+        );
+        assert_eq!(
+            plan.summary(),
+            "1 downloads, 0 mkdirs, 0 uploads, 1 deletes, 1 moves, 1 stashes"
+        );
+    }
+}
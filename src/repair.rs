@@ -1,15 +1,20 @@
 use crate::errors::NcsError::*;
-use crate::local_listen::{get_localpath, LocalEvent};
+use crate::history::{tree_etag, HistoryRecord, Source};
+use crate::local_listen::{deal_local_event, get_localpath, LocalEvent};
 use crate::meta::*;
+use crate::metrics::METRICS;
 use crate::nc_listen::*;
+use crate::plan::{PlanAction, SyncPlan};
+use crate::stash::DELETE_CAUSE;
+use crate::symlinks::LocalKind;
 use crate::*;
-use anyhow::Result;
+use anyhow::{Context, Result};
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use tokio::sync::mpsc;
 use tokio::time::{sleep, Duration};
 
@@ -48,35 +53,161 @@ impl<T: ModifiedPath> ModifiedPathVec for Vec<T> {
 //  this operation use local tree's etag to distinguish modified and not-modified. -> 実装する...?
 // hard repair: equal reset. delete .ncs file and all files in root and restart all process.
 
-// soft repair
-// return have_to_rerun.
-pub async fn soft_repair(
-    local_info: &LocalInfo,
+// Applies a change of the exclude rules without a restart. `old_rules` are the rules before the change.
+// Newly included entries are synced. Newly excluded ones are only dropped from the tree
+// and are not deleted on either side.
+pub async fn reconcile_excludes(
+    old_rules: &ExcludeRules,
+    tree: &mut Tree,
     nc_info: &NCInfo,
-    resource: &ArcResource,
-    local_events: Vec<LocalEvent>,
-    tx: mpsc::Sender<Command>,
+    local_info: &LocalInfo,
     nc2l_cancel_map: &mut HashMap<String, usize>,
     l2nc_cancel_set: &mut HashSet<NCEvent>,
-) -> Result<bool> {
-    let res;
-    let events;
-    {
-        let mut pr_ref = resource.lock().map_err(|_| LockError)?;
-        events = get_ncevents(nc_info, local_info, &mut pr_ref.nc_state).await?;
+) -> Result<()> {
+    let checker = &local_info.exc_checker;
 
-        res = update_and_download(
-            events.clone(),
-            &pr_ref.root,
+    let mut excluded = Vec::new();
+    collect_newly_excluded(tree, Tree::ROOT, checker, &mut excluded);
+    for path in excluded.iter() {
+        info!("[reconcile excludes] drop from the tree: {}", path);
+        tree.remove(path);
+    }
+
+    let nc_events = list_remote_paths(nc_info, local_info)
+        .await?
+        .into_iter()
+        .filter(|(path, is_dir)| !old_rules.judge_dir(path, *is_dir))
+        .map(|(path, _)| NCEvent::Create(path))
+        .collect::<Vec<_>>();
+    info!(
+        "[reconcile excludes] newly included remote entries: {}",
+        nc_events.len()
+    );
+    update_and_download(
+        nc_events,
+        tree,
+        nc_info,
+        local_info,
+        nc2l_cancel_map,
+        l2nc_cancel_set,
+        false,
+        &Source::repair("excludes"),
+    )
+    .await?;
+
+    let mut included = Vec::new();
+    collect_untracked_local(
+        Some(old_rules),
+        tree,
+        Path::new(""),
+        local_info,
+        &mut included,
+    )?;
+    info!(
+        "[reconcile excludes] newly included local entries: {}",
+        included.len()
+    );
+    for path in included {
+        // directories are uploaded with their contents.
+        let res = deal_local_event(
+            LocalEvent::Create(path),
+            tree,
             nc_info,
             local_info,
             nc2l_cancel_map,
             l2nc_cancel_set,
-            true,
         )
         .await;
+        if let Err(e) = res {
+            warn!("{:?}", e);
+        }
     }
 
+    Ok(())
+}
+
+fn collect_newly_excluded(
+    tree: &Tree,
+    id: EntryId,
+    checker: &ExcludeChecker,
+    excluded: &mut Vec<String>,
+) {
+    for child in tree.children(id) {
+        let (child_path, is_dir) = match (tree.path_of(child), tree.entry(child)) {
+            (Some(p), Some(e)) => (p, e.type_.is_dir()),
+            _ => continue,
+        };
+        if !checker.judge_dir(&child_path, is_dir) {
+            excluded.push(child_path);
+        } else if is_dir {
+            collect_newly_excluded(tree, child, checker, excluded);
+        }
+    }
+}
+
+// local entries which are included and not in the tree.
+// With `old_rules`, only the ones excluded by them. (newly included)
+fn collect_untracked_local(
+    old_rules: Option<&ExcludeRules>,
+    tree: &Tree,
+    dir: &Path,
+    local_info: &LocalInfo,
+    included: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let read_dir = match fs::read_dir(get_localpath(dir, local_info)) {
+        Ok(r) => r,
+        Err(_) => return Ok(()),
+    };
+
+    for child in read_dir.flatten() {
+        let path = dir.join(child.file_name());
+        let kind = local_info.local_kind(&child.path());
+        if !kind.exists() {
+            continue;
+        }
+        let is_dir = kind.is_dir();
+        if !local_info.exc_checker.judge_dir(&path, is_dir) {
+            continue;
+        }
+
+        if !tree.contains(&path2str(&path)) {
+            if !old_rules.is_some_and(|r| r.judge_dir(&path, is_dir)) {
+                included.push(path);
+            }
+        } else if is_dir {
+            collect_untracked_local(old_rules, tree, &path, local_info, included)?;
+        }
+    }
+
+    Ok(())
+}
+
+// soft repair
+// return have_to_rerun.
+pub async fn soft_repair(
+    local_info: &LocalInfo,
+    nc_info: &NCInfo,
+    resource: &mut PublicResource,
+    local_events: Vec<LocalEvent>,
+    tx: mpsc::Sender<Command>,
+    nc2l_cancel_map: &mut HashMap<String, usize>,
+    l2nc_cancel_set: &mut HashSet<NCEvent>,
+) -> Result<bool> {
+    METRICS.record_repair("soft");
+    let events = get_ncevents(nc_info, local_info, &mut resource.nc_state).await?;
+
+    let res = update_and_download(
+        events.clone(),
+        resource.tree_mut(),
+        nc_info,
+        local_info,
+        nc2l_cancel_map,
+        l2nc_cancel_set,
+        true,
+        &Source::repair("soft"),
+    )
+    .await;
+
     if let Err(e) = res {
         warn!("{:?}\nI'll try normal repair.", e);
         *nc2l_cancel_map = HashMap::new();
@@ -86,28 +217,27 @@ pub async fn soft_repair(
         return Ok(true);
     }
 
-    let pr_ref = resource.lock().map_err(|_| LockError)?;
+    let tree = resource.tree_mut();
 
     let mut download_list = Vec::new();
     check_exists_rec(
-        "",
-        &pr_ref.root,
+        tree,
+        Tree::ROOT,
         local_info,
         &mut download_list,
         nc2l_cancel_map,
     )?;
 
-    for w in download_list.into_iter() {
-        let entry = if let Some(v) = w.upgrade() {
-            v
-        } else {
-            continue;
-        };
-
-        let _ = nc_listen::download_file_with_check_etag(nc_info, local_info, &entry, true).await?;
+    for path in download_list.into_iter() {
+        download_with_history(nc_info, local_info, tree, &path, "soft").await?;
     }
 
-    let local_modified_path_vec = local_events.get_modified_path_vec();
+    let mut local_modified_path_vec = local_events.get_modified_path_vec();
+    for p in collect_changed_local(tree, local_info) {
+        if !local_modified_path_vec.contains(&p) {
+            local_modified_path_vec.push(p);
+        }
+    }
 
     debug!("local_events: {:?}", local_modified_path_vec);
 
@@ -117,7 +247,7 @@ pub async fn soft_repair(
             continue;
         }
         let p_str = path2str(&p);
-        let com = if Entry::get(&pr_ref.root, &p_str)?.is_some() {
+        let com = if tree.contains(&p_str) {
             Command::LocEvent(LocalEvent::Modify(p))
         } else {
             Command::LocEvent(LocalEvent::Create(p))
@@ -128,21 +258,54 @@ pub async fn soft_repair(
     Ok(false)
 }
 
+// files whose local mtime is not the synced one. They were changed while ncs was not running.
+fn collect_changed_local(tree: &Tree, local_info: &LocalInfo) -> Vec<PathBuf> {
+    tree.descendants(Tree::ROOT)
+        .into_iter()
+        .filter_map(|id| {
+            let entry = tree.entry(id)?;
+            if !entry.type_.is_file() || entry.status != EntryStatus::UpToDate {
+                return None;
+            }
+            let synced = entry.mtime?;
+            let path = PathBuf::from(tree.path_of(id)?);
+            let local = mtime::local_mtime(get_localpath(&path, local_info))?;
+            (local != synced).then_some(path)
+        })
+        .collect()
+}
+
+// the local files are taken as synced. Used when the tree is got from the server again.
+fn adopt_local_mtimes(tree: &mut Tree, local_info: &LocalInfo) {
+    for id in tree.descendants(Tree::ROOT) {
+        let path = match tree.path_of(id) {
+            Some(p) => PathBuf::from(p),
+            None => continue,
+        };
+        if let Some(entry) = tree.entry_mut(id) {
+            if entry.type_.is_file() {
+                entry.mtime = mtime::local_mtime(get_localpath(&path, local_info));
+            }
+        }
+    }
+}
+
 fn check_exists_rec(
-    ancestor: &str,
-    arc_entry: &ArcEntry,
+    tree: &mut Tree,
+    id: EntryId,
     local_info: &LocalInfo,
-    download_list: &mut Vec<WeakEntry>,
+    download_list: &mut Vec<String>,
     nc2l_cancel_map: &mut HashMap<String, usize>,
 ) -> Result<()> {
-    let weak_entry = Arc::downgrade(arc_entry);
-    let mut entry = arc_entry.lock().map_err(|_| LockError)?;
-    let name = entry.get_raw_name();
-    if !local_info.exc_checker.judge(&name) {
+    let path_s = tree.path_of(id).with_context(|| WeakUpgradeError)?;
+    let entry = tree.entry_mut(id).unwrap();
+    if !local_info
+        .exc_checker
+        .judge_dir(&path_s, entry.type_.is_dir())
+    {
         return Ok(());
     }
 
-    let path_s = format!("{}/{}", ancestor, name);
     let local_path = get_localpath(Path::new(&path_s), local_info);
 
     entry.status = EntryStatus::UpToDate;
@@ -157,25 +320,20 @@ fn check_exists_rec(
             }
 
             if local_path.is_file() {
-                fileope::remove_entry(&local_path, true, local_info)?;
+                fileope::remove_entry(&local_path, true, DELETE_CAUSE, local_info)?;
                 fileope::create_dir_all(&local_path)?;
                 have_to_cancel = true;
             }
 
-            let children = entry
-                .get_all_children()
-                .into_iter()
-                .filter_map(|c| c.upgrade());
-
-            for child in children {
-                check_exists_rec(&path_s, &child, local_info, download_list, nc2l_cancel_map)?;
+            for child in tree.children(id) {
+                check_exists_rec(tree, child, local_info, download_list, nc2l_cancel_map)?;
             }
         }
         EntryType::File { etag: _ } => {
             if !local_path.exists() {
                 entry.type_ = EntryType::File { etag: None };
                 entry.status = EntryStatus::NeedUpdate;
-                download_list.push(weak_entry);
+                download_list.push(path_s.clone());
                 have_to_cancel = true;
             }
         }
@@ -189,58 +347,113 @@ fn check_exists_rec(
     Ok(())
 }
 
+// rebuild: used when the saved state is lost. (see tree_store)
+// The tree is got from the server again and local files are not deleted.
+// Missing ones are downloaded, the ones on both sides are taken as the server version,
+// and local only entries are returned to be uploaded.
+pub async fn rebuild(
+    local_info: &LocalInfo,
+    nc_info: &NCInfo,
+) -> Result<(Tree, String, Vec<LocalEvent>)> {
+    METRICS.record_repair("rebuild");
+    let mut tree = from_nc_all(nc_info, local_info, "/").await?;
+    let latest_activity_id = get_latest_activity_id(local_info).await?;
+
+    // the watchers are not started yet. Nothing to cancel.
+    let mut download_list = Vec::new();
+    check_exists_rec(
+        &mut tree,
+        Tree::ROOT,
+        local_info,
+        &mut download_list,
+        &mut HashMap::new(),
+    )?;
+    for path in download_list.into_iter() {
+        download_with_history(nc_info, local_info, &mut tree, &path, "rebuild").await?;
+    }
+    adopt_local_mtimes(&mut tree, local_info);
+
+    let mut untracked = Vec::new();
+    collect_untracked_local(None, &tree, Path::new(""), local_info, &mut untracked)?;
+    info!("[rebuild] local only entries: {}", untracked.len());
+    let local_events = untracked.into_iter().map(LocalEvent::Create).collect();
+
+    Ok((tree, latest_activity_id, local_events))
+}
+
 // normal repair
 pub async fn normal_repair(
     local_info: &LocalInfo,
     nc_info: &NCInfo,
-    resource: &ArcResource,
+    resource: &mut PublicResource,
     events: Vec<NCEvent>,
 ) -> Result<()> {
-    let root_entry = from_nc_all(nc_info, local_info, "/").await?;
-    let latest_activity_id = get_latest_activity_id(nc_info, local_info).await?;
+    METRICS.record_repair("normal");
+    let mut tree = from_nc_all(nc_info, local_info, "/").await?;
+    let latest_activity_id = get_latest_activity_id(local_info).await?;
 
     let modified_path_vec = events.get_modified_path_vec();
 
     let mut download_list = modified_path_vec
         .into_iter()
-        .filter_map(|p| Entry::get(&root_entry, &p).ok().and_then(|w| w))
+        .map(|p| local_info.names.local_path(&tree, &p, None))
+        .filter(|p| tree.contains(p))
         .collect::<Vec<_>>();
 
-    choose_leave_dirfile_rec("", &root_entry, local_info, &mut download_list)?;
-
-    for w in download_list.into_iter() {
-        let entry = if let Some(v) = w.upgrade() {
-            v
-        } else {
-            continue;
-        };
+    let mut plan = SyncPlan::new();
+    choose_leave_dirfile_rec(
+        &mut tree,
+        Tree::ROOT,
+        local_info,
+        &mut download_list,
+        &mut plan,
+    )?;
+    apply_local_actions(&plan, local_info, &Source::repair("normal"))?;
 
-        let _ = nc_listen::download_file_with_check_etag(nc_info, local_info, &entry, true).await?;
+    for path in download_list.into_iter() {
+        download_with_history(nc_info, local_info, &mut tree, &path, "normal").await?;
     }
+    adopt_local_mtimes(&mut tree, local_info);
 
-    {
-        let mut resource_ref = resource.lock().map_err(|_| LockError)?;
-        resource_ref.root = root_entry;
-        resource_ref.nc_state = nc_listen::NCState { latest_activity_id };
-    }
+    resource.set_tree(tree);
+    resource.nc_state = nc_listen::NCState { latest_activity_id };
 
     Ok(())
 }
 
+// what `normal_repair` would do. Only the server tree is fetched, nothing is changed.
+pub async fn plan_normal_repair(local_info: &LocalInfo, nc_info: &NCInfo) -> Result<SyncPlan> {
+    let mut tree = from_nc_all(nc_info, local_info, "/").await?;
+
+    let mut plan = SyncPlan::new();
+    choose_leave_dirfile_rec(
+        &mut tree,
+        Tree::ROOT,
+        local_info,
+        &mut Vec::new(),
+        &mut plan,
+    )?;
+
+    Ok(plan)
+}
+
+// Only looks at the local. Changes are put into `plan` and applied by `apply_local_actions`.
 fn choose_leave_dirfile_rec(
-    ancestor: &str,
-    arc_entry: &ArcEntry,
+    tree: &mut Tree,
+    id: EntryId,
     local_info: &LocalInfo,
-    download_list: &mut Vec<WeakEntry>,
+    download_list: &mut Vec<String>,
+    plan: &mut SyncPlan,
 ) -> Result<()> {
-    let weak_entry = Arc::downgrade(arc_entry);
-    let mut entry = arc_entry.lock().map_err(|_| LockError)?;
-    let name = entry.get_raw_name();
-    if !local_info.exc_checker.judge(&name) {
+    let path_s = tree.path_of(id).with_context(|| WeakUpgradeError)?;
+    let entry = tree.entry_mut(id).unwrap();
+    if !local_info
+        .exc_checker
+        .judge_dir(&path_s, entry.type_.is_dir())
+    {
         return Ok(());
     }
 
-    let path_s = format!("{}/{}", ancestor, name);
     let local_path = get_localpath(Path::new(&path_s), local_info);
 
     entry.status = EntryStatus::UpToDate;
@@ -248,52 +461,52 @@ fn choose_leave_dirfile_rec(
     match entry.type_.clone() {
         EntryType::Directory => {
             if !local_path.exists() {
-                fileope::create_dir_all(&local_path)?;
-            }
-
-            if local_path.is_file() {
-                fileope::remove_entry(&local_path, true, local_info)?;
-                fileope::create_dir_all(&local_path)?;
-            }
-
-            // check unnecessary files and dirs
-            for child in fs::read_dir(&local_path)? {
-                let child = if let Ok(c) = child {
-                    c
-                } else {
-                    continue;
-                };
-
-                let child_path = child.path();
-                let c_name = if let Some(v) = child_path.file_name().map(|n| n.to_string_lossy()) {
-                    v.to_string()
-                } else {
-                    continue;
-                };
-
-                if !local_info.exc_checker.judge(&c_name) {
-                    continue;
-                }
-
-                if entry.get_child(&c_name).is_none() {
-                    fileope::remove_entry(&child_path, true, local_info)?;
+                plan.push(PlanAction::Mkdir(path_s.clone()));
+            } else if local_path.is_file() {
+                plan.push(PlanAction::Stash(path_s.clone()));
+                plan.push(PlanAction::Mkdir(path_s.clone()));
+            } else {
+                // check unnecessary files and dirs
+                for child in fs::read_dir(&local_path)? {
+                    let child = if let Ok(c) = child {
+                        c
+                    } else {
+                        continue;
+                    };
+
+                    let child_path = child.path();
+                    let c_name =
+                        if let Some(v) = child_path.file_name().map(|n| n.to_string_lossy()) {
+                            v.to_string()
+                        } else {
+                            continue;
+                        };
+
+                    if !local_info.exc_checker.judge(&child_path)
+                        || local_info.local_kind(&child_path) == LocalKind::Skip
+                    {
+                        continue;
+                    }
+
+                    if entry.get_child(&c_name).is_none() {
+                        plan.push(PlanAction::Stash(Tree::normalize_path(&format!(
+                            "{}/{}",
+                            path_s, c_name
+                        ))));
+                    }
                 }
             }
 
-            let children = entry
-                .get_all_children()
-                .into_iter()
-                .filter_map(|c| c.upgrade());
-
-            for child in children {
-                choose_leave_dirfile_rec(&path_s, &child, local_info, download_list)?;
+            for child in tree.children(id) {
+                choose_leave_dirfile_rec(tree, child, local_info, download_list, plan)?;
             }
         }
         EntryType::File { etag: _ } => {
             if !local_path.exists() {
                 entry.type_ = EntryType::File { etag: None };
                 entry.status = EntryStatus::NeedUpdate;
-                download_list.push(weak_entry);
+                download_list.push(path_s.clone());
+                plan.push(PlanAction::Download(path_s));
             }
         }
     }
@@ -301,15 +514,123 @@ fn choose_leave_dirfile_rec(
     Ok(())
 }
 
+// local side actions only. Downloads and uploads are done by the caller.
+fn apply_local_actions(plan: &SyncPlan, local_info: &LocalInfo, source: &Source) -> Result<()> {
+    for action in plan.actions.iter() {
+        let (p, name) = match action {
+            PlanAction::Mkdir(p) => {
+                fileope::create_dir_all(get_localpath(Path::new(p), local_info))?;
+                (p, "mkdir")
+            }
+            PlanAction::Stash(p) => {
+                let local_path = get_localpath(Path::new(p), local_info);
+                fileope::remove_entry(&local_path, true, DELETE_CAUSE, local_info)?;
+                (p, "delete")
+            }
+            PlanAction::Delete(p) => {
+                let local_path = get_localpath(Path::new(p), local_info);
+                fileope::remove_entry(&local_path, false, DELETE_CAUSE, local_info)?;
+                (p, "delete")
+            }
+            _ => continue,
+        };
+        local_info
+            .history
+            .append(HistoryRecord::new(p, source.clone(), name));
+    }
+
+    Ok(())
+}
+
+async fn download_with_history(
+    nc_info: &NCInfo,
+    local_info: &LocalInfo,
+    tree: &mut Tree,
+    path: &str,
+    kind: &str,
+) -> Result<()> {
+    let old_etag = tree_etag(tree, path);
+    let downloaded =
+        nc_listen::download_file_with_check_etag(nc_info, local_info, tree, path, true).await?;
+    if let Some(path) = downloaded {
+        local_info.history.append(
+            HistoryRecord::new(&path, Source::repair(kind), "download")
+                .etags(old_etag, tree_etag(tree, &path)),
+        );
+    }
+
+    Ok(())
+}
+
 // hard repair
 pub fn all_delete(local_info: &LocalInfo) -> Result<()> {
+    METRICS.record_repair("hard");
+    let entries = root_items(local_info)?;
+
+    fileope::remove_items(&entries, false, local_info)?;
+    // the history itself is in `.ncs` and starts again from here.
+    for p in entries.iter() {
+        let name = p.file_name().map(|n| n.to_string_lossy().to_string());
+        if let Some(name) = name.filter(|n| n != ".ncs") {
+            local_info
+                .history
+                .append(HistoryRecord::new(&name, Source::repair("hard"), "delete"));
+        }
+    }
+
+    Ok(())
+}
+
+fn root_items(local_info: &LocalInfo) -> Result<Vec<PathBuf>> {
     let root_entry = Path::new(&local_info.root_path);
     let entries = fs::read_dir(root_entry)?
-        .into_iter()
         .filter_map(|e| e.map(|e| e.path()).ok())
         .collect::<Vec<_>>();
 
-    fileope::remove_items(&entries, false, local_info)?;
+    Ok(entries)
+}
 
-    Ok(())
+// what a hard repair would do: everything in the root (.ncs included) is deleted,
+// then the whole tree is got again like at the first start.
+pub async fn plan_hard_repair(local_info: &LocalInfo, nc_info: &NCInfo) -> Result<SyncPlan> {
+    let mut plan = SyncPlan::new();
+    for p in root_items(local_info)? {
+        let name = p.file_name().map(|n| n.to_string_lossy().to_string());
+        if let Some(name) = name {
+            plan.push(PlanAction::Delete(add_head_slash(&name)));
+        }
+    }
+
+    let init_plan = plan_init(local_info, nc_info).await?;
+    plan.actions.extend(init_plan.actions);
+
+    Ok(plan)
+}
+
+// what the first start (`init_local_entries`) would do.
+pub async fn plan_init(local_info: &LocalInfo, nc_info: &NCInfo) -> Result<SyncPlan> {
+    let tree = from_nc_all(nc_info, local_info, "/").await?;
+
+    let mut plan = SyncPlan::new();
+    for child in tree.children(Tree::ROOT) {
+        plan_init_rec(&tree, child, &mut plan);
+    }
+
+    Ok(plan)
+}
+
+fn plan_init_rec(tree: &Tree, id: EntryId, plan: &mut SyncPlan) {
+    let (path_s, entry) = match (tree.path_of(id), tree.entry(id)) {
+        (Some(p), Some(e)) => (p, e),
+        _ => return,
+    };
+
+    if entry.type_.is_dir() {
+        plan.push(PlanAction::Mkdir(path_s));
+        for child in tree.children(id) {
+            plan_init_rec(tree, child, plan);
+        }
+    } else {
+        plan.push(PlanAction::Download(path_s));
+    }
 }
@@ -0,0 +1,726 @@
+use crate::errors::NcsError::*;
+use crate::meta::LocalInfo;
+use crate::*;
+use anyhow::Result;
+use chrono::prelude::*;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StashReason {
+    // overwritten by a download from the server.
+    DownloadOverwrite,
+    Delete,
+    // overwritten by a move on the server.
+    MoveOverwrite,
+    // overwritten by `stash restore`.
+    RestoreOverwrite,
+}
+
+impl StashReason {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Self::DownloadOverwrite => "overwritten by a download",
+            Self::Delete => "deleted",
+            Self::MoveOverwrite => "overwritten by a move",
+            Self::RestoreOverwrite => "overwritten by a restore",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StashKind {
+    Stash,
+    Autostash,
+}
+
+// why and from which server version a local copy is stashed. Passed to fileope.
+#[derive(Debug, Clone, Copy)]
+pub struct StashCause<'a> {
+    pub reason: StashReason,
+    pub etag: Option<&'a str>,
+}
+
+impl<'a> StashCause<'a> {
+    pub fn new(reason: StashReason, etag: Option<&'a str>) -> Self {
+        Self { reason, etag }
+    }
+}
+
+// deleted without a known server version.
+pub const DELETE_CAUSE: StashCause<'static> = StashCause {
+    reason: StashReason::Delete,
+    etag: None,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StashItem {
+    pub id: u64,
+    // relative to the local root. ex) "/docs/README.md"
+    pub original_path: String,
+    pub reason: StashReason,
+    pub kind: StashKind,
+    // unix time in milliseconds.
+    pub timestamp: i64,
+    // etag of the server version the local copy came from, if known.
+    pub etag: Option<String>,
+    // relative to the meta dir. ex) "autostash/20211018/README_20211018123456789.md"
+    pub stash_path: String,
+    pub is_dir: bool,
+    #[serde(default)]
+    pub size: u64,
+    // sha256 of the content. None for directories.
+    #[serde(default)]
+    pub hash: Option<String>,
+}
+
+impl StashItem {
+    pub fn datetime(&self) -> DateTime<Local> {
+        Local.timestamp_millis(self.timestamp)
+    }
+
+    pub fn detail(&self) -> String {
+        format!(
+            "id: {}\npath: {}{}\nreason: {:?}\nkind: {:?}\ntime: {}\netag: {}\nstashed at: {}",
+            self.id,
+            self.original_path,
+            if self.is_dir { "/" } else { "" },
+            self.reason,
+            self.kind,
+            self.datetime().format("%Y-%m-%d %H:%M:%S%.3f"),
+            self.etag.as_deref().unwrap_or("-"),
+            self.stash_path
+        )
+    }
+}
+
+impl fmt::Display for StashItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>5}  {}  {:?}/{:?}  {}{}",
+            self.id,
+            self.datetime().format("%Y-%m-%d %H:%M:%S"),
+            self.kind,
+            self.reason,
+            self.original_path,
+            if self.is_dir { "/" } else { "" }
+        )
+    }
+}
+
+// Applied to autostash copies only. Copies made by `stash` are kept until removed by hand.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetentionPolicy {
+    pub max_age_days: Option<u32>,
+    pub max_bytes: Option<u64>,
+    // per original path.
+    pub max_versions: Option<usize>,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            max_age_days: Some(7),
+            max_bytes: None,
+            max_versions: None,
+        }
+    }
+}
+
+impl fmt::Display for RetentionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |v: Option<String>| v.unwrap_or_else(|| "unlimited".to_string());
+        write!(
+            f,
+            "max age: {} days, max size: {} bytes, max versions: {}",
+            show(self.max_age_days.map(|v| v.to_string())),
+            show(self.max_bytes.map(|v| v.to_string())),
+            show(self.max_versions.map(|v| v.to_string()))
+        )
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CleanupReport {
+    pub removed: usize,
+    pub freed_bytes: u64,
+}
+
+impl fmt::Display for CleanupReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "removed {} copies, freed {} bytes",
+            self.removed, self.freed_bytes
+        )
+    }
+}
+
+// what to do with a new copy whose content is already stashed.
+pub(crate) enum Duplicate {
+    // the newest copy of the same path has the same content. Nothing to do.
+    Latest,
+    // another copy has the same content. The new item refers to that file.
+    Stored(PathBuf),
+    None,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct StashIndexData {
+    next_id: u64,
+    items: Vec<StashItem>,
+}
+
+// `.ncs/stash.json`. Shared by the clones of LocalInfo.
+#[derive(Clone, Debug)]
+pub struct StashIndex {
+    index_file: PathBuf,
+    data: Arc<Mutex<StashIndexData>>,
+    // autostashes since the start. (see cleanup_if_due)
+    autostashed: Arc<AtomicUsize>,
+}
+
+impl StashIndex {
+    pub fn load<P: AsRef<Path>>(index_file: P) -> Self {
+        let index_file = index_file.as_ref().to_path_buf();
+        let data = match fs::read_to_string(&index_file) {
+            Ok(s) => serde_json::from_str(&s).unwrap_or_else(|e| {
+                warn!(
+                    "{:?} is broken. start with an empty index. | {:?}",
+                    index_file, e
+                );
+                StashIndexData::default()
+            }),
+            Err(_) => StashIndexData::default(),
+        };
+
+        Self {
+            index_file,
+            data: Arc::new(Mutex::new(data)),
+            autostashed: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn save(&self, data: &StashIndexData) -> Result<()> {
+        let j = serde_json::to_string(data)?;
+        meta::write_atomic(&self.index_file, format!("{}\n", j).as_bytes())
+    }
+
+    fn add(&self, mut item: StashItem) -> Result<u64> {
+        let mut data = self.data.lock().map_err(|_| LockError)?;
+        item.id = data.next_id;
+        data.next_id += 1;
+        data.items.push(item);
+        self.save(&data)?;
+
+        Ok(data.next_id - 1)
+    }
+
+    // oldest first.
+    pub fn list(&self) -> Result<Vec<StashItem>> {
+        let data = self.data.lock().map_err(|_| LockError)?;
+        Ok(data.items.clone())
+    }
+
+    pub fn get(&self, id: u64) -> Result<Option<StashItem>> {
+        let data = self.data.lock().map_err(|_| LockError)?;
+        Ok(data.items.iter().find(|item| item.id == id).cloned())
+    }
+
+    // the meta dir. `stash_path`s are relative to it.
+    fn base_dir(&self) -> &Path {
+        self.index_file.parent().unwrap_or_else(|| Path::new(""))
+    }
+
+    fn file_of(&self, stash_path: &str) -> PathBuf {
+        file_in(self.base_dir(), stash_path)
+    }
+
+    fn find_duplicate(
+        &self,
+        original_path: &str,
+        kind: StashKind,
+        hash: &str,
+    ) -> Result<Duplicate> {
+        let data = self.data.lock().map_err(|_| LockError)?;
+        let same_kind = || data.items.iter().rev().filter(|item| item.kind == kind);
+
+        let latest = same_kind().find(|item| item.original_path == original_path);
+        if let Some(latest) = latest {
+            if latest.hash.as_deref() == Some(hash) && self.file_of(&latest.stash_path).exists() {
+                return Ok(Duplicate::Latest);
+            }
+        }
+
+        let stored = same_kind()
+            .filter(|item| item.hash.as_deref() == Some(hash))
+            .map(|item| self.file_of(&item.stash_path))
+            .find(|p| p.exists());
+
+        Ok(stored.map(Duplicate::Stored).unwrap_or(Duplicate::None))
+    }
+
+    // Removes the items and their files. A file shared by other items is kept.
+    fn remove(&self, ids: &HashSet<u64>) -> Result<CleanupReport> {
+        let mut report = CleanupReport::default();
+        if ids.is_empty() {
+            return Ok(report);
+        }
+
+        let mut data = self.data.lock().map_err(|_| LockError)?;
+        let (removed, kept): (Vec<_>, Vec<_>) = data
+            .items
+            .drain(..)
+            .partition(|item| ids.contains(&item.id));
+        data.items = kept;
+        self.save(&data)?;
+
+        let still_used = data
+            .items
+            .iter()
+            .map(|item| item.stash_path.as_str())
+            .collect::<HashSet<_>>();
+        let mut done = HashSet::new();
+        for item in removed.iter() {
+            report.removed += 1;
+            if still_used.contains(item.stash_path.as_str()) || !done.insert(&item.stash_path) {
+                continue;
+            }
+
+            let p = self.file_of(&item.stash_path);
+            let res = if p.is_dir() {
+                fs::remove_dir_all(&p)
+            } else {
+                fs::remove_file(&p)
+            };
+            match res {
+                Ok(()) => report.freed_bytes += item.size,
+                Err(e) => debug!("{:?} | {:?}", p, e),
+            }
+            // the date folder of autostash. Fails unless it is empty.
+            if let Some(parent) = p.parent() {
+                let _ = fs::remove_dir(parent);
+            }
+        }
+
+        Ok(report)
+    }
+
+    // the files of the items. Several items can share one. (deduplicated)
+    fn referenced_paths(&self) -> Result<HashSet<PathBuf>> {
+        let data = self.data.lock().map_err(|_| LockError)?;
+        Ok(data
+            .items
+            .iter()
+            .map(|item| self.file_of(&item.stash_path))
+            .collect())
+    }
+
+    // drop the items whose files were removed from outside of the index.
+    fn prune_missing(&self) -> Result<usize> {
+        let mut data = self.data.lock().map_err(|_| LockError)?;
+        let before = data.items.len();
+        let base_dir = self.base_dir().to_path_buf();
+        data.items
+            .retain(|item| file_in(&base_dir, &item.stash_path).exists());
+        let pruned = before - data.items.len();
+        if pruned > 0 {
+            self.save(&data)?;
+        }
+
+        Ok(pruned)
+    }
+}
+
+// Older indexes have `stash_path`s with a head slash. ex) "/autostash/..."
+fn file_in(base_dir: &Path, stash_path: &str) -> PathBuf {
+    base_dir.join(stash_path.trim_start_matches('/'))
+}
+
+// ids of the autostash items which are out of the policy. `now` is unix time in milliseconds.
+fn select_expired(items: &[StashItem], policy: &RetentionPolicy, now: i64) -> HashSet<u64> {
+    let mut expired = HashSet::new();
+
+    // newest first.
+    let mut autostashed = items
+        .iter()
+        .filter(|item| item.kind == StashKind::Autostash)
+        .collect::<Vec<_>>();
+    autostashed.sort_by_key(|item| std::cmp::Reverse(item.timestamp));
+
+    if let Some(days) = policy.max_age_days {
+        let limit = now - i64::from(days) * 24 * 60 * 60 * 1000;
+        for item in autostashed.iter().filter(|item| item.timestamp < limit) {
+            expired.insert(item.id);
+        }
+    }
+
+    if let Some(max_versions) = policy.max_versions {
+        let mut counts = HashMap::new();
+        for item in autostashed.iter() {
+            if expired.contains(&item.id) {
+                continue;
+            }
+            let count = counts.entry(item.original_path.as_str()).or_insert(0);
+            *count += 1;
+            if *count > max_versions {
+                expired.insert(item.id);
+            }
+        }
+    }
+
+    if let Some(max_bytes) = policy.max_bytes {
+        // a file shared by deduplicated items is counted once.
+        let mut refs: HashMap<&str, (usize, u64)> = HashMap::new();
+        for item in autostashed
+            .iter()
+            .filter(|item| !expired.contains(&item.id))
+        {
+            let r = refs
+                .entry(item.stash_path.as_str())
+                .or_insert((0, item.size));
+            r.0 += 1;
+        }
+        let mut total = refs.values().map(|(_, size)| size).sum::<u64>();
+
+        for item in autostashed.iter().rev() {
+            if total <= max_bytes {
+                break;
+            }
+            if !expired.insert(item.id) {
+                continue;
+            }
+            if let Some(r) = refs.get_mut(item.stash_path.as_str()) {
+                r.0 -= 1;
+                if r.0 == 0 {
+                    total -= r.1;
+                }
+            }
+        }
+    }
+
+    expired
+}
+
+// `cleanup` reads the whole stash. It's run once in this many autostashes.
+const CLEANUP_INTERVAL: usize = 20;
+
+// called after every autostash. The first one and then every `CLEANUP_INTERVAL`th run `cleanup`.
+pub fn cleanup_if_due(local_info: &LocalInfo) -> Result<Option<CleanupReport>> {
+    let count = local_info
+        .stash_index
+        .autostashed
+        .fetch_add(1, Ordering::SeqCst);
+    if count.rem_euclid(CLEANUP_INTERVAL) > 0 {
+        return Ok(None);
+    }
+
+    cleanup(local_info).map(Some)
+}
+
+// Enforce the retention policy.
+pub fn cleanup(local_info: &LocalInfo) -> Result<CleanupReport> {
+    let index = &local_info.stash_index;
+    let policy = &local_info.retention_policy;
+
+    let expired = select_expired(&index.list()?, policy, Local::now().timestamp_millis());
+    let mut report = index.remove(&expired)?;
+
+    if let Some(days) = policy.max_age_days {
+        let legacy = cleanup_autostash_folders(local_info, days)?;
+        report.freed_bytes += legacy.freed_bytes;
+        report.removed += legacy.removed;
+    }
+    index.prune_missing()?;
+
+    if report.removed > 0 {
+        info!("stash cleanup: {}", report);
+    }
+
+    Ok(report)
+}
+
+// Date folders of autostash older than `days`. Copies made before the index existed live only here.
+// A copy in them can still be the file of a newer item (deduplicated), so only the ones
+// the index doesn't refer to are removed.
+fn cleanup_autostash_folders(local_info: &LocalInfo, days: u32) -> Result<CleanupReport> {
+    #[allow(non_upper_case_globals)]
+    static re: Lazy<Regex> = Lazy::new(|| Regex::new(r"^\d{8}$").unwrap());
+
+    let mut report = CleanupReport::default();
+    let referenced = local_info.stash_index.referenced_paths()?;
+
+    let autostashpath_name = local_info.get_autostashpath_name();
+    let stash_folder = Path::new(&autostashpath_name);
+    if !stash_folder.exists() {
+        return Ok(report);
+    }
+
+    for entry in stash_folder.read_dir()? {
+        let path = entry?.path();
+
+        if !path.is_dir() {
+            continue;
+        }
+
+        let f_name = match path.file_name() {
+            Some(n) => n.to_string_lossy().to_string(),
+            None => continue,
+        };
+        if !re.is_match(&f_name) {
+            continue;
+        }
+        let d = match NaiveDate::parse_from_str(&f_name, "%Y%m%d") {
+            Ok(d) => d,
+            Err(_) => continue,
+        };
+
+        if Local::today().naive_local() - d <= chrono::Duration::days(i64::from(days)) {
+            continue;
+        }
+
+        for child in fs::read_dir(&path)? {
+            let child = child?.path();
+            if referenced.contains(&child) {
+                continue;
+            }
+            let size = if child.is_dir() {
+                fs_extra::dir::get_size(&child).unwrap_or(0)
+            } else {
+                fs::metadata(&child).map(|m| m.len()).unwrap_or(0)
+            };
+            let res = if child.is_dir() {
+                fs::remove_dir_all(&child)
+            } else {
+                fs::remove_file(&child)
+            };
+            match res {
+                Ok(()) => {
+                    report.removed += 1;
+                    report.freed_bytes += size;
+                }
+                Err(e) => debug!("{:?} | {:?}", child, e),
+            }
+        }
+        // kept if a referenced copy is left.
+        let _ = fs::remove_dir(&path);
+    }
+
+    Ok(report)
+}
+
+pub(crate) fn content_hash(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut f = fs::File::open(path)?;
+    io::copy(&mut f, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub(crate) fn find_duplicate(
+    local_info: &LocalInfo,
+    original: &Path,
+    kind: StashKind,
+    hash: &str,
+) -> Result<Duplicate> {
+    let original_path = original_relpath(local_info, original)?;
+    local_info
+        .stash_index
+        .find_duplicate(&original_path, kind, hash)
+}
+
+fn relative_path(path: &Path, base: &Path) -> Option<String> {
+    path.strip_prefix(base)
+        .ok()
+        .map(|p| add_head_slash(&path2str(p)))
+}
+
+pub(crate) fn original_relpath(local_info: &LocalInfo, original: &Path) -> Result<String> {
+    relative_path(original, Path::new(&local_info.root_path))
+        .or_else(|| relative_path(original, &local_info.root_path_cano))
+        .ok_or_else(|| InvalidPathError(format!("{:?} is out of the root.", original)).into())
+}
+
+// called by fileope after a copy is made into the stash.
+pub(crate) fn record(
+    local_info: &LocalInfo,
+    original: &Path,
+    stashed: &Path,
+    kind: StashKind,
+    cause: StashCause,
+    hash: Option<String>,
+) -> Result<u64> {
+    let original_path = original_relpath(local_info, original)?;
+    let metadir = local_info.get_metadir_name();
+    let stash_path = stashed
+        .strip_prefix(&metadir)
+        .map(|p| path2str(p).trim_start_matches('/').to_string())
+        .unwrap_or_else(|_| path2str(stashed));
+
+    let item = StashItem {
+        id: 0,
+        original_path,
+        reason: cause.reason,
+        kind,
+        timestamp: Local::now().timestamp_millis(),
+        etag: cause.etag.map(str::to_string),
+        stash_path,
+        is_dir: stashed.is_dir(),
+        size: if stashed.is_dir() {
+            fs_extra::dir::get_size(stashed).unwrap_or(0)
+        } else {
+            fs::metadata(stashed).map(|m| m.len()).unwrap_or(0)
+        },
+        hash,
+    };
+
+    local_info.stash_index.add(item)
+}
+
+pub fn stashed_path(item: &StashItem, local_info: &LocalInfo) -> PathBuf {
+    file_in(Path::new(&local_info.get_metadir_name()), &item.stash_path)
+}
+
+// Put the item back at its original path. The watcher picks it up and it is synced as usual.
+pub fn restore(local_info: &LocalInfo, id: u64) -> Result<StashItem> {
+    let item = local_info
+        .stash_index
+        .get(id)?
+        .ok_or_else(|| InvalidPathError(format!("No such stash item: {}", id)))?;
+
+    let from_path = stashed_path(&item, local_info);
+    if !from_path.exists() {
+        return Err(InvalidPathError(format!("{:?} is already removed.", from_path)).into());
+    }
+    let to_path = local_listen::get_localpath(Path::new(&item.original_path), local_info);
+    fileope::restore_from_stash(&from_path, &to_path, local_info)?;
+
+    info!("restored from stash: {}", item);
+
+    Ok(item)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(id: u64, path: &str, stash_path: &str, timestamp: i64, size: u64) -> StashItem {
+        StashItem {
+            id,
+            original_path: path.to_string(),
+            reason: StashReason::DownloadOverwrite,
+            kind: StashKind::Autostash,
+            timestamp,
+            etag: None,
+            stash_path: stash_path.to_string(),
+            is_dir: false,
+            size,
+            hash: None,
+        }
+    }
+
+    #[test]
+    fn cleanup_keeps_shared_copy_test() {
+        let root =
+            std::env::temp_dir().join(format!("ncs_stash_cleanup_test_{}", std::process::id()));
+        fs::create_dir_all(root.join(".ncs")).unwrap();
+        let nc_info = meta::NCInfo::new(
+            "user".to_string(),
+            "pass".to_string(),
+            "https://example.com/".to_string(),
+        );
+        let client = nc_client::NcClient::new(&nc_info, reqwest::Client::new());
+        let local_info = LocalInfo::new(path2str(&root), client).unwrap();
+        let cause = StashCause::new(StashReason::DownloadOverwrite, None);
+        let save = |name: &str| {
+            let p = path2str(&root.join(name));
+            fs::write(&p, b"same").unwrap();
+            fileope::save_file(&mut &b"new"[..], &p, false, cause, &local_info).unwrap();
+        };
+
+        save("a.txt");
+        // the first copy is made long ago.
+        let today = local_info.get_autostashpath_name_with_date();
+        let old = format!("{}/20000101", local_info.get_autostashpath_name());
+        fs::rename(&today, &old).unwrap();
+        {
+            let mut data = local_info.stash_index.data.lock().unwrap();
+            let first = &mut data.items[0];
+            first.stash_path = first.stash_path.replace(
+                &today[local_info.get_metadir_name().len()..],
+                "autostash/20000101",
+            );
+            first.timestamp = 0;
+        }
+
+        // the same content. It refers to the old copy.
+        save("b.txt");
+        let items = local_info.stash_index.list().unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].stash_path, items[1].stash_path);
+
+        cleanup(&local_info).unwrap();
+        let items = local_info.stash_index.list().unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].original_path, "/b.txt");
+        restore(&local_info, items[0].id).unwrap();
+        assert_eq!(fs::read(root.join("b.txt")).unwrap(), b"same");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn select_expired_test() {
+        const DAY: i64 = 24 * 60 * 60 * 1000;
+        let now = 10 * DAY;
+        let items = vec![
+            item(0, "/a.txt", "autostash/a_0.txt", now - 8 * DAY, 100),
+            item(1, "/a.txt", "autostash/a_1.txt", now - 3 * DAY, 100),
+            item(2, "/a.txt", "autostash/a_2.txt", now - 2 * DAY, 100),
+            item(3, "/a.txt", "autostash/a_3.txt", now - DAY, 100),
+            // deduplicated: 4 and 5 share a file.
+            item(4, "/b.txt", "autostash/b_4.txt", now - 2 * DAY, 300),
+            item(5, "/c/b.txt", "autostash/b_4.txt", now - DAY, 300),
+        ];
+
+        let policy = RetentionPolicy::default();
+        let expired = select_expired(&items, &policy, now);
+        assert_eq!(expired, [0].iter().copied().collect());
+
+        let policy = RetentionPolicy {
+            max_versions: Some(2),
+            ..Default::default()
+        };
+        let expired = select_expired(&items, &policy, now);
+        assert_eq!(expired, [0, 1].iter().copied().collect());
+
+        // 200 (a_2, a_3) + 300 (b_4) = 500 after removing the old ones.
+        let policy = RetentionPolicy {
+            max_age_days: None,
+            max_bytes: Some(500),
+            max_versions: None,
+        };
+        let expired = select_expired(&items, &policy, now);
+        assert_eq!(expired, [0, 1].iter().copied().collect());
+
+        let policy = RetentionPolicy {
+            max_age_days: None,
+            max_bytes: Some(200),
+            max_versions: None,
+        };
+        let expired = select_expired(&items, &policy, now);
+        assert_eq!(expired, [0, 1, 2, 4, 5].iter().copied().collect());
+    }
+}
@@ -0,0 +1,180 @@
+use crate::failures::{Failure, FailureRegistry, FailureReport};
+use crate::network::NetworkStatus;
+use crate::*;
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncState {
+    Online,
+    Offline,
+    // some entries or local events are waiting.
+    Syncing,
+    Error,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct QueueLengths {
+    pub offline_local_events: usize,
+    pub held_deletes: usize,
+    pub nc2l_cancel: usize,
+    pub l2nc_cancel: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct EntryReport {
+    pub path: String,
+    pub is_dir: bool,
+    pub status: EntryStatus,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SyncStatus {
+    pub state: SyncState,
+    pub network: &'static str,
+    pub queues: QueueLengths,
+    pub last_activity_id: String,
+    // RFC 3339
+    pub last_sync: Option<String>,
+    pub last_error: Option<String>,
+    // only entries in NeedUpdate or Error. sorted by path.
+    pub entries: Vec<EntryReport>,
+    // failed operations waiting for a retry or flagged for the user.
+    pub failures: Vec<FailureReport>,
+}
+
+// results of the sync operations in the main loop.
+#[derive(Debug, Default)]
+pub struct SyncRecord {
+    pub last_sync: Option<DateTime<Local>>,
+    pub last_error: Option<String>,
+}
+
+impl SyncRecord {
+    pub fn record<T>(&mut self, res: &Result<T>) {
+        match res {
+            Ok(_) => {
+                self.last_sync = Some(Local::now());
+                self.last_error = None;
+            }
+            Err(e) => self.last_error = Some(format!("{:#}", e)),
+        }
+    }
+}
+
+impl SyncStatus {
+    pub fn new(
+        network: &NetworkStatus,
+        queues: QueueLengths,
+        last_activity_id: String,
+        record: &SyncRecord,
+        tree: &Tree,
+        failures: &[Failure],
+    ) -> Result<Self> {
+        let entries = collect_unsynced(tree);
+
+        let state = if !network.is_connect() {
+            SyncState::Offline
+        } else if record.last_error.is_some()
+            || entries.iter().any(|e| e.status == EntryStatus::Error)
+            || failures.iter().any(|f| f.flagged)
+        {
+            SyncState::Error
+        } else if !entries.is_empty() || queues.offline_local_events > 0 || !failures.is_empty() {
+            SyncState::Syncing
+        } else {
+            SyncState::Online
+        };
+
+        let network = match network {
+            NetworkStatus::Connect => "connect",
+            NetworkStatus::Maintenance => "maintenance",
+            NetworkStatus::Disconnect => "disconnect",
+            NetworkStatus::Err(_) => "error",
+        };
+
+        Ok(Self {
+            state,
+            network,
+            queues,
+            last_activity_id,
+            last_sync: record.last_sync.map(|dt| dt.to_rfc3339()),
+            last_error: record.last_error.clone(),
+            entries,
+            failures: failures.iter().map(FailureReport::from).collect(),
+        })
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+// the reply of `status`.
+pub fn report(
+    network: &NetworkStatus,
+    queues: QueueLengths,
+    last_activity_id: String,
+    record: &SyncRecord,
+    tree: &Tree,
+    failures: &FailureRegistry,
+) -> Result<String> {
+    let failures = failures.list()?;
+    SyncStatus::new(network, queues, last_activity_id, record, tree, &failures)?.to_json()
+}
+
+// sorted by path.
+fn collect_unsynced(tree: &Tree) -> Vec<EntryReport> {
+    tree.descendants(Tree::ROOT)
+        .into_iter()
+        .skip(1)
+        .filter_map(|id| {
+            let entry = tree.entry(id)?;
+            if entry.status == EntryStatus::UpToDate {
+                return None;
+            }
+            Some(EntryReport {
+                path: tree.path_of(id)?,
+                is_dir: entry.type_.is_dir(),
+                status: entry.status,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_test() {
+        let mut tree = Tree::new();
+        for name in ["b", "a"] {
+            let mut dir = Entry::new(name.to_string(), EntryType::Directory);
+            dir.status = EntryStatus::UpToDate;
+            let dir = tree.add_child(Tree::ROOT, dir).unwrap();
+            let file = Entry::new("f".to_string(), EntryType::File { etag: None });
+            tree.add_child(dir, file).unwrap();
+        }
+
+        let status = SyncStatus::new(
+            &NetworkStatus::Connect,
+            QueueLengths::default(),
+            "1".to_string(),
+            &SyncRecord::default(),
+            &tree,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(status.state, SyncState::Syncing);
+        let paths = status
+            .entries
+            .iter()
+            .map(|e| e.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec!["/a/f", "/b/f"]);
+        assert!(status.to_json().unwrap().contains("\"need_update\""));
+    }
+}
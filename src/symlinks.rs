@@ -0,0 +1,224 @@
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// the first line of a link-descriptor file. The second one is the target.
+const DESCRIPTOR_HEADER: &str = "ncs-symlink\n";
+const MAX_DESCRIPTOR_LEN: usize = 4096;
+
+// How symlinks in the local root are synced.
+// The watcher and the repair scanners look at local entries through `classify`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    #[default]
+    Ignore,
+    // synced as what they point to, if it's in the root.
+    Follow,
+    // uploaded as a small file with the target, and made a symlink again on download.
+    Descriptor,
+}
+
+impl fmt::Display for SymlinkPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ignore => write!(f, "ignore"),
+            Self::Follow => write!(f, "follow"),
+            Self::Descriptor => write!(f, "link"),
+        }
+    }
+}
+
+// what a local path is to the sync.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LocalKind {
+    Missing,
+    File,
+    Dir,
+    // a symlink uploaded as a link-descriptor file.
+    Link(PathBuf),
+    // not synced. An ignored symlink, a symlink out of the root or a loop.
+    Skip,
+}
+
+impl LocalKind {
+    pub fn is_file(&self) -> bool {
+        matches!(self, Self::File | Self::Link(_))
+    }
+
+    pub fn is_dir(&self) -> bool {
+        matches!(self, Self::Dir)
+    }
+
+    pub fn exists(&self) -> bool {
+        !matches!(self, Self::Missing | Self::Skip)
+    }
+}
+
+impl SymlinkPolicy {
+    // `path` is a local path under `root`. `root_cano` is the canonical `root`.
+    pub fn classify(&self, path: &Path, root: &Path, root_cano: &Path) -> LocalKind {
+        let meta = match fs::symlink_metadata(path) {
+            Ok(m) => m,
+            Err(_) => return LocalKind::Missing,
+        };
+        if !meta.file_type().is_symlink() {
+            return if meta.is_dir() {
+                LocalKind::Dir
+            } else {
+                LocalKind::File
+            };
+        }
+
+        match self {
+            Self::Ignore => {
+                debug!("{:?}: symlink. ignored.", path);
+                LocalKind::Skip
+            }
+            Self::Descriptor => match fs::read_link(path) {
+                Ok(target) => LocalKind::Link(target),
+                Err(_) => LocalKind::Skip,
+            },
+            Self::Follow => {
+                let target = match path.canonicalize() {
+                    Ok(t) => t,
+                    Err(_) => {
+                        info!("{:?}: broken symlink. skipped.", path);
+                        return LocalKind::Skip;
+                    }
+                };
+                if !target.starts_with(root_cano) {
+                    info!(
+                        "{:?}: points out of the root ({:?}). skipped.",
+                        path, target
+                    );
+                    return LocalKind::Skip;
+                }
+                if !target.is_dir() {
+                    return LocalKind::File;
+                }
+                if is_loop(path, root, root_cano, &target) {
+                    warn!("{:?}: symlink loop to {:?}. skipped.", path, target);
+                    return LocalKind::Skip;
+                }
+                LocalKind::Dir
+            }
+        }
+    }
+
+    // what is uploaded for `path` if it is a link uploaded as a link-descriptor file.
+    // None means the content of the file.
+    pub fn link_descriptor(&self, path: &Path) -> io::Result<Option<Vec<u8>>> {
+        let is_link = fs::symlink_metadata(path)?.file_type().is_symlink();
+        if is_link && *self == Self::Descriptor {
+            Ok(Some(descriptor(&fs::read_link(path)?)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // the target if `content` is a link-descriptor file and symlinks are made from them.
+    pub fn parse_descriptor(&self, content: &[u8]) -> Option<PathBuf> {
+        if *self != Self::Descriptor || content.len() > MAX_DESCRIPTOR_LEN {
+            return None;
+        }
+        let content = std::str::from_utf8(content).ok()?;
+        let target = content
+            .strip_prefix(DESCRIPTOR_HEADER)?
+            .trim_end_matches('\n');
+        if target.is_empty() || target.contains('\n') {
+            return None;
+        }
+
+        Some(PathBuf::from(target))
+    }
+
+    // `parse_descriptor` of a downloaded file. Only a small one is read.
+    pub fn parse_descriptor_file(&self, path: &Path) -> Option<PathBuf> {
+        if *self != Self::Descriptor || fs::metadata(path).ok()?.len() > MAX_DESCRIPTOR_LEN as u64 {
+            return None;
+        }
+        self.parse_descriptor(&fs::read(path).ok()?)
+    }
+}
+
+pub fn descriptor(target: &Path) -> Vec<u8> {
+    format!("{}{}\n", DESCRIPTOR_HEADER, target.to_string_lossy()).into_bytes()
+}
+
+// A directory on the way from the root to `path` is (under) `target`.
+// Following it would come back to the same place again and again.
+fn is_loop(path: &Path, root: &Path, root_cano: &Path, target: &Path) -> bool {
+    let rel = match path.parent().and_then(|p| p.strip_prefix(root).ok()) {
+        Some(r) => r,
+        None => return false,
+    };
+
+    let mut cur = root.to_path_buf();
+    let mut chain = vec![root_cano.to_path_buf()];
+    for c in rel.components() {
+        cur.push(c);
+        if let Ok(p) = cur.canonicalize() {
+            chain.push(p);
+        }
+    }
+
+    chain.iter().any(|p| p.starts_with(target))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn symlink_policy_test() {
+        let base = std::env::temp_dir().join(format!("ncs_symlink_test_{}", std::process::id()));
+        let root = base.join("root");
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::create_dir_all(base.join("outside")).unwrap();
+        fs::write(root.join("a/f.txt"), b"x").unwrap();
+        symlink(root.join("a/f.txt"), root.join("link.txt")).unwrap();
+        symlink(root.join("a"), root.join("a/b/up")).unwrap();
+        symlink(root.join("a/b"), root.join("b")).unwrap();
+        symlink(base.join("outside"), root.join("out")).unwrap();
+        let root_cano = root.canonicalize().unwrap();
+
+        let classify =
+            |policy: SymlinkPolicy, p: &str| policy.classify(&root.join(p), &root, &root_cano);
+        assert_eq!(classify(SymlinkPolicy::Ignore, "a"), LocalKind::Dir);
+        assert_eq!(classify(SymlinkPolicy::Ignore, "link.txt"), LocalKind::Skip);
+        assert_eq!(classify(SymlinkPolicy::Ignore, "none"), LocalKind::Missing);
+
+        assert_eq!(classify(SymlinkPolicy::Follow, "link.txt"), LocalKind::File);
+        assert_eq!(classify(SymlinkPolicy::Follow, "b"), LocalKind::Dir);
+        assert_eq!(classify(SymlinkPolicy::Follow, "out"), LocalKind::Skip);
+        assert_eq!(classify(SymlinkPolicy::Follow, "a/b/up"), LocalKind::Skip);
+        // through the followed "b", which is in "a".
+        assert_eq!(classify(SymlinkPolicy::Follow, "b/up"), LocalKind::Skip);
+
+        let policy = SymlinkPolicy::Descriptor;
+        assert_eq!(
+            classify(policy, "link.txt"),
+            LocalKind::Link(root.join("a/f.txt"))
+        );
+        let content = policy
+            .link_descriptor(&root.join("link.txt"))
+            .unwrap()
+            .unwrap();
+        assert!(policy
+            .link_descriptor(&root.join("a/f.txt"))
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            policy.parse_descriptor(&content),
+            Some(root.join("a/f.txt"))
+        );
+        assert!(SymlinkPolicy::Follow.parse_descriptor(&content).is_none());
+        assert!(policy.parse_descriptor(b"x").is_none());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,297 @@
+use anyhow::Result;
+use chrono::prelude::*;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep_until, Duration, Instant};
+
+// `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    pub upload: Option<u64>,   // bytes/sec
+    pub download: Option<u64>, // bytes/sec
+    pub requests: Option<u64>, // requests/sec
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitKind {
+    Upload,
+    Download,
+    Requests,
+}
+
+impl LimitKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "up" | "upload" => Some(Self::Upload),
+            "down" | "download" => Some(Self::Download),
+            "req" | "requests" => Some(Self::Requests),
+            _ => None,
+        }
+    }
+}
+
+impl Limits {
+    pub fn get(&self, kind: LimitKind) -> Option<u64> {
+        match kind {
+            LimitKind::Upload => self.upload,
+            LimitKind::Download => self.download,
+            LimitKind::Requests => self.requests,
+        }
+    }
+
+    pub fn set(&mut self, kind: LimitKind, value: Option<u64>) {
+        match kind {
+            LimitKind::Upload => self.upload = value,
+            LimitKind::Download => self.download = value,
+            LimitKind::Requests => self.requests = value,
+        }
+    }
+}
+
+fn limit2str(v: Option<u64>) -> String {
+    v.map(|v| v.to_string())
+        .unwrap_or_else(|| "unlimited".to_string())
+}
+
+impl fmt::Display for Limits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "up={} down={} req={}",
+            limit2str(self.upload),
+            limit2str(self.download),
+            limit2str(self.requests)
+        )
+    }
+}
+
+// "unlimited", "0" => None
+pub fn parse_limit(s: &str) -> Result<Option<u64>> {
+    match s {
+        "unlimited" | "none" | "0" => Ok(None),
+        _ => Ok(Some(s.parse::<u64>()?)),
+    }
+}
+
+// A time window in local time. `end` earlier than `start` means it crosses midnight.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduleRule {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    // only the keys written in the rule. The others are the base limits.
+    pub overrides: Vec<(LimitKind, Option<u64>)>,
+}
+
+impl ScheduleRule {
+    pub fn apply(&self, base: Limits) -> Limits {
+        let mut limits = base;
+        for (kind, value) in self.overrides.iter() {
+            limits.set(*kind, *value);
+        }
+        limits
+    }
+
+    fn contains(&self, t: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= t && t < self.end
+        } else {
+            self.start <= t || t < self.end
+        }
+    }
+}
+
+// Format: "HH:MM-HH:MM up=<bytes/sec> down=<bytes/sec> req=<n>; ..."
+// Keys not written in a rule keep the base limits in that window.
+// ex) "22:00-06:00 up=unlimited down=unlimited; 09:00-18:00 up=100000"
+pub fn parse_schedule(s: &str) -> Result<Vec<ScheduleRule>> {
+    let mut rules = Vec::new();
+
+    for rule in s.split(';').map(str::trim).filter(|r| !r.is_empty()) {
+        let mut items = rule.split_whitespace();
+        let span = items
+            .next()
+            .ok_or_else(|| anyhow!("Invalid schedule rule: {}", rule))?;
+        let (start, end) = span
+            .split_once('-')
+            .ok_or_else(|| anyhow!("Invalid schedule span: {}", span))?;
+        let start = NaiveTime::parse_from_str(start, "%H:%M")?;
+        let end = NaiveTime::parse_from_str(end, "%H:%M")?;
+
+        let mut overrides = Vec::new();
+        for item in items {
+            let (key, value) = item
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid schedule item: {}", item))?;
+            let kind = LimitKind::from_name(key)
+                .ok_or_else(|| anyhow!("Invalid schedule key: {}", key))?;
+            overrides.push((kind, parse_limit(value)?));
+        }
+
+        rules.push(ScheduleRule {
+            start,
+            end,
+            overrides,
+        });
+    }
+
+    Ok(rules)
+}
+
+// Paces usage so that the average rate never goes over the limit.
+#[derive(Debug)]
+struct Pacer {
+    next_free: Instant,
+}
+
+impl Pacer {
+    fn new() -> Self {
+        Self {
+            next_free: Instant::now(),
+        }
+    }
+
+    // returns when the caller may proceed.
+    fn reserve(&mut self, amount: u64, rate: Option<u64>) -> Instant {
+        let now = Instant::now();
+        let rate = match rate {
+            Some(r) if r > 0 => r,
+            _ => {
+                self.next_free = now;
+                return now;
+            }
+        };
+
+        let start = self.next_free.max(now);
+        self.next_free = start + Duration::from_secs_f64(amount as f64 / rate as f64);
+        start
+    }
+}
+
+#[derive(Debug)]
+struct ThrottleInner {
+    limits: Limits,
+    schedule: Vec<ScheduleRule>,
+    upload: Pacer,
+    download: Pacer,
+    requests: Pacer,
+}
+
+impl ThrottleInner {
+    fn current_limits(&self) -> Limits {
+        let now = Local::now().time();
+        self.schedule
+            .iter()
+            .find(|r| r.contains(now))
+            .map(|r| r.apply(self.limits))
+            .unwrap_or(self.limits)
+    }
+}
+
+// Shared by every clone, so all transfers are limited together.
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    inner: Arc<Mutex<ThrottleInner>>,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self::new(Limits::default(), Vec::new())
+    }
+}
+
+impl Throttle {
+    pub fn new(limits: Limits, schedule: Vec<ScheduleRule>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(ThrottleInner {
+                limits,
+                schedule,
+                upload: Pacer::new(),
+                download: Pacer::new(),
+                requests: Pacer::new(),
+            })),
+        }
+    }
+
+    pub fn set_limit(&self, kind: LimitKind, value: Option<u64>) {
+        if let Ok(mut inner) = self.inner.lock() {
+            info!("set {:?} limit to {}", kind, limit2str(value));
+            inner.limits.set(kind, value);
+        }
+    }
+
+    pub fn set_schedule(&self, schedule: Vec<ScheduleRule>) {
+        if let Ok(mut inner) = self.inner.lock() {
+            info!("set throttle schedule ({} rules)", schedule.len());
+            inner.schedule = schedule;
+        }
+    }
+
+    pub fn get_limits(&self) -> Limits {
+        self.inner
+            .lock()
+            .map(|inner| inner.limits)
+            .unwrap_or_default()
+    }
+
+    // the limits in effect now (the schedule applied).
+    pub fn get_current_limits(&self) -> Limits {
+        self.inner
+            .lock()
+            .map(|inner| inner.current_limits())
+            .unwrap_or_default()
+    }
+
+    pub async fn acquire(&self, kind: LimitKind, amount: u64) {
+        let until = match self.inner.lock() {
+            Ok(mut inner) => {
+                let rate = inner.current_limits().get(kind);
+                let pacer = match kind {
+                    LimitKind::Upload => &mut inner.upload,
+                    LimitKind::Download => &mut inner.download,
+                    LimitKind::Requests => &mut inner.requests,
+                };
+                pacer.reserve(amount, rate)
+            }
+            Err(_) => return,
+        };
+
+        if until > Instant::now() {
+            sleep_until(until).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schedule_test() {
+        let rules = parse_schedule("22:00-06:00 up=unlimited; 09:00-18:00 up=1000 req=5").unwrap();
+        assert_eq!(rules.len(), 2);
+        let base = Limits {
+            upload: Some(10),
+            download: Some(20),
+            requests: None,
+        };
+        let limits = rules[1].apply(base);
+        assert_eq!(limits.upload, Some(1000));
+        assert_eq!(limits.requests, Some(5));
+        // not written in the rule.
+        assert_eq!(limits.download, Some(20));
+        assert_eq!(rules[0].apply(base).upload, None);
+        assert_eq!(rules[0].apply(base).download, Some(20));
+
+        let t = |h, m| NaiveTime::from_hms(h, m, 0);
+        assert!(rules[0].contains(t(23, 0)));
+        assert!(rules[0].contains(t(5, 59)));
+        assert!(!rules[0].contains(t(6, 0)));
+        assert!(rules[1].contains(t(12, 0)));
+        assert!(!rules[1].contains(t(18, 0)));
+
+        assert!(parse_schedule("22:00 up=1").is_err());
+        assert!(parse_schedule("22:00-06:00 side=1").is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use crate::errors::NcsError::*;
+use crate::logging::sync_event;
+use crate::meta::*;
+use crate::*;
+use anyhow::Result;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use reqwest::Method;
+use std::fmt;
+use urlencoding::decode;
+
+const TRASHBIN_PREFIX: &str = "/remote.php/dav/trashbin/";
+
+const TRASHBIN_BODY: &str = r#"<?xml version="1.0"?>
+<d:propfind  xmlns:d="DAV:" xmlns:oc="http://owncloud.org/ns" xmlns:nc="http://nextcloud.org/ns">
+  <d:prop>
+        <nc:trashbin-filename />
+        <nc:trashbin-original-location />
+        <nc:trashbin-deletion-time />
+        <d:resourcetype />
+  </d:prop>
+</d:propfind>
+"#;
+
+#[derive(Debug, Clone)]
+pub struct TrashItem {
+    // name in the trash bin. ex) "memo.txt.d1634567890"
+    pub name: String,
+    pub original_location: String,
+    pub deletion_time: i64,
+    pub is_dir: bool,
+}
+
+impl fmt::Display for TrashItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let dt = chrono::NaiveDateTime::from_timestamp(self.deletion_time, 0);
+        write!(
+            f,
+            "{}  {}{}  ({})",
+            dt.format("%Y-%m-%d %H:%M:%S"),
+            add_head_slash(&self.original_location),
+            if self.is_dir { "/" } else { "" },
+            self.name
+        )
+    }
+}
+
+fn trash_path(nc_info: &NCInfo) -> String {
+    format!("{}{}/trash", TRASHBIN_PREFIX, nc_info.username)
+}
+
+fn restore_path(nc_info: &NCInfo) -> String {
+    format!("{}{}/restore", TRASHBIN_PREFIX, nc_info.username)
+}
+
+fn check_available(nc_info: &NCInfo) -> Result<()> {
+    if nc_info.capabilities.dav {
+        Ok(())
+    } else {
+        Err(anyhow!("The trash bin needs remote.php/dav on the server."))
+    }
+}
+
+// newest first.
+pub async fn list(nc_info: &NCInfo, local_info: &LocalInfo) -> Result<Vec<TrashItem>> {
+    check_available(nc_info)?;
+
+    let client = &local_info.nc_client;
+    let req = client
+        .request(
+            Method::from_bytes(b"PROPFIND").unwrap(),
+            client.url(&trash_path(nc_info))?,
+        )
+        .header("Depth", "1")
+        .body(TRASHBIN_BODY);
+    let res = client.send(req).await?;
+    let text = res.text_with_charset("utf-8").await?;
+
+    let document = roxmltree::Document::parse(&text)?;
+    let mut items = trash_xml2items(&document);
+    items.sort_by_key(|item| std::cmp::Reverse(item.deletion_time));
+
+    Ok(items)
+}
+
+fn trash_xml2items(document: &roxmltree::Document) -> Vec<TrashItem> {
+    document
+        .root_element()
+        .children()
+        .filter(|n| n.tag_name().name() == "response")
+        .filter_map(|n| {
+            let mut name_w = None;
+            let mut original_location_w = None;
+            let mut deletion_time = 0;
+            let mut is_dir = false;
+
+            for d in n.descendants() {
+                match d.tag_name().name() {
+                    "href" => {
+                        let href = d.text()?;
+                        name_w = Some(decode(&path2name(href)).ok()?.to_string());
+                    }
+                    "trashbin-original-location" => {
+                        original_location_w = d.text().map(str::to_string);
+                    }
+                    "trashbin-deletion-time" => {
+                        deletion_time = d.text().and_then(|t| t.parse().ok()).unwrap_or(0);
+                    }
+                    "collection" => is_dir = true,
+                    _ => (),
+                }
+            }
+
+            // the response of the trash folder itself has no original location.
+            Some(TrashItem {
+                name: name_w?,
+                original_location: original_location_w?,
+                deletion_time,
+                is_dir,
+            })
+        })
+        .collect()
+}
+
+// `target` is the name in the trash bin or the original path. The newest one is chosen for the path.
+// The restored item comes back to the local through the FileRestored activity.
+pub async fn restore(nc_info: &NCInfo, local_info: &LocalInfo, target: &str) -> Result<TrashItem> {
+    let items = list(nc_info, local_info).await?;
+    let target_path = drop_slash(&add_head_slash(target), &RE_HAS_LAST_SLASH);
+    let item = items
+        .into_iter()
+        .find(|item| item.name == target || add_head_slash(&item.original_location) == target_path)
+        .ok_or_else(|| InvalidPathError(format!("{} is not in the trash bin.", target)))?;
+
+    let client = &local_info.nc_client;
+    let from_url = client.url(&format!("{}/{}", trash_path(nc_info), item.name))?;
+    let to_url = client.url(&format!("{}/{}", restore_path(nc_info), item.name))?;
+    let req = client
+        .request(Method::from_bytes(b"MOVE").unwrap(), from_url)
+        .header("Destination", to_url.as_str());
+    client.send(req).await?;
+
+    info!("restored from trash bin: {}", item);
+    sync_event(
+        "restore_trash",
+        &add_head_slash(&item.original_location),
+        None,
+        &format!("restored from the trash bin as {}", item.name),
+    );
+
+    Ok(item)
+}
@@ -0,0 +1,460 @@
+use crate::meta::{json_entry2tree, load_cache, root2json_entry, save_cache, NCSCache};
+use crate::*;
+use anyhow::Result;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+// not paths. Paths start with "/", so they are out of their range.
+const ACTIVITY_ID_KEY: &str = "latest_activity_id";
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+// the format of the keys and the values.
+pub const SCHEMA_VERSION: u32 = 1;
+const OPEN_ATTEMPTS: u32 = 30;
+
+// what is found at the start.
+pub enum LoadedTree {
+    Loaded(Tree, String),
+    // the first start.
+    Empty,
+    // the db is broken or of an unknown version, and there is no usable backup.
+    // The state has to be rebuilt.
+    Broken,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct StoredEntry {
+    is_dir: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    remote_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mtime: Option<i64>,
+}
+
+// The tree in `.ncs/tree.db`. One key per entry, keyed by the path. ex) "/a/b.txt"
+// Only the paths changed in the tree since the last save are written, in one batch.
+pub struct TreeStore {
+    db: sled::Db,
+    // the backup which has the same tree as the db. It is removed on the next change.
+    backup_file: Option<String>,
+}
+
+impl TreeStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        // the lock of a dropped db is released by its background thread, a little later.
+        // It happens on rerun.
+        let mut attempts = 0;
+        let db = loop {
+            match sled::open(path.as_ref()) {
+                Ok(db) => break db,
+                Err(sled::Error::Io(e))
+                    if e.kind() == io::ErrorKind::Other && attempts < OPEN_ATTEMPTS =>
+                {
+                    debug!("tree store: {:?}. retry.", e);
+                    attempts += 1;
+                    thread::sleep(Duration::from_millis(100));
+                }
+                Err(e) => {
+                    return Err(
+                        anyhow::Error::from(e).context(format!("can't open {:?}", path.as_ref()))
+                    )
+                }
+            }
+        };
+
+        Ok(Self {
+            db,
+            backup_file: None,
+        })
+    }
+
+    // Opens the db and loads the tree. A broken db is moved aside and
+    // the last-good backup is loaded into a new one instead.
+    // The backup is used only if the db is not changed after it. (see `save`)
+    pub fn open_and_load(db_path: &str, backup_file: &str) -> Result<(Self, LoadedTree)> {
+        let res = match Self::open(db_path) {
+            Ok(mut store) => {
+                store.backup_file = Some(backup_file.to_string());
+                store.load().map(|loaded| (store, loaded))
+            }
+            // not of the db. ex) locked by another process.
+            Err(e) if is_io_error(&e) => return Err(e),
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok((store, Some((root, id)))) => return Ok((store, LoadedTree::Loaded(root, id))),
+            Ok((store, None)) => return Ok((store, LoadedTree::Empty)),
+            Err(e) => warn!("{} is broken. | {:?}", db_path, e),
+        }
+
+        let broken_path = format!(
+            "{}.broken-{}",
+            db_path,
+            chrono::Local::now().format("%Y%m%d%H%M%S")
+        );
+        warn!("{} is moved to {}.", db_path, broken_path);
+        fs::rename(db_path, &broken_path)?;
+        let mut store = Self::open(db_path)?;
+
+        let ncs_cache = match load_cache(backup_file) {
+            Ok(c) => c,
+            Err(e) => {
+                warn!("no usable backup. | {:?}", e);
+                return Ok((store, LoadedTree::Broken));
+            }
+        };
+        warn!(
+            "the tree is restored from {}. (latest activity id: {})",
+            backup_file, ncs_cache.latest_activity_id
+        );
+        let mut tree = json_entry2tree(ncs_cache.root_entry)?;
+        store.save(&mut tree, &ncs_cache.latest_activity_id)?;
+        store.flush()?;
+        store.backup_file = Some(backup_file.to_string());
+
+        Ok((
+            store,
+            LoadedTree::Loaded(tree, ncs_cache.latest_activity_id),
+        ))
+    }
+
+    // for the dry-run. The db is copied into a temporary one, which is removed when dropped.
+    // Nothing in `db_path` is written, even if it is broken.
+    pub fn open_copy_and_load(db_path: &str, backup_file: &str) -> Result<(Self, LoadedTree)> {
+        let tmp = std::env::temp_dir().join(format!("ncs-dry-run-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&tmp);
+        fs::create_dir_all(&tmp)?;
+        if Path::new(db_path).exists() {
+            let options = fs_extra::dir::CopyOptions {
+                content_only: true,
+                ..Default::default()
+            };
+            fs_extra::dir::copy(db_path, &tmp, &options)
+                .map_err(|e| anyhow!("{:?} | {:?}", db_path, e))?;
+        }
+        let db = sled::Config::new().path(&tmp).temporary(true).open()?;
+        let mut store = Self {
+            db,
+            backup_file: None,
+        };
+
+        let loaded = match store.load() {
+            Ok(Some((root, id))) => LoadedTree::Loaded(root, id),
+            Ok(None) => LoadedTree::Empty,
+            Err(e) => {
+                warn!("{} is broken. | {:?}", db_path, e);
+                match load_cache(backup_file) {
+                    Ok(c) => {
+                        LoadedTree::Loaded(json_entry2tree(c.root_entry)?, c.latest_activity_id)
+                    }
+                    Err(_) => LoadedTree::Broken,
+                }
+            }
+        };
+
+        Ok((store, loaded))
+    }
+
+    // None if nothing is saved yet.
+    pub fn load(&mut self) -> Result<Option<(Tree, String)>> {
+        // written by the first version of the store, which had no version key.
+        let version = match self.db.get(SCHEMA_VERSION_KEY)? {
+            Some(v) => String::from_utf8(v.to_vec())?.parse::<u32>()?,
+            None => SCHEMA_VERSION,
+        };
+        if version != SCHEMA_VERSION {
+            return Err(anyhow!("unknown schema version {}.", version));
+        }
+
+        let latest_activity_id = match self.db.get(ACTIVITY_ID_KEY)? {
+            Some(v) => String::from_utf8(v.to_vec())?,
+            None => return Ok(None),
+        };
+
+        let mut tree = Tree::new();
+        tree.root_mut().status = EntryStatus::UpToDate;
+
+        // keys come in byte order, so a directory comes before its children.
+        for kv in self.db.scan_prefix("/") {
+            let (k, v) = kv?;
+            let path = String::from_utf8(k.to_vec())?;
+            let stored: StoredEntry = serde_json::from_slice(&v)?;
+
+            let (parent_path, name) = match path.rsplit_once('/') {
+                Some(v) => v,
+                None => continue,
+            };
+            let parent = match tree.get_id(parent_path) {
+                Some(p) if tree.entry(p).unwrap().type_.is_dir() => p,
+                _ => {
+                    warn!("{}: the parent is not in the db. skipped.", path);
+                    continue;
+                }
+            };
+
+            let type_ = if stored.is_dir {
+                EntryType::Directory
+            } else {
+                EntryType::File {
+                    etag: stored.etag.clone(),
+                }
+            };
+            let mut entry = Entry::new(name.to_string(), type_);
+            entry.status = EntryStatus::UpToDate;
+            if let Some(r) = &stored.remote_name {
+                entry.set_remote_name(r);
+            }
+            entry.mtime = stored.mtime;
+            tree.add_child(parent, entry)?;
+        }
+        // all in the db already.
+        tree.clear_changes();
+        debug!("tree store: {} entries loaded.", tree.len());
+
+        Ok(Some((tree, latest_activity_id)))
+    }
+
+    // returns the number of changed keys.
+    pub fn save(&mut self, tree: &mut Tree, latest_activity_id: &str) -> Result<usize> {
+        if !tree.root().is_root() {
+            return Err(anyhow!("This function can be called by root entry."));
+        }
+
+        let mut batch = sled::Batch::default();
+        let mut changes = 0;
+        for path in tree.changes().filter(|p| *p != "/") {
+            match tree.get(path) {
+                Some(entry) => batch.insert(path.as_bytes(), serde_json::to_vec(&stored(entry))?),
+                // removed or moved away.
+                None => batch.remove(path.as_bytes()),
+            }
+            changes += 1;
+        }
+        batch.insert(ACTIVITY_ID_KEY, latest_activity_id.as_bytes());
+        batch.insert(SCHEMA_VERSION_KEY, SCHEMA_VERSION.to_string().as_bytes());
+
+        // an older backup would bring back removed entries after a crash.
+        // Without it, a broken db is rebuilt.
+        if changes > 0 {
+            self.drop_backup()?;
+        }
+        // on an error, the changes are kept for the next save.
+        self.db.apply_batch(batch)?;
+        tree.clear_changes();
+        if changes > 0 {
+            debug!("tree store: {} keys changed.", changes);
+        }
+
+        Ok(changes)
+    }
+
+    fn drop_backup(&mut self) -> Result<()> {
+        if let Some(backup_file) = self.backup_file.take() {
+            match fs::remove_file(&backup_file) {
+                Ok(()) => debug!("tree store: {} is out of date. removed.", backup_file),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => {
+                    self.backup_file = Some(backup_file);
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    // The whole tree as NCSCache. Written on a clean exit and used if the db is broken.
+    pub fn write_backup(
+        &mut self,
+        tree: &Tree,
+        latest_activity_id: &str,
+        backup_file: &str,
+    ) -> Result<()> {
+        let root_entry = root2json_entry(tree)?;
+        let ncs_cache = NCSCache::new(latest_activity_id.to_string(), root_entry);
+        save_cache(&ncs_cache, backup_file)?;
+        self.backup_file = Some(backup_file.to_string());
+        Ok(())
+    }
+
+    // `cache.json` of the older versions. It is kept as `cache.json.old` once moved into the db.
+    pub fn migrate_from_json(&mut self, cache_file: &str) -> Result<(Tree, String)> {
+        info!("move {} into the tree store.", cache_file);
+        let ncs_cache = load_cache(cache_file)?;
+        let mut tree = json_entry2tree(ncs_cache.root_entry)?;
+        self.save(&mut tree, &ncs_cache.latest_activity_id)?;
+        self.flush()?;
+        fs::rename(cache_file, format!("{}.old", cache_file))?;
+
+        Ok((tree, ncs_cache.latest_activity_id))
+    }
+}
+
+fn is_io_error(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<sled::Error>(), Some(sled::Error::Io(_)))
+}
+
+fn stored(entry: &Entry) -> StoredEntry {
+    let remote_name = Some(entry.get_remote_raw_name()).filter(|r| r != &entry.get_raw_name());
+    match &entry.type_ {
+        EntryType::Directory => StoredEntry {
+            is_dir: true,
+            etag: None,
+            remote_name,
+            mtime: None,
+        },
+        EntryType::File { etag } => StoredEntry {
+            is_dir: false,
+            etag: etag.clone(),
+            remote_name,
+            mtime: entry.mtime,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_entry(name: &str, type_: EntryType) -> Entry {
+        let mut entry = Entry::new(name.to_string(), type_);
+        entry.status = EntryStatus::UpToDate;
+        entry
+    }
+
+    fn new_tree() -> Tree {
+        let mut tree = Tree::new();
+        tree.root_mut().status = EntryStatus::UpToDate;
+        tree
+    }
+
+    #[test]
+    fn tree_store_test() {
+        let dir = std::env::temp_dir().join(format!("ncs_tree_store_test_{}", std::process::id()));
+        let mut root = new_tree();
+        let a = root
+            .add_child(Tree::ROOT, new_entry("a", EntryType::Directory))
+            .unwrap();
+        let b = new_entry(
+            "b.txt",
+            EntryType::File {
+                etag: Some("e1".to_string()),
+            },
+        );
+        root.add_child(a, b).unwrap();
+        let mut a_txt = new_entry("a.txt", EntryType::File { etag: None });
+        a_txt.mtime = Some(1445412480);
+        root.add_child(Tree::ROOT, a_txt).unwrap();
+
+        {
+            let mut store = TreeStore::open(&dir).unwrap();
+            assert!(store.load().unwrap().is_none());
+            assert_eq!(store.save(&mut root, "10").unwrap(), 3);
+            assert_eq!(store.save(&mut root, "11").unwrap(), 0);
+
+            root.remove("/a/b.txt").unwrap();
+            assert_eq!(store.save(&mut root, "12").unwrap(), 1);
+            // the old keys are removed and the new ones written.
+            root.insert(
+                "/a/c.txt",
+                new_entry("c.txt", EntryType::File { etag: None }),
+            )
+            .unwrap();
+            root.move_entry("/a", "/d").unwrap();
+            assert_eq!(store.save(&mut root, "12").unwrap(), 4);
+            store.flush().unwrap();
+        }
+
+        let mut store = TreeStore::open(&dir).unwrap();
+        let (loaded, latest_activity_id) = store.load().unwrap().unwrap();
+        assert_eq!(latest_activity_id, "12");
+        assert!(loaded.get("/a").is_none());
+        assert!(loaded.get("/d/c.txt").is_some());
+        assert!(loaded.get("/d/b.txt").is_none());
+        assert_eq!(loaded.get("/a.txt").unwrap().mtime, Some(1445412480));
+        assert_eq!(loaded.get_tree(), root.get_tree());
+        drop(store);
+
+        // the dry-run's copy. Saving to it leaves the db as it is.
+        let dir_str = dir.to_string_lossy().to_string();
+        let (mut copy, loaded) = TreeStore::open_copy_and_load(&dir_str, "none").unwrap();
+        assert!(matches!(loaded, LoadedTree::Loaded(_, ref id) if id == "12"));
+        copy.save(&mut root, "13").unwrap();
+        copy.flush().unwrap();
+        drop(copy);
+        let mut store = TreeStore::open(&dir).unwrap();
+        assert_eq!(store.load().unwrap().unwrap().1, "12");
+        drop(store);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tree_store_recovery_test() {
+        let dir =
+            std::env::temp_dir().join(format!("ncs_tree_recovery_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("tree.db").to_string_lossy().to_string();
+        let backup_file = dir
+            .join("tree.last-good.json")
+            .to_string_lossy()
+            .to_string();
+
+        let mut root = new_tree();
+        root.add_child(Tree::ROOT, new_entry("a", EntryType::Directory))
+            .unwrap();
+        {
+            let mut store = TreeStore::open(&db_path).unwrap();
+            store.save(&mut root, "10").unwrap();
+            store.write_backup(&root, "10", &backup_file).unwrap();
+            // written by a newer version.
+            store.db.insert(SCHEMA_VERSION_KEY, "99").unwrap();
+            store.flush().unwrap();
+        }
+
+        let (store, loaded) = TreeStore::open_and_load(&db_path, &backup_file).unwrap();
+        match loaded {
+            LoadedTree::Loaded(loaded, id) => {
+                assert_eq!(id, "10");
+                assert!(loaded.get("/a").is_some());
+            }
+            _ => panic!("not restored from the backup."),
+        }
+        assert!(Path::new(&backup_file).exists());
+        drop(store);
+        let names = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert!(names.iter().any(|n| n.starts_with("tree.db.broken-")));
+        for n in names.iter().filter(|n| n.starts_with("tree.db.broken-")) {
+            fs::remove_dir_all(dir.join(n)).unwrap();
+        }
+
+        // changed after the backup, and then broken.
+        {
+            let (mut store, _) = TreeStore::open_and_load(&db_path, &backup_file).unwrap();
+            root.add_child(Tree::ROOT, new_entry("b", EntryType::Directory))
+                .unwrap();
+            store.save(&mut root, "11").unwrap();
+            assert!(!Path::new(&backup_file).exists());
+            store.db.insert(SCHEMA_VERSION_KEY, "99").unwrap();
+            store.flush().unwrap();
+        }
+        let (_store, loaded) = TreeStore::open_and_load(&db_path, &backup_file).unwrap();
+        assert!(matches!(loaded, LoadedTree::Broken));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,256 @@
+use crate::errors::NcsError::*;
+use crate::logging::sync_event;
+use crate::meta::*;
+use crate::stash::{StashCause, StashReason};
+use crate::*;
+use anyhow::{Context, Result};
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use reqwest::Method;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+
+const VERSIONS_PREFIX: &str = "/remote.php/dav/versions/";
+
+const FILEID_BODY: &str = r#"<?xml version="1.0"?>
+<d:propfind  xmlns:d="DAV:" xmlns:oc="http://owncloud.org/ns">
+  <d:prop>
+        <oc:fileid />
+  </d:prop>
+</d:propfind>
+"#;
+
+const VERSIONS_BODY: &str = r#"<?xml version="1.0"?>
+<d:propfind  xmlns:d="DAV:">
+  <d:prop>
+        <d:getlastmodified />
+        <d:getcontentlength />
+        <d:getetag />
+  </d:prop>
+</d:propfind>
+"#;
+
+#[derive(Debug, Clone)]
+pub struct FileVersion {
+    // name of the version in the versions folder. (unix time of the version)
+    pub id: String,
+    pub last_modified: String,
+    pub size: u64,
+}
+
+impl fmt::Display for FileVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}  {}  {} bytes",
+            self.id, self.last_modified, self.size
+        )
+    }
+}
+
+fn check_available(nc_info: &NCInfo) -> Result<()> {
+    if nc_info.capabilities.dav {
+        Ok(())
+    } else {
+        Err(anyhow!("File versions need remote.php/dav on the server."))
+    }
+}
+
+fn versions_path(nc_info: &NCInfo, fileid: &str) -> String {
+    format!(
+        "{}{}/versions/{}",
+        VERSIONS_PREFIX, nc_info.username, fileid
+    )
+}
+
+// `path` is a local one. It's asked by the name on the server.
+async fn get_fileid(local_info: &LocalInfo, tree: &Tree, path: &str) -> Result<String> {
+    let remote_path = local_info.names.remote_path(tree, path);
+    let client = &local_info.nc_client;
+    let req = client
+        .dav_request(Method::from_bytes(b"PROPFIND").unwrap(), &remote_path)?
+        .header("Depth", "0")
+        .body(FILEID_BODY);
+    let text = client.send(req).await?.text_with_charset("utf-8").await?;
+
+    let document = roxmltree::Document::parse(&text)?;
+    let fileid = document
+        .descendants()
+        .find(|n| n.tag_name().name() == "fileid")
+        .and_then(|n| n.text())
+        .with_context(|| InvalidXMLError)?;
+
+    Ok(fileid.to_string())
+}
+
+// newest first.
+pub async fn list(
+    nc_info: &NCInfo,
+    local_info: &LocalInfo,
+    tree: &Tree,
+    path: &str,
+) -> Result<Vec<FileVersion>> {
+    check_available(nc_info)?;
+
+    let path = add_head_slash(path);
+    let fileid = get_fileid(local_info, tree, &path).await?;
+
+    let client = &local_info.nc_client;
+    let req = client
+        .request(
+            Method::from_bytes(b"PROPFIND").unwrap(),
+            client.url(&versions_path(nc_info, &fileid))?,
+        )
+        .header("Depth", "1")
+        .body(VERSIONS_BODY);
+    let text = client.send(req).await?.text_with_charset("utf-8").await?;
+
+    let document = roxmltree::Document::parse(&text)?;
+    let mut versions = document
+        .root_element()
+        .children()
+        .filter(|n| n.tag_name().name() == "response")
+        .filter_map(|n| {
+            let mut props = HashMap::new();
+            for d in n.descendants() {
+                if let Some(t) = d.text() {
+                    props.insert(d.tag_name().name(), t);
+                }
+            }
+            let id = path2name(props.get("href")?);
+            // the folder itself is also listed.
+            if id == fileid {
+                return None;
+            }
+            Some(FileVersion {
+                id,
+                last_modified: props.get("getlastmodified").unwrap_or(&"").to_string(),
+                size: props
+                    .get("getcontentlength")
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or(0),
+            })
+        })
+        .collect::<Vec<_>>();
+    versions.sort_by(|a, b| b.id.cmp(&a.id));
+
+    Ok(versions)
+}
+
+// "~" is excluded by default. So the side by side file won't be synced.
+fn side_by_side_path(path: &str, version_id: &str, local_info: &LocalInfo) -> PathBuf {
+    let p = Path::new(path);
+    let stem = p
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let ext = p
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let name = format!("~{}.v{}{}", stem, version_id, ext);
+    let parent = p.parent().map(path2str).unwrap_or_default();
+
+    local_listen::get_localpath(Path::new(&format!("{}/{}", parent, name)), local_info)
+}
+
+// download a version next to the current file.
+pub async fn download(
+    nc_info: &NCInfo,
+    local_info: &LocalInfo,
+    tree: &Tree,
+    path: &str,
+    version_id: &str,
+) -> Result<PathBuf> {
+    check_available(nc_info)?;
+
+    let path = add_head_slash(path);
+    let fileid = get_fileid(local_info, tree, &path).await?;
+
+    let client = &local_info.nc_client;
+    let url = client.url(&format!(
+        "{}/{}",
+        versions_path(nc_info, &fileid),
+        version_id
+    ))?;
+    let bytes = client
+        .send(client.request(Method::GET, url))
+        .await?
+        .bytes()
+        .await?;
+
+    let local_path = side_by_side_path(&path, version_id, local_info);
+    fileope::save_file(
+        &mut bytes.as_ref(),
+        &local_path.to_string_lossy(),
+        false,
+        StashCause::new(StashReason::DownloadOverwrite, None),
+        local_info,
+    )?;
+
+    sync_event(
+        "download",
+        &path2str(
+            local_path
+                .strip_prefix(&local_info.root_path)
+                .unwrap_or(&local_path),
+        ),
+        None,
+        &format!("version {} of {}", version_id, path),
+    );
+
+    Ok(local_path)
+}
+
+// restore a version as current on the server, then get it to the local.
+// The new etag is recorded in the tree, so the local write is not uploaded again.
+pub async fn restore(
+    nc_info: &NCInfo,
+    local_info: &LocalInfo,
+    tree: &mut Tree,
+    nc2l_cancel_map: &mut HashMap<String, usize>,
+    path: &str,
+    version_id: &str,
+) -> Result<()> {
+    check_available(nc_info)?;
+
+    let path = add_head_slash(path);
+    let fileid = get_fileid(local_info, tree, &path).await?;
+
+    let client = &local_info.nc_client;
+    let from_url = client.url(&format!(
+        "{}/{}",
+        versions_path(nc_info, &fileid),
+        version_id
+    ))?;
+    let to_url = client.url(&format!(
+        "{}{}/restore/target",
+        VERSIONS_PREFIX, nc_info.username
+    ))?;
+    let req = client
+        .request(Method::from_bytes(b"MOVE").unwrap(), from_url)
+        .header("Destination", to_url.as_str());
+    client.send(req).await?;
+
+    info!("restored version {} of {}", version_id, path);
+    sync_event(
+        "restore_version",
+        &path,
+        None,
+        &format!("version {}", version_id),
+    );
+
+    // refresh takes a local path.
+    let local_path = local_info.root_path_cano.join(path.trim_start_matches('/'));
+    nc_listen::refresh(
+        &local_path,
+        false,
+        tree,
+        nc_info,
+        local_info,
+        nc2l_cancel_map,
+        false,
+    )
+    .await
+}